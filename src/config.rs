@@ -0,0 +1,124 @@
+//! Hypervisor options.
+//!
+//! Options are passed on the host kernel command line (`/chosen/bootargs` in the host device tree)
+//! as words of the form `rvirt.<name>=<value>`. The command line is handed on unmodified to guests,
+//! which will ignore them.
+
+use crate::constants::{MAX_GUEST_HARTS, MAX_HOST_HARTS};
+
+/// What to do when a guest asks to be shut down or rebooted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResetPolicy {
+    /// Do whatever the guest asked for.
+    Guest,
+    /// Always halt the guest, even if it asked to reboot.
+    Halt,
+    /// Always reboot the guest, even if it asked to shut down.
+    Reboot,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Number of vCPUs to give each guest (`rvirt.vcpus`).
+    pub guest_vcpus: u64,
+    /// Bytes of memory to give each guest (`rvirt.memory`, which takes a K, M or G suffix). By
+    /// default host memory is split evenly between guests.
+    pub guest_memory: Option<u64>,
+
+    /// Reset policy for guests without one of their own (`rvirt.on-reset`).
+    pub default_on_reset: ResetPolicy,
+    /// Reset policy for each guest, indexed by guestid (`rvirt.on-reset.<guestid>`).
+    pub on_reset: [Option<ResetPolicy>; MAX_HOST_HARTS + 1],
+
+    /// Guest to attach the GDB stub to (`rvirt.gdb`). Only takes effect along with `gdb_uart`.
+    pub gdb_guest: Option<u64>,
+    /// Physical address of a host UART, of the same type as the console, for the GDB stub to use
+    /// (`rvirt.gdb-uart`).
+    pub gdb_uart: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            guest_vcpus: 1,
+            guest_memory: None,
+            default_on_reset: ResetPolicy::Guest,
+            on_reset: [None; MAX_HOST_HARTS + 1],
+            gdb_guest: None,
+            gdb_uart: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(bootargs: &str) -> Self {
+        let mut config = Self::default();
+        for arg in bootargs.split(' ') {
+            let mut parts = arg.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("rvirt.vcpus"), Some(value)) => match value.parse::<u64>() {
+                    Ok(n) if n >= 1 && n <= MAX_GUEST_HARTS as u64 => config.guest_vcpus = n,
+                    _ => println!("WARN: Ignoring invalid option rvirt.vcpus={}", value),
+                }
+                (Some("rvirt.memory"), Some(value)) => match parse_size(value) {
+                    Some(size) if size > 0 => config.guest_memory = Some(size),
+                    _ => println!("WARN: Ignoring invalid option rvirt.memory={}", value),
+                }
+                (Some("rvirt.on-reset"), Some(value)) => match parse_reset_policy(value) {
+                    Some(policy) => config.default_on_reset = policy,
+                    None => println!("WARN: Ignoring invalid option rvirt.on-reset={}", value),
+                }
+                (Some(name), Some(value)) if name.starts_with("rvirt.on-reset.") => {
+                    let guestid = name["rvirt.on-reset.".len()..].parse::<usize>().ok()
+                        .filter(|&id| id >= 1 && id <= MAX_HOST_HARTS);
+                    match (guestid, parse_reset_policy(value)) {
+                        (Some(guestid), Some(policy)) => config.on_reset[guestid] = Some(policy),
+                        _ => println!("WARN: Ignoring invalid option {}={}", name, value),
+                    }
+                }
+                (Some("rvirt.gdb"), Some(value)) => match value.parse::<u64>() {
+                    Ok(id) if id >= 1 && id <= MAX_HOST_HARTS as u64 => config.gdb_guest = Some(id),
+                    _ => println!("WARN: Ignoring invalid option rvirt.gdb={}", value),
+                }
+                (Some("rvirt.gdb-uart"), Some(value)) => {
+                    let address = if value.starts_with("0x") {
+                        u64::from_str_radix(&value[2..], 16).ok()
+                    } else {
+                        value.parse::<u64>().ok()
+                    };
+                    match address {
+                        Some(address) => config.gdb_uart = Some(address),
+                        None => println!("WARN: Ignoring invalid option rvirt.gdb-uart={}", value),
+                    }
+                }
+                _ => {}
+            }
+        }
+        config
+    }
+
+    /// Reset policy for the guest with the given id.
+    pub fn on_reset(&self, guestid: u64) -> ResetPolicy {
+        self.on_reset.get(guestid as usize).and_then(|&p| p).unwrap_or(self.default_on_reset)
+    }
+}
+
+/// Parse a number of bytes, optionally followed by a K, M or G suffix.
+fn parse_size(value: &str) -> Option<u64> {
+    let (number, shift) = match value.as_bytes().last() {
+        Some(b'K') | Some(b'k') => (&value[..value.len() - 1], 10),
+        Some(b'M') | Some(b'm') => (&value[..value.len() - 1], 20),
+        Some(b'G') | Some(b'g') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn parse_reset_policy(value: &str) -> Option<ResetPolicy> {
+    match value {
+        "guest" => Some(ResetPolicy::Guest),
+        "halt" => Some(ResetPolicy::Halt),
+        "reboot" => Some(ResetPolicy::Reboot),
+        _ => None,
+    }
+}
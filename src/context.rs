@@ -1,16 +1,47 @@
 use arrayvec::ArrayVec;
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use spin::Mutex;
-use crate::fdt::MachineMeta;
+use crate::config::ResetPolicy;
+use crate::error::HypervisorError;
+use crate::constants::MAX_GUEST_HARTS;
+use crate::dirty::DirtyLog;
+use crate::fdt::{Fdt, Hart, MachineMeta};
+use crate::fp::FpState;
+use crate::gdb::Debugger;
+use crate::hext::{self, GStage};
 use crate::memory_region::MemoryRegion;
+use crate::mmio::MmioDevice;
+use crate::monitor::{self, Command};
 use crate::plic::PlicState;
-use crate::pmap::{PageTables, PageTableRoot};
+use crate::pmap::{GuestLayout, PageTables, PageTableRoot};
+use crate::ptwatch::{GuestPageTables, LogPosition};
+use crate::sbi::constants::{HSM_STATUS_STARTED, HSM_STATUS_START_PENDING, HSM_STATUS_STOPPED};
 use crate::statics::SHARED_STATICS;
+use crate::stats::ExitStats;
 use crate::trap::constants::*;
 use crate::trap::U64Bits;
-use crate::{csr, pmap, print, riscv, virtio};
+use crate::{csr, dirty, elf, fp, pmap, print, ptwatch, riscv, virtio};
 
 pub static CONTEXT: Mutex<Option<Context>> = Mutex::new(None);
 
+// Bits of `Vcpu::requests`.
+pub const REQUEST_IPI: u64 = 0x1;
+pub const REQUEST_FENCE_I: u64 = 0x2;
+pub const REQUEST_SFENCE_VMA: u64 = 0x4;
+pub const REQUEST_INTERRUPT: u64 = 0x8;
+/// Unlike the other requests, this one is only acknowledged once the vCPU has actually stopped.
+pub const REQUEST_STOP: u64 = 0x10;
+/// Carry out the command in `SharedContext::monitor_command` for the hypervisor monitor.
+pub const REQUEST_MONITOR: u64 = 0x20;
+/// Discard the shadow (or G-stage) page tables entirely, rather than just catching up on guest page
+/// table writes as for `REQUEST_SFENCE_VMA`.
+pub const REQUEST_FLUSH_PAGE_TABLES: u64 = 0x40;
+/// Write-protect newly tracked guest page tables (see ptwatch.rs).
+pub const REQUEST_WRITE_PROTECT: u64 = 0x80;
+/// Write-protect guest memory, or stop doing so, after the dirty log is changed (see dirty.rs).
+pub const REQUEST_DIRTY_LOG: u64 = 0x100;
+
 pub struct ControlRegisters {
     // sedeleg: u64, -- Hard-wired to zero
     // sideleg: u64, -- Hard-wired to zero
@@ -19,7 +50,7 @@ pub struct ControlRegisters {
     pub sie: u64,
     pub sip: u64,
     pub stvec: u64,
-    // scounteren: u64, -- Hard-wired to zero
+    pub scounteren: u64,
     pub sscratch: u64,
     pub sepc: u64,
     pub scause: u64,
@@ -58,29 +89,100 @@ pub struct HostPlic {
     pub claim_clear: MemoryRegion<u32>,
 }
 
+/// Per-vCPU state that other vCPUs of the same guest need to see.
+pub struct Vcpu {
+    /// Host hart this vCPU is pinned to.
+    pub hartid: u64,
+    /// Guest PLIC context for S-mode interrupts on this vCPU.
+    pub plic_context: u64,
+    /// Bitmask of `REQUEST_*` values posted by other vCPUs and not yet handled.
+    pub requests: AtomicU64,
+    /// SBI HSM status of the vCPU (one of `sbi::constants::HSM_STATUS_*`).
+    pub status: AtomicU64,
+    /// Start address and opaque argument passed to SBI HSM hart_start, until the vCPU picks them up.
+    pub start: Mutex<Option<(u64, u64)>>,
+}
+
+/// State shared by all vCPUs of a guest. Lives in the guest's segment of memory at `SHARED_OFFSET`.
+///
+/// To avoid deadlocks, locks must be taken in the order: uart, virtio, plic, page_tables. No lock may
+/// be held while waiting on another vCPU.
+pub struct SharedContext {
+    pub plic: Mutex<PlicState>,
+    pub uart: Mutex<Uart>,
+    pub virtio: Mutex<VirtIO>,
+    /// Guest pages holding page tables that shadow mappings were derived from.
+    pub page_tables: Mutex<GuestPageTables>,
+    /// Guest pages written since the dirty log was last fetched, while it is enabled.
+    pub dirty_log: DirtyLog,
+
+    /// Map from host external interrupt number to guest external interrupt nmuber
+    pub irq_map: [u16; 512],
+
+    pub vcpus: ArrayVec<[Vcpu; MAX_GUEST_HARTS]>,
+    clint_address: u64,
+
+    /// Value of mtime when the guest booted. Subtracted from the host's time to get the guest's.
+    pub time_offset: AtomicU64,
+
+    /// What to do when the guest asks to shut down or reboot.
+    pub on_reset: ResetPolicy,
+    /// Set while one of the vCPUs is resetting the guest.
+    pub resetting: AtomicBool,
+
+    /// Kinds of HypervisorError that have already been logged for this guest.
+    pub logged_errors: AtomicU64,
+
+    /// GDB stub, if one is attached to this guest.
+    pub debugger: Option<Mutex<Debugger>>,
+    /// While the guest is stopped by the debugger, one plus the index of the only vCPU allowed to
+    /// run. Zero otherwise.
+    pub debug_halt: AtomicU64,
+
+    /// Set while the monitor has paused the guest.
+    pub paused: AtomicBool,
+    /// Command for the vCPU sent `REQUEST_MONITOR` to carry out.
+    pub monitor_command: Mutex<Option<Command>>,
+
+    /// Where the guest's memory and the hypervisor's state for it are in host memory.
+    pub layout: GuestLayout,
+    /// Kernel image the guest is booted from, and the device tree to derive the guest's from.
+    kernel_image: u64,
+    host_fdt: u64,
+}
+
 pub struct Context {
     pub csrs: ControlRegisters,
-    pub plic: PlicState,
-    pub uart: Uart,
-    pub virtio: VirtIO,
+    pub shared: &'static SharedContext,
+    /// Index of this vCPU within the guest. Doubles as the guest's hartid.
+    pub vcpu: usize,
 
     pub saved_registers: MemoryRegion,
     pub guest_memory: MemoryRegion,
     pub shadow_page_tables: PageTables,
+    /// G-stage page tables, used instead of shadow page tables with the hypervisor extension.
+    pub gstage: Option<GStage>,
 
     pub guest_shift: u64,
 
     /// Whether the guest is in S-Mode.
     pub smode: bool,
 
+    /// Floating point registers, when not loaded into the hart's.
+    pub fp: FpState,
+
+    /// How many of the guest page table writes and newly tracked pages logged by all vCPUs have
+    /// been applied to this vCPU's shadow page tables.
+    pub page_table_log: LogPosition,
+
     /// If set, hypervisor exits do not need to check for pending interrupts
     pub no_interrupt: bool,
 
     pub host_clint: HostClint,
     pub host_plic: HostPlic,
 
-    /// Map from host external interrupt number to guest external interrupt nmuber
-    pub irq_map: [u16; 512],
+    /// Counts of exits to the hypervisor, and the time spent handling them.
+    pub stats: ExitStats,
 }
 
 
@@ -99,6 +201,19 @@ impl ControlRegisters {
 impl Uart {
     const IRQ: u32 = 10;
 
+    pub fn new(guestid: Option<u64>) -> Self {
+        Self {
+            dlab: false,
+            interrupt_enable: 0,
+            divisor_latch: 1,
+            next_interrupt_time: 0,
+            input_fifo: [0; 16],
+            input_bytes_ready: 0,
+            line_buffer: ArrayVec::new(),
+            guestid,
+        }
+    }
+
     fn tx_interrupt(&self, current_time: u64) -> bool {
         self.next_interrupt_time  <= current_time && self.interrupt_enable & 0x2 != 0
     }
@@ -106,16 +221,23 @@ impl Uart {
         self.input_bytes_ready >= 1 && self.interrupt_enable & 0x1 != 0
     }
     pub fn timer(state: &mut Context, current_time: u64) {
-        state.uart.fill_fifo();
-        if state.uart.tx_interrupt(current_time) || state.uart.rx_interrupt() {
-            state.plic.set_pending(Uart::IRQ, true);
-            state.no_interrupt = false;
+        let raise = {
+            let mut uart = state.shared.uart.lock();
+            uart.fill_fifo();
+            uart.tx_interrupt(current_time) || uart.rx_interrupt()
+        };
+        if raise {
+            state.raise_interrupt(Uart::IRQ);
         }
     }
 
     pub fn fill_fifo(&mut self) {
-        while self.input_bytes_ready < self.input_fifo.len() {
+        while self.input_bytes_ready < self.input_fifo.len() && monitor::guest_input_enabled() {
             if let Some(ch) = SHARED_STATICS.uart_writer.lock().getchar() {
+                if ch == monitor::ESCAPE {
+                    monitor::request();
+                    break;
+                }
                 self.input_fifo[self.input_bytes_ready] = ch;
                 self.input_bytes_ready += 1;
             } else {
@@ -124,6 +246,19 @@ impl Uart {
         }
     }
 
+    /// Like `fill_fifo`, but once the FIFO is full input is read and discarded, so that the monitor
+    /// escape is noticed even while the guest isn't draining it.
+    pub fn poll_escape(&mut self) {
+        self.fill_fifo();
+        while monitor::guest_input_enabled() {
+            match SHARED_STATICS.uart_writer.lock().getchar() {
+                Some(monitor::ESCAPE) => monitor::request(),
+                Some(_) => {}
+                None => break,
+            }
+        }
+    }
+
     const TRANSMIT_HOLDING_REGISTER: u64 = 0x10000000;
     const RECEIVE_BUFFER_REGISTER: u64 = 0x10000000;
     const DIVISOR_LATCH_LSB: u64 = 0x10000000;
@@ -163,20 +298,23 @@ impl Uart {
     const MCR_LOOPBACK_ENABLE: u8 = 0x10;
     const MCR_RESERVED_BITS: u8 = 0xe0;
 
-    pub fn read(&mut self, host_clint: &HostClint, addr: u64) -> u8 {
-        match (self.dlab, addr) {
-            (false, Uart::RECEIVE_BUFFER_REGISTER) => {
-                if self.input_bytes_ready > 0 {
-                    let ret = self.input_fifo[0];
-                    self.input_bytes_ready -= 1;
-                    for i in 0..(self.input_bytes_ready) {
-                        self.input_fifo[i] = self.input_fifo[i+1];
-                    }
-                    ret
-                } else {
-                    0
-                }
+    /// Remove and return the oldest byte from the input FIFO, if there is one.
+    pub fn input_byte(&mut self) -> Option<u8> {
+        if self.input_bytes_ready > 0 {
+            let ret = self.input_fifo[0];
+            self.input_bytes_ready -= 1;
+            for i in 0..(self.input_bytes_ready) {
+                self.input_fifo[i] = self.input_fifo[i+1];
             }
+            Some(ret)
+        } else {
+            None
+        }
+    }
+
+    pub fn read(&mut self, host_clint: &HostClint, addr: u64) -> Result<u8, HypervisorError> {
+        Ok(match (self.dlab, addr) {
+            (false, Uart::RECEIVE_BUFFER_REGISTER) => self.input_byte().unwrap_or(0),
             (true, Uart::DIVISOR_LATCH_LSB) => (self.divisor_latch & 0xff) as u8,
             (true, Uart::DIVISOR_LATCH_MSB) => (self.divisor_latch >> 8) as u8,
             (false, Uart::INTERRUPT_ENABLE_REGISTER) => self.interrupt_enable, // (top four should always be zero)
@@ -204,13 +342,10 @@ impl Uart {
                 lsr
             }
             (_, Uart::MODEM_STATUS_REGISTER) => Uart::MSR_CLEAR_TO_SEND, // other bits don't matter to Linux
-            (dlab, _) => {
-                println!("UART: Read uimplemented ?? <- {:#x} (dlab={})", addr, dlab);
-                loop {}
-            }
-        }
+            _ => return Err(HypervisorError::UnsupportedRegister),
+        })
     }
-    pub fn write(&mut self, host_clint: &HostClint, addr: u64, value: u8) {
+    pub fn write(&mut self, host_clint: &HostClint, addr: u64, value: u8) -> Result<(), HypervisorError> {
         match (self.dlab, addr, value) {
             (false, Uart::TRANSMIT_HOLDING_REGISTER, _) => {
                 self.output_byte(value as u8);
@@ -232,12 +367,9 @@ impl Uart {
             (_, Uart::FIFO_CONTROL_REGISTER, _) => {}
             (_, Uart::LINE_CONTROL_REGISTER, _) => self.dlab = (value & Uart::LCR_DIVISOR_LATCH_ACCESS) != 0,
             (_, Uart::MODEM_CONTROL_REGISTER, _) if value & (Uart::MCR_LOOPBACK_ENABLE | Uart::MCR_RESERVED_BITS) == 0 => {}
-            _ => {
-                println!("UART: Write unimplemented {:#x} -> {:#x} (dlab={})",
-                         value, addr, self.dlab);
-                loop {}
-            }
+            _ => return Err(HypervisorError::UnsupportedRegister),
         }
+        Ok(())
     }
 
     pub fn output_byte(&mut self, value: u8) {
@@ -259,6 +391,21 @@ impl Uart {
     }
 }
 
+impl MmioDevice for Uart {
+    fn read(&mut self, state: &mut Context, addr: u64, width: u64) -> Result<u64, HypervisorError> {
+        match width {
+            1 => Uart::read(self, &state.host_clint, addr).map(|value| value as u64),
+            _ => Err(HypervisorError::UnsupportedAccess),
+        }
+    }
+    fn write(&mut self, state: &mut Context, addr: u64, width: u64, value: u64) -> Result<(), HypervisorError> {
+        match width {
+            1 => Uart::write(self, &state.host_clint, addr, value as u8),
+            _ => Err(HypervisorError::UnsupportedAccess),
+        }
+    }
+}
+
 impl HostClint {
     pub fn get_mtime(&self) -> u64 {
         self.mtime[0]
@@ -277,14 +424,255 @@ impl HostPlic {
     }
 }
 
+impl SharedContext {
+    /// Send a software interrupt to the host hart running `vcpu` so that it notices any newly posted
+    /// requests.
+    pub fn kick(&self, vcpu: usize) {
+        self.kick_hart(self.vcpus[vcpu].hartid);
+    }
+
+    /// Send a software interrupt to host hart `hartid`, which need not run a vCPU of this guest.
+    pub fn kick_hart(&self, hartid: u64) {
+        let msip = pmap::pa2va(self.clint_address + hartid * 4);
+        unsafe { ptr::write_volatile(msip as *mut u32, 1) }
+    }
+
+    /// Start `vcpu` running at `start_addr` with `opaque` in a1. Returns false if it wasn't stopped.
+    pub fn start_vcpu(&self, vcpu: usize, start_addr: u64, opaque: u64) -> bool {
+        let target = &self.vcpus[vcpu];
+        if target.status.compare_and_swap(HSM_STATUS_STOPPED, HSM_STATUS_START_PENDING, Ordering::SeqCst)
+            != HSM_STATUS_STOPPED {
+            return false;
+        }
+
+        *target.start.lock() = Some((start_addr, opaque));
+        self.kick(vcpu);
+        true
+    }
+
+    /// Load the guest kernel and device tree into guest memory. Returns the entry point and the guest
+    /// physical address of the device tree.
+    pub unsafe fn load_guest(&self, guest_memory: &MemoryRegion, guest_shift: u64) -> (u64, u64) {
+        let base = pmap::pa2va(guest_memory.base() + guest_shift);
+        let (entry, max_addr) = elf::load_elf(self.kernel_image as *const u8, base as *mut u8);
+
+        let guest_dtb = (max_addr | 0x1fffff) + 1;
+        let host_fdt = Fdt::new(self.host_fdt);
+        let guest_fdt = pmap::pa2va(guest_dtb + guest_shift);
+        ptr::copy(host_fdt.address(), guest_fdt as *mut u8, host_fdt.total_size() as usize);
+        Fdt::new(guest_fdt).mask(guest_memory.len(), self.vcpus.len() as u64);
+        self.dirty_log.mark(guest_memory.base(), guest_dtb + host_fdt.total_size() as u64 - guest_memory.base());
+
+        (entry, guest_dtb)
+    }
+
+    /// Return the emulated and host devices of the guest to their power-on state.
+    pub fn reset_devices(&self) {
+        let mut uart = self.uart.lock();
+        let mut virtio = self.virtio.lock();
+        let mut plic = self.plic.lock();
+
+        *uart = Uart::new(uart.guestid);
+        for device in &mut virtio.devices {
+            device.reset();
+        }
+        virtio.queue_guest_pages.clear();
+        *plic = PlicState::new();
+        self.page_tables.lock().clear();
+    }
+
+    /// Park the calling host hart until `vcpu` is started, and return the start address and opaque
+    /// argument it was given. Requests sent to the vCPU in the meantime are dropped since it has no
+    /// state for them to apply to, but the monitor is still served. A start left pending while the
+    /// guest is being reset is discarded, since dropping the request to stop has already let the
+    /// resetting vCPU go ahead.
+    pub fn wait_for_start(&'static self, vcpu: usize) -> (u64, u64) {
+        let vcpu = &self.vcpus[vcpu];
+        loop {
+            riscv::clear_sip(IP_SSIP);
+            vcpu.requests.store(0, Ordering::SeqCst);
+            if self.resetting.load(Ordering::SeqCst) {
+                if vcpu.start.lock().take().is_some() {
+                    vcpu.status.store(HSM_STATUS_STOPPED, Ordering::SeqCst);
+                }
+            } else if let Some(start) = vcpu.start.lock().take() {
+                vcpu.status.store(HSM_STATUS_STARTED, Ordering::SeqCst);
+                return start;
+            }
+            monitor::serve(self, vcpu.hartid, None);
+            riscv::wfi();
+        }
+    }
+}
+
 impl Context {
+    /// Current value of the guest's `time` CSR.
+    pub fn guest_time(&self) -> u64 {
+        self.host_clint.get_mtime().wrapping_sub(self.shared.time_offset.load(Ordering::Relaxed))
+    }
+
+    /// Convert a guest time value into the host time it corresponds to.
+    pub fn host_time(&self, guest_time: u64) -> u64 {
+        guest_time.saturating_add(self.shared.time_offset.load(Ordering::Relaxed))
+    }
+
+    pub fn plic_context(&self) -> usize {
+        self.shared.vcpus[self.vcpu].plic_context as usize
+    }
+
+    /// Mark guest interrupt `irq` as pending and notify every vCPU that might now take it.
+    pub fn raise_interrupt(&mut self, irq: u32) {
+        let shared = self.shared;
+        let mut plic = shared.plic.lock();
+        plic.set_pending(irq, true);
+        for (i, vcpu) in shared.vcpus.iter().enumerate() {
+            if plic.interrupt_pending(vcpu.plic_context as usize) {
+                if i == self.vcpu {
+                    self.no_interrupt = false;
+                } else {
+                    vcpu.requests.fetch_or(REQUEST_INTERRUPT, Ordering::SeqCst);
+                    shared.kick(i);
+                }
+            }
+        }
+    }
+
+    /// Post `request` to every other vCPU in `mask`. Unless the request is only an IPI, wait for all
+    /// of them to handle it. Requests sent to this vCPU are processed while waiting so that two vCPUs
+    /// waiting on each other cannot deadlock.
+    pub fn remote_request(&mut self, mask: u64, request: u64) {
+        let shared = self.shared;
+        let this = self.vcpu;
+        let targets = || shared.vcpus.iter().enumerate()
+            .filter(move |&(i, _)| i != this && i < 64 && mask & (1 << i) != 0);
+
+        for (i, vcpu) in targets() {
+            vcpu.requests.fetch_or(request, Ordering::SeqCst);
+            shared.kick(i);
+        }
+
+        if request & !REQUEST_IPI != 0 {
+            for (_, vcpu) in targets() {
+                while vcpu.requests.load(Ordering::SeqCst) & request & !REQUEST_IPI != 0 {
+                    self.process_requests();
+                }
+            }
+        }
+    }
+
+    /// Handle any requests other vCPUs have posted to this one.
+    pub fn process_requests(&mut self) {
+        let requests = &self.shared.vcpus[self.vcpu].requests;
+        let pending = requests.load(Ordering::SeqCst);
+        if pending == 0 {
+            return;
+        }
+
+        if pending & REQUEST_IPI != 0 {
+            self.csrs.sip |= IP_SSIP;
+            self.no_interrupt = false;
+        }
+        if pending & REQUEST_FENCE_I != 0 {
+            riscv::fence_i();
+        }
+        if pending & REQUEST_SFENCE_VMA != 0 {
+            match self.gstage {
+                Some(_) => riscv::hfence_vvma(),
+                None => ptwatch::catch_up(self),
+            }
+        }
+        if pending & REQUEST_FLUSH_PAGE_TABLES != 0 {
+            self.flush_page_tables();
+        }
+        if pending & REQUEST_WRITE_PROTECT != 0 {
+            ptwatch::protect_new(self);
+        }
+        if pending & REQUEST_DIRTY_LOG != 0 {
+            dirty::protect(self);
+        }
+        if pending & REQUEST_INTERRUPT != 0 {
+            self.no_interrupt = false;
+        }
+        if pending & REQUEST_MONITOR != 0 {
+            let command = self.shared.monitor_command.lock().take();
+            if let Some(command) = command {
+                monitor::execute(self, command);
+            }
+        }
+
+        // Only clear the bits once the work is done, since the sender may be waiting on them. Stop
+        // requests are handled by `park`, once the current trap has been dealt with.
+        requests.fetch_and(!(pending & !REQUEST_STOP), Ordering::SeqCst);
+    }
+
+    /// Flush the page tables that are derived from the guest's memory layout and page tables: the
+    /// shadow page tables, or the G-stage page tables with the hypervisor extension.
+    pub fn flush_page_tables(&mut self) {
+        match self.gstage {
+            Some(ref mut gstage) => gstage.flush(),
+            None => {
+                pmap::flush_shadow_page_table(&mut self.shadow_page_tables);
+
+                // Queue pages may have been set up or released since the MPA root was last updated.
+                let queue_pages = self.shared.virtio.lock().queue_guest_pages.clone();
+                self.shadow_page_tables.set_mpa_holes(&queue_pages);
+                riscv::sfence_vma();
+            }
+        }
+    }
+
+    pub fn stop_requested(&self) -> bool {
+        self.shared.vcpus[self.vcpu].requests.load(Ordering::SeqCst) & REQUEST_STOP != 0
+    }
+
+    /// Stop this vCPU until it is started again by SBI HSM hart_start, then reset it to run from
+    /// the requested start address.
+    pub fn park(&mut self) {
+        let shared = self.shared;
+        fp::unload(self);
+        shared.vcpus[self.vcpu].status.store(HSM_STATUS_STOPPED, Ordering::SeqCst);
+        let (start_addr, opaque) = shared.wait_for_start(self.vcpu);
+        self.reset(start_addr, opaque);
+    }
+
+    /// Reset this vCPU to the state the SBI HSM extension specifies for a newly started hart: S-mode
+    /// with paging and interrupts disabled, executing at `start_addr` with a0 = hartid and a1 =
+    /// `opaque`.
+    pub fn reset(&mut self, start_addr: u64, opaque: u64) {
+        self.csrs = ControlRegisters {
+            sstatus: 0,
+            stvec: 0,
+            sie: 0,
+            sip: 0,
+            scounteren: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+
+            mtimecmp: u64::max_value(),
+        };
+        self.host_clint.set_mtimecmp(u64::max_value());
+        fp::reset(self);
+        self.flush_page_tables();
+        dirty::protect(self);
+        if self.gstage.is_some() {
+            hext::reset_vcpu(self);
+        }
+
+        self.smode = true;
+        self.no_interrupt = true;
+
+        let hartid = self.vcpu as u64;
+        crate::trap::set_register(self, 10, hartid);
+        crate::trap::set_register(self, 11, opaque);
+        riscv::set_sepc(start_addr);
+    }
+
     pub fn get_csr(&mut self, csr: u32) -> Option<u64> {
         Some(match csr as u64 {
-            csr::sstatus => {
-                let real = csrr!(sstatus);
-                self.csrs.sstatus = (self.csrs.sstatus & !SSTATUS_DYNAMIC_MASK) | (real & SSTATUS_DYNAMIC_MASK);
-                self.csrs.sstatus
-            }
+            csr::sstatus => fp::guest_sstatus(self),
             csr::satp => self.csrs.satp,
             csr::sie => self.csrs.sie,
             csr::stvec => self.csrs.stvec,
@@ -295,9 +683,10 @@ impl Context {
             csr::sip => self.csrs.sip,
             csr::sedeleg => 0,
             csr::sideleg => 0,
-            csr::scounteren => 0,
-            csr::time if self.smode => self.host_clint.get_mtime(),
-            csr::time => unimplemented!(),
+            csr::scounteren => self.csrs.scounteren,
+            csr::cycle => csrr!(cycle),
+            csr::time => self.guest_time(),
+            csr::instret => csrr!(instret),
             c => {
                 println!("Read from unrecognized CSR: {:#x}", c);
                 return None;
@@ -312,13 +701,12 @@ impl Context {
                 let value = value & SSTATUS_WRITABLE_MASK;
 
                 let changed = self.csrs.sstatus ^ value;
-                self.csrs.sstatus = value;
+                self.csrs.sstatus = (value & !STATUS_FS) | (self.csrs.sstatus & STATUS_FS);
+                fp::set_guest_fs(self, value);
 
                 if changed & STATUS_MXR != 0 {
-                    unimplemented!("STATUS.MXR");
-                }
-                if changed & STATUS_FS != 0 {
-                    riscv::set_sstatus_fs(value);
+                    // Shadow PTEs for execute-only pages are only readable while MXR is set.
+                    pmap::flush_shadow_page_table(&mut self.shadow_page_tables);
                 }
 
                 if changed.get(STATUS_SIE) && value.get(STATUS_SIE) {
@@ -328,14 +716,17 @@ impl Context {
             }
             csr::satp => {
                 let mode = (value & SATP_MODE) >> 60;
-                if mode == 0 || mode == 8 {
-                    self.csrs.satp = value & !SATP_ASID;
+                if mode == 0 || mode == 8 || (mode == 9 && self.shadow_page_tables.sv48_supported()) {
+                    self.csrs.satp = value;
+                    if mode != 0 {
+                        // Shadow page tables for the new address space may still be cached. Writes
+                        // to its guest page tables since they were built have been trapped and
+                        // invalidated the affected mappings (see ptwatch), so they are still valid.
+                        self.shadow_page_tables.switch_address_space(value);
+                    }
                 } else {
                     println!("Attempted to install page table with unsupported mode");
                 }
-                // This should not be necessary. However, currently QEMU doesn't trap when
-                // sfence.vma is executed from user mode so flush here to compensate.
-                pmap::flush_shadow_page_table(&mut self.shadow_page_tables);
             }
             csr::sie => {
                 let value = value & (IE_SEIE | IE_STIE | IE_SSIE);
@@ -355,9 +746,9 @@ impl Context {
                 }
                 self.csrs.sip = (self.csrs.sip & !IP_SSIP) | (value & IP_SSIP)
             }
+            csr::scounteren => self.csrs.scounteren = value & (COUNTEREN_CY | COUNTEREN_TM | COUNTEREN_IR),
             csr::sedeleg |
-            csr::sideleg |
-            csr::scounteren => {}
+            csr::sideleg => {}
             c => {
                 println!("Write to unrecognized CSR: {:#x}", c);
                 return false;
@@ -380,32 +771,98 @@ impl Context {
     }
 }
 
-pub unsafe fn initialize(machine: &MachineMeta,
-                         guest_machine: &MachineMeta,
-                         shadow_page_tables: PageTables,
-                         guest_memory: MemoryRegion,
-                         guest_shift: u64,
-                         hartid: u64,
-                         guestid: Option<u64>) {
+/// Create the state shared by all vCPUs of a guest in the guest's segment of memory. Must be called
+/// before any of the guest's vCPUs are initialized.
+pub unsafe fn initialize_shared(machine: &MachineMeta,
+                                fdt: &Fdt,
+                                layout: &GuestLayout,
+                                harts: &[Hart],
+                                guestid: Option<u64>) -> &'static SharedContext {
     let mut irq_map = [0; 512];
     let mut virtio_devices = ArrayVec::new();
     for i in 0..4 {
         let index = (guestid.unwrap_or(1) as usize - 1) * 4 + i;
         if index < machine.virtio.len() {
-            virtio_devices.push(virtio::Device::new(machine.virtio[index].base_address));
             let host_irq = machine.virtio[index].irq;
-            let mut guest_irq = None;
-            for j in 0..4 {
-                if guest_machine.virtio[j].base_address == 0x10001000 + 0x1000 * i as u64 {
-                    guest_irq = Some(guest_machine.virtio[j].irq);
-                    break;
-                }
-            }
+
+            // The guest device tree is derived from the host's, so the guest sees the device at
+            // this address with whatever interrupt the host's device there has.
+            let guest_irq = machine.virtio.iter()
+                .find(|v| v.base_address == 0x10001000 + 0x1000 * i as u64)
+                .map(|v| v.irq)
+                .unwrap();
             assert_eq!(irq_map[host_irq as usize], 0);
-            irq_map[host_irq as usize] = guest_irq.unwrap() as u16;
+            irq_map[host_irq as usize] = guest_irq as u16;
+            virtio_devices.push(virtio::Device::new(machine.virtio[index].base_address, guest_irq as u32));
         }
     }
 
+    let mut vcpus = ArrayVec::new();
+    for (i, hart) in harts.iter().enumerate() {
+        vcpus.push(Vcpu {
+            hartid: hart.hartid,
+            // Each hart has an M-mode context followed by an S-mode context (see plic.rs).
+            plic_context: 2 * i as u64 + 1,
+            requests: AtomicU64::new(0),
+            // Only the boot vCPU runs initially, the rest wait for SBI HSM hart_start.
+            status: AtomicU64::new(if i == 0 { HSM_STATUS_STARTED } else { HSM_STATUS_STOPPED }),
+            start: Mutex::new(None),
+        });
+    }
+
+    let debugger = match (machine.config.gdb_guest, machine.config.gdb_uart, machine.uart_type) {
+        (Some(id), Some(address), Some(ty)) if id == guestid.unwrap_or(1) => {
+            println!("GDB stub for guest {} on UART at {:#x}", id, address);
+            Some(Mutex::new(Debugger::new(address, ty)))
+        }
+        _ => None,
+    };
+
+    // Keep a copy of the device tree that won't be overwritten, in case the guest reboots.
+    assert!(mem::size_of::<SharedContext>() as u64 <= pmap::SHARED_FDT_OFFSET - pmap::SHARED_OFFSET);
+    assert!(fdt.total_size() as u64 <= pmap::SHARED_OFFSET + pmap::SHARED_SIZE - pmap::SHARED_FDT_OFFSET);
+    let host_fdt = pmap::pa2va(layout.base + pmap::SHARED_FDT_OFFSET);
+    ptr::copy(fdt.address(), host_fdt as *mut u8, fdt.total_size() as usize);
+
+    let shared = pmap::pa2va(layout.base + pmap::SHARED_OFFSET) as *mut SharedContext;
+    ptr::write(shared, SharedContext {
+        plic: Mutex::new(PlicState::new()),
+        uart: Mutex::new(Uart::new(guestid)),
+        virtio: Mutex::new(VirtIO {
+            devices: virtio_devices,
+            queue_guest_pages: ArrayVec::new(),
+        }),
+        page_tables: Mutex::new(GuestPageTables::new()),
+        dirty_log: DirtyLog::new(pmap::pa2va(layout.dirty_log),
+                                 machine.physical_memory_offset, layout.memory_size),
+        irq_map,
+        vcpus,
+        clint_address: machine.clint_address,
+        time_offset: AtomicU64::new(ptr::read_volatile(pmap::pa2va(machine.clint_address + 0xbff8) as *const u64)),
+        on_reset: machine.config.on_reset(guestid.unwrap_or(1)),
+        resetting: AtomicBool::new(false),
+        logged_errors: AtomicU64::new(0),
+        debugger,
+        debug_halt: AtomicU64::new(0),
+        paused: AtomicBool::new(false),
+        monitor_command: Mutex::new(None),
+        layout: *layout,
+        kernel_image: pmap::pa2va(layout.kernel_image),
+        host_fdt,
+    });
+    SHARED_STATICS.monitor_harts[guestid.unwrap_or(1) as usize - 1].store(harts[0].hartid, Ordering::SeqCst);
+    SHARED_STATICS.guests[guestid.unwrap_or(1) as usize - 1].store(shared as u64, Ordering::SeqCst);
+    &*shared
+}
+
+pub unsafe fn initialize(machine: &MachineMeta,
+                         shadow_page_tables: PageTables,
+                         gstage: Option<GStage>,
+                         guest_memory: MemoryRegion,
+                         guest_shift: u64,
+                         hartid: u64,
+                         vcpu: usize,
+                         shared: &'static SharedContext) {
     let plic_context = machine.harts.iter().find(|h| h.hartid == hartid).unwrap().plic_context;
 
     // Memory backing for CONTEXT might not be in a valid state, so force_unlock() first. This is
@@ -418,6 +875,7 @@ pub unsafe fn initialize(machine: &MachineMeta,
             stvec: 0,
             sie: 0,
             sip: 0,
+            scounteren: 0,
             sscratch: 0,
             sepc: 0,
             scause: 0,
@@ -426,26 +884,16 @@ pub unsafe fn initialize(machine: &MachineMeta,
 
             mtimecmp: u64::max_value(),
         },
+        shared,
+        vcpu,
         saved_registers: MemoryRegion::with_base_address(SSTACK_BASE, 0, 32 * 8),
         guest_memory,
         shadow_page_tables,
-        plic: PlicState::new(),
-        uart: Uart {
-            dlab: false,
-            interrupt_enable: 0,
-            divisor_latch: 1,
-            next_interrupt_time: 0,
-            input_fifo: [0; 16],
-            input_bytes_ready: 0,
-            line_buffer: ArrayVec::new(),
-            guestid,
-        },
-        virtio: VirtIO {
-            devices: virtio_devices,
-            queue_guest_pages: ArrayVec::new(),
-        },
+        gstage,
         guest_shift,
         smode: true,
+        fp: FpState::new(),
+        page_table_log: LogPosition::default(),
         no_interrupt: true,
         host_clint: HostClint {
             mtime: MemoryRegion::with_base_address(
@@ -457,6 +905,12 @@ pub unsafe fn initialize(machine: &MachineMeta,
             claim_clear: MemoryRegion::with_base_address(
                 pmap::pa2va(machine.plic_address + 0x200004 + 0x1000 * plic_context), 0, 8),
         },
-        irq_map,
+        stats: ExitStats::new(),
     });
+
+    let mut state = CONTEXT.lock();
+    let state = state.as_mut().unwrap();
+    if state.gstage.is_some() {
+        hext::init_vcpu(state);
+    }
 }
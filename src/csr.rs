@@ -121,6 +121,27 @@ pub const sintstatus: u64 = 0x146;
 pub const sscratchcsw: u64 = 0x148;
 pub const sptbr: u64 = 0x180;
 pub const satp: u64 = 0x180;
+pub const vsstatus: u64 = 0x200;
+pub const vsie: u64 = 0x204;
+pub const vstvec: u64 = 0x205;
+pub const vsscratch: u64 = 0x240;
+pub const vsepc: u64 = 0x241;
+pub const vscause: u64 = 0x242;
+pub const vstval: u64 = 0x243;
+pub const vsip: u64 = 0x244;
+pub const vsatp: u64 = 0x280;
+pub const hstatus: u64 = 0x600;
+pub const hedeleg: u64 = 0x602;
+pub const hideleg: u64 = 0x603;
+pub const hie: u64 = 0x604;
+pub const htimedelta: u64 = 0x605;
+pub const hcounteren: u64 = 0x606;
+pub const hgeie: u64 = 0x607;
+pub const htval: u64 = 0x643;
+pub const hip: u64 = 0x644;
+pub const hvip: u64 = 0x645;
+pub const htinst: u64 = 0x64a;
+pub const hgatp: u64 = 0x680;
 pub const pmpcfg0: u64 = 0x3a0;
 pub const pmpcfg1: u64 = 0x3a1;
 pub const pmpcfg2: u64 = 0x3a2;
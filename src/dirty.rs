@@ -0,0 +1,160 @@
+//! Logging of which guest physical pages a guest writes to, as needed for incremental snapshots or
+//! migration.
+//!
+//! While logging is enabled, nothing maps guest memory writable unless the page is already marked
+//! dirty. The first write to a clean page therefore faults, and the page is marked before the
+//! mapping is made writable. Fetching the log clears it and write-protects guest memory again, on
+//! every vCPU, before reporting any of the pages that were written.
+//!
+//! Shadow page tables track pages individually. The MPA root (used while guest paging is disabled)
+//! and the G-stage page tables map guest memory with 2 MB pages, so a write through them marks the
+//! whole 2 MB region. Writes the hypervisor makes on the guest's behalf are logged as well, and so
+//! are the buffers the guest hands to passed through virtio devices to write (see
+//! virtio::check_available).
+
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::context::{Context, REQUEST_DIRTY_LOG};
+use crate::riscv;
+
+const PAGE_SIZE: u64 = 4096;
+const HPAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Bitmap of the guest pages written since the log was last fetched, shared by all of a guest's
+/// vCPUs.
+pub struct DirtyLog {
+    enabled: AtomicBool,
+    /// Guest physical address of the page that the first bit stands for.
+    base: u64,
+    bitmap: &'static [AtomicU64],
+    /// Pages taken out of `bitmap` by a fetch, but not yet reported.
+    fetched: &'static [AtomicU64],
+}
+impl DirtyLog {
+    /// Create a disabled log for `size` bytes of guest memory at guest physical address `base`, with
+    /// its two bitmaps at `bitmaps`.
+    pub unsafe fn new(bitmaps: u64, base: u64, size: u64) -> Self {
+        let words = Self::bitmaps_size(size) / 16;
+        Self {
+            enabled: AtomicBool::new(false),
+            base,
+            bitmap: slice::from_raw_parts(bitmaps as *const AtomicU64, words as usize),
+            fetched: slice::from_raw_parts((bitmaps + words * 8) as *const AtomicU64, words as usize),
+        }
+    }
+
+    /// Bytes needed for the two bitmaps of a log for `size` bytes of guest memory.
+    pub fn bitmaps_size(size: u64) -> u64 {
+        2 * 8 * ((size / PAGE_SIZE + 63) / 64)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    pub fn is_dirty(&self, guest_pa: u64) -> bool {
+        match self.word(guest_pa) {
+            Some((word, bit)) => word.load(Ordering::SeqCst) & bit != 0,
+            None => false,
+        }
+    }
+
+    /// Mark the pages overlapping the `len` bytes at `guest_pa` as dirty, if logging is enabled.
+    pub fn mark(&self, guest_pa: u64, len: u64) {
+        if !self.enabled() || len == 0 {
+            return;
+        }
+
+        let mut page = guest_pa & !(PAGE_SIZE - 1);
+        while page < guest_pa + len {
+            if let Some((word, bit)) = self.word(page) {
+                if word.load(Ordering::Relaxed) & bit == 0 {
+                    word.fetch_or(bit, Ordering::SeqCst);
+                }
+            }
+            page += PAGE_SIZE;
+        }
+    }
+
+    /// Word of the bitmap holding the bit for `guest_pa`, and the mask selecting that bit.
+    fn word(&self, guest_pa: u64) -> Option<(&AtomicU64, u64)> {
+        let index = guest_pa.checked_sub(self.base)? / PAGE_SIZE;
+        let word = self.bitmap.get(index as usize / 64)?;
+        Some((word, 1 << (index % 64)))
+    }
+}
+
+/// Start logging the guest's writes to memory, with every page initially clean.
+pub fn start(state: &mut Context) {
+    let log = &state.shared.dirty_log;
+    for word in log.bitmap {
+        word.store(0, Ordering::SeqCst);
+    }
+    log.enabled.store(true, Ordering::SeqCst);
+    protect_all(state);
+}
+
+/// Stop logging, letting the guest write to its memory freely again.
+pub fn stop(state: &mut Context) {
+    state.shared.dirty_log.enabled.store(false, Ordering::SeqCst);
+    protect_all(state);
+}
+
+/// Call `f` with the guest physical address of each page written since logging started or the log
+/// was last fetched, and mark them all clean again. By the time `f` is called, any further writes
+/// to those pages will be logged, so it may copy them.
+pub fn fetch_and_clear<F: FnMut(u64)>(state: &mut Context, mut f: F) {
+    let log = &state.shared.dirty_log;
+    for (word, fetched) in log.bitmap.iter().zip(log.fetched) {
+        fetched.store(word.swap(0, Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    // Pages marked clean may still be mapped writable, including by other vCPUs. Their contents
+    // can't be relied on until they no longer are.
+    protect_all(state);
+
+    let log = &state.shared.dirty_log;
+    for (i, fetched) in log.fetched.iter().enumerate() {
+        let mut bits = fetched.swap(0, Ordering::SeqCst);
+        while bits != 0 {
+            let bit = bits.trailing_zeros() as u64;
+            f(log.base + (i as u64 * 64 + bit) * PAGE_SIZE);
+            bits &= bits - 1;
+        }
+    }
+}
+
+/// Bring the calling vCPU's page tables in line with whether logging is enabled: while it is, remove
+/// write permission from every mapping of guest memory, and otherwise restore it where the mappings
+/// aren't recreated on demand.
+pub fn protect(state: &mut Context) {
+    let enabled = state.shared.dirty_log.enabled();
+    match state.gstage {
+        Some(ref mut gstage) => gstage.flush(),
+        None => {
+            if enabled {
+                state.shadow_page_tables.write_protect_all();
+            }
+            state.shadow_page_tables.set_mpa_writable(None, !enabled);
+            riscv::sfence_vma();
+        }
+    }
+}
+
+fn protect_all(state: &mut Context) {
+    protect(state);
+    state.remote_request(!0, REQUEST_DIRTY_LOG);
+}
+
+/// Handle a write fault through the MPA root, which only happens while logging. Returns false if
+/// `guest_pa` isn't in guest memory.
+pub fn handle_mpa_write(state: &mut Context, guest_pa: u64) -> bool {
+    if !state.shared.dirty_log.enabled() || !state.guest_memory.in_region(guest_pa) {
+        return false;
+    }
+
+    state.shared.dirty_log.mark(guest_pa & !(HPAGE_SIZE - 1), HPAGE_SIZE);
+    state.shadow_page_tables.set_mpa_writable(Some(guest_pa), true);
+    riscv::sfence_vma();
+    true
+}
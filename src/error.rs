@@ -0,0 +1,35 @@
+//! Errors caused by guests accessing devices in ways the hypervisor doesn't emulate.
+//!
+//! None of these are fatal. The faulting access is instead reported to the guest as an access fault
+//! (see `trap::inject_access_fault`), the same as real hardware would do for a bus error.
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HypervisorError {
+    /// A device was accessed by an instruction other than an integer load or store.
+    UnsupportedInstruction,
+    /// A device was accessed with a width or alignment it doesn't support.
+    UnsupportedAccess,
+    /// A device register, or value written to one, that isn't emulated.
+    UnsupportedRegister,
+    /// A value written to a device register was out of range.
+    InvalidValue,
+    /// A guest physical address with neither memory nor a device behind it was accessed.
+    UnmappedAddress,
+}
+
+impl HypervisorError {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            HypervisorError::UnsupportedInstruction => "unsupported instruction",
+            HypervisorError::UnsupportedAccess => "unsupported access width or alignment",
+            HypervisorError::UnsupportedRegister => "unsupported register",
+            HypervisorError::InvalidValue => "invalid register value",
+            HypervisorError::UnmappedAddress => "no memory or device at address",
+        }
+    }
+
+    /// Bit identifying this kind of error in `SharedContext::logged_errors`.
+    pub fn mask(&self) -> u64 {
+        1 << (*self as u64)
+    }
+}
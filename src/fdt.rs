@@ -1,5 +1,6 @@
 use arrayvec::{ArrayString, ArrayVec};
 use core::{mem, ptr};
+use crate::config::Config;
 
 const FDT_BEGIN_NODE: u32 = 0x01000000;
 const FDT_END_NODE: u32 = 0x02000000;
@@ -44,6 +45,13 @@ pub struct Hart {
     pub plic_context: u64,
 }
 
+/// Smallest range of host physical addresses holding the MMIO registers of all `devices`.
+pub fn device_span(devices: &[Device]) -> (u64, u64) {
+    let start = devices.iter().map(|d| d.base_address).min().unwrap_or(0);
+    let end = devices.iter().map(|d| d.base_address + d.size).max().unwrap_or(0);
+    (start, end)
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct MachineMeta {
     pub physical_memory_offset: u64,
@@ -61,6 +69,17 @@ pub struct MachineMeta {
 
     pub initrd_start: u64,
     pub initrd_end: u64,
+
+    pub config: Config,
+}
+
+impl MachineMeta {
+    /// Virtio devices assigned to guest `guestid`: four each, in the order the device tree lists
+    /// them.
+    pub fn guest_virtio(&self, guestid: u64) -> &[Device] {
+        let first = (((guestid - 1) * 4) as usize).min(self.virtio.len());
+        &self.virtio[first..(first + 4).min(self.virtio.len())]
+    }
 }
 
 #[repr(C)]
@@ -185,6 +204,9 @@ impl Fdt {
                 FdtVisit::Property { name, prop } => match (path, name) {
                     (["", "chosen"], "linux,initrd-end") => initrd_end = Some(prop.read_int()),
                     (["", "chosen"], "linux,initrd-start") => initrd_start = Some(prop.read_int()),
+                    (["", "chosen"], "bootargs") => if let Some(bootargs) = prop.value_str() {
+                        meta.config = Config::parse(bootargs.trim_end_matches('\0'));
+                    }
                     (["", "memory"], "reg") => {
                         assert_eq!(prop.len(), 16);
                         let region = prop.address().offset(8) as *const _ as *mut MemoryRegion;
@@ -272,7 +294,7 @@ impl Fdt {
         meta
     }
 
-    pub unsafe fn mask(&self, guest_memory_size: u64) {
+    pub unsafe fn mask(&self, guest_memory_size: u64, guest_harts: u64) {
         self.walk(|path, unit_addresses, v| match v {
             FdtVisit::Property { name, prop } => match (path, name) {
                 (["", "chosen"], "linux,initrd-end") => prop.mask(),
@@ -284,7 +306,8 @@ impl Fdt {
                 _ => {},
             }
             FdtVisit::Node { mask } => *mask = match path {
-                ["", "cpus", "cpu"] => (unit_addresses[2] != "" && unit_addresses[2] != "0"),
+                ["", "cpus", "cpu"] => unit_addresses[2] != "" &&
+                    unit_addresses[2].parse::<u64>().map(|i| i >= guest_harts).unwrap_or(true),
                 ["", "soc", "pci"] => true,
                 ["", "test"] => true,
                 ["", "virtio_mmio"] if unit_addresses[1] == "10005000" => true,
@@ -0,0 +1,223 @@
+//! Lazy switching of floating point state.
+//!
+//! Guests run in U-mode, so the FS field of sstatus that the guest kernel sees is virtual. The real
+//! field stays Off until the guest first uses the floating point registers after they were handed
+//! to it, which traps so that its saved registers can be loaded. From then on the real field is
+//! Clean until the hardware marks it Dirty, which tells the hypervisor both to report Dirty to the
+//! guest and to save the registers before the hart uses them for anything else.
+//!
+//! With the hypervisor extension the hardware implements vsstatus.FS itself, and a vCPU keeps the
+//! registers of the hart it runs on.
+
+use crate::context::Context;
+use crate::trap::constants::{STATUS_FS, STATUS_SD};
+
+pub const FS_OFF: u64 = 0 << 13;
+pub const FS_INITIAL: u64 = 1 << 13;
+pub const FS_CLEAN: u64 = 2 << 13;
+pub const FS_DIRTY: u64 = 3 << 13;
+
+/// Copy of a vCPU's floating point registers. Laid out as expected by `save` and `restore`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Registers {
+    f: [u64; 32],
+    fcsr: u64,
+}
+
+pub struct FpState {
+    registers: Registers,
+    /// Whether the hart's floating point registers hold this vCPU's state.
+    loaded: bool,
+    /// Whether the hart's registers may have been modified since they were loaded or saved.
+    dirty: bool,
+}
+impl FpState {
+    /// State of a newly started vCPU: all registers zero, and nothing loaded.
+    pub fn new() -> Self {
+        Self {
+            registers: Registers { f: [0; 32], fcsr: 0 },
+            loaded: false,
+            dirty: false,
+        }
+    }
+}
+
+/// Value of the guest's sstatus, with FS reporting any modification of the floating point
+/// registers and SD set accordingly.
+pub fn guest_sstatus(state: &mut Context) -> u64 {
+    observe_dirty(state);
+    let sstatus = state.csrs.sstatus & !STATUS_SD;
+    if sstatus & STATUS_FS == FS_DIRTY { sstatus | STATUS_SD } else { sstatus }
+}
+
+/// Change the guest's FS field to `fs`.
+pub fn set_guest_fs(state: &mut Context, fs: u64) {
+    // Any modification has to be noticed before the real field is reset to Clean.
+    observe_dirty(state);
+    state.csrs.sstatus = (state.csrs.sstatus & !STATUS_FS) | (fs & STATUS_FS);
+    update_real_fs(state);
+}
+
+/// Handle an illegal instruction exception caused by the guest using floating point registers that
+/// aren't loaded. Returns false if the exception has some other cause, like the guest itself having
+/// set FS to Off.
+pub fn handle_illegal_instruction(state: &mut Context, instruction: u32) -> bool {
+    if state.fp.loaded || state.csrs.sstatus & STATUS_FS == FS_OFF || !is_fp_instruction(instruction) {
+        return false;
+    }
+
+    set_real_fs(FS_CLEAN);
+    unsafe { restore(&state.fp.registers) }
+    state.fp.loaded = true;
+    state.fp.dirty = false;
+    // Loading the registers marked them Dirty.
+    update_real_fs(state);
+    true
+}
+
+/// Give up the hart's floating point registers, saving their contents first if the guest modified
+/// them.
+pub fn unload(state: &mut Context) {
+    if !state.fp.loaded {
+        return;
+    }
+
+    observe_dirty(state);
+    if state.fp.dirty {
+        set_real_fs(FS_DIRTY);
+        unsafe { save(&mut state.fp.registers) }
+    }
+    state.fp.loaded = false;
+    state.fp.dirty = false;
+    update_real_fs(state);
+}
+
+/// Return the floating point state to that of a newly started vCPU.
+pub fn reset(state: &mut Context) {
+    state.fp = FpState::new();
+    update_real_fs(state);
+}
+
+/// If the hardware has marked the loaded registers Dirty, record that for both the guest and the
+/// hypervisor.
+fn observe_dirty(state: &mut Context) {
+    if state.fp.loaded && csrr!(sstatus) & STATUS_FS == FS_DIRTY {
+        state.fp.dirty = true;
+        state.csrs.sstatus |= FS_DIRTY;
+    }
+}
+
+/// Set the real FS field to match the guest's view: Off unless the guest has enabled floating point
+/// and its registers are loaded, and otherwise Clean, so that the next modification is noticed.
+fn update_real_fs(state: &Context) {
+    if state.fp.loaded && state.csrs.sstatus & STATUS_FS != FS_OFF {
+        set_real_fs(FS_CLEAN);
+    } else {
+        set_real_fs(FS_OFF);
+    }
+}
+
+fn set_real_fs(fs: u64) {
+    unsafe { csrw!(sstatus, fs | (csrr!(sstatus) & !STATUS_FS)) }
+}
+
+/// Whether `instruction` accesses the floating point registers or fcsr.
+fn is_fp_instruction(instruction: u32) -> bool {
+    if instruction & 0x3 != 0x3 {
+        // c.fld, c.fsd, c.fldsp and c.fsdsp.
+        let quadrant = instruction & 0x3;
+        let funct3 = (instruction >> 13) & 0x7;
+        return (quadrant == 0 || quadrant == 2) && (funct3 == 1 || funct3 == 5);
+    }
+
+    match instruction & 0x7f {
+        0x07 | 0x27 | 0x43 | 0x47 | 0x4b | 0x4f | 0x53 => true,
+        // CSR instructions accessing fflags, frm or fcsr.
+        0x73 => {
+            let funct3 = (instruction >> 12) & 0x7;
+            let csr = instruction >> 20;
+            funct3 != 0 && funct3 != 4 && csr >= 0x001 && csr <= 0x003
+        }
+        _ => false,
+    }
+}
+
+// The target doesn't include the D extension, so the loads and stores are encoded by hand. Both
+// require the real FS field not to be Off.
+
+unsafe fn save(registers: &mut Registers) {
+    asm!("
+          .word 0x00053027 // fsd f0, 0(a0)
+          .word 0x00153427 // fsd f1, 8(a0)
+          .word 0x00253827 // fsd f2, 16(a0)
+          .word 0x00353c27 // fsd f3, 24(a0)
+          .word 0x02453027 // fsd f4, 32(a0)
+          .word 0x02553427 // fsd f5, 40(a0)
+          .word 0x02653827 // fsd f6, 48(a0)
+          .word 0x02753c27 // fsd f7, 56(a0)
+          .word 0x04853027 // fsd f8, 64(a0)
+          .word 0x04953427 // fsd f9, 72(a0)
+          .word 0x04a53827 // fsd f10, 80(a0)
+          .word 0x04b53c27 // fsd f11, 88(a0)
+          .word 0x06c53027 // fsd f12, 96(a0)
+          .word 0x06d53427 // fsd f13, 104(a0)
+          .word 0x06e53827 // fsd f14, 112(a0)
+          .word 0x06f53c27 // fsd f15, 120(a0)
+          .word 0x09053027 // fsd f16, 128(a0)
+          .word 0x09153427 // fsd f17, 136(a0)
+          .word 0x09253827 // fsd f18, 144(a0)
+          .word 0x09353c27 // fsd f19, 152(a0)
+          .word 0x0b453027 // fsd f20, 160(a0)
+          .word 0x0b553427 // fsd f21, 168(a0)
+          .word 0x0b653827 // fsd f22, 176(a0)
+          .word 0x0b753c27 // fsd f23, 184(a0)
+          .word 0x0d853027 // fsd f24, 192(a0)
+          .word 0x0d953427 // fsd f25, 200(a0)
+          .word 0x0da53827 // fsd f26, 208(a0)
+          .word 0x0db53c27 // fsd f27, 216(a0)
+          .word 0x0fc53027 // fsd f28, 224(a0)
+          .word 0x0fd53427 // fsd f29, 232(a0)
+          .word 0x0fe53827 // fsd f30, 240(a0)
+          .word 0x0ff53c27 // fsd f31, 248(a0)
+          csrr t0, 0x003
+          sd t0, 256(a0)" :: "{a0}"(registers as *mut Registers) : "t0", "memory" : "volatile");
+}
+
+unsafe fn restore(registers: &Registers) {
+    asm!("
+          .word 0x00053007 // fld f0, 0(a0)
+          .word 0x00853087 // fld f1, 8(a0)
+          .word 0x01053107 // fld f2, 16(a0)
+          .word 0x01853187 // fld f3, 24(a0)
+          .word 0x02053207 // fld f4, 32(a0)
+          .word 0x02853287 // fld f5, 40(a0)
+          .word 0x03053307 // fld f6, 48(a0)
+          .word 0x03853387 // fld f7, 56(a0)
+          .word 0x04053407 // fld f8, 64(a0)
+          .word 0x04853487 // fld f9, 72(a0)
+          .word 0x05053507 // fld f10, 80(a0)
+          .word 0x05853587 // fld f11, 88(a0)
+          .word 0x06053607 // fld f12, 96(a0)
+          .word 0x06853687 // fld f13, 104(a0)
+          .word 0x07053707 // fld f14, 112(a0)
+          .word 0x07853787 // fld f15, 120(a0)
+          .word 0x08053807 // fld f16, 128(a0)
+          .word 0x08853887 // fld f17, 136(a0)
+          .word 0x09053907 // fld f18, 144(a0)
+          .word 0x09853987 // fld f19, 152(a0)
+          .word 0x0a053a07 // fld f20, 160(a0)
+          .word 0x0a853a87 // fld f21, 168(a0)
+          .word 0x0b053b07 // fld f22, 176(a0)
+          .word 0x0b853b87 // fld f23, 184(a0)
+          .word 0x0c053c07 // fld f24, 192(a0)
+          .word 0x0c853c87 // fld f25, 200(a0)
+          .word 0x0d053d07 // fld f26, 208(a0)
+          .word 0x0d853d87 // fld f27, 216(a0)
+          .word 0x0e053e07 // fld f28, 224(a0)
+          .word 0x0e853e87 // fld f29, 232(a0)
+          .word 0x0f053f07 // fld f30, 240(a0)
+          .word 0x0f853f87 // fld f31, 248(a0)
+          ld t0, 256(a0)
+          csrw 0x003, t0" :: "{a0}"(registers as *const Registers) : "t0", "memory" : "volatile");
+}
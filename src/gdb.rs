@@ -0,0 +1,543 @@
+//! GDB remote serial protocol stub, for debugging a guest from its own point of view.
+//!
+//! The stub is attached to a single guest (`rvirt.gdb`) and talks to GDB over a host UART set aside
+//! for it (`rvirt.gdb-uart`). Registers are those of the vCPU that stopped, and memory is accessed at
+//! guest virtual addresses by walking the guest's own page tables, so GDB sees what the guest kernel
+//! sees rather than the shadow page tables.
+//!
+//! While the guest is stopped, the vCPU that stopped runs the protocol and every other vCPU spins in
+//! `poll`. Breakpoints are only patched into guest memory while the guest is running, and are all
+//! removed again whenever it stops. Single stepping places temporary breakpoints at each possible
+//! successor of the current instruction, and keeps the other vCPUs stopped until one is reached.
+
+use arrayvec::ArrayVec;
+use core::sync::atomic::Ordering;
+use crate::context::{Context, REQUEST_FENCE_I};
+use crate::fdt::UartType;
+use crate::guest_memory::{AccessType, GuestMemory};
+use crate::print::{UartWriter, UartWriterInner};
+use crate::trap::constants::TVEC_BASE;
+use crate::{pmap, riscv, trap};
+
+const MAX_BREAKPOINTS: usize = 32;
+/// Largest packet we accept from GDB. Must be able to hold a `G` packet.
+const PACKET_SIZE: usize = 1024;
+
+const EBREAK: u32 = 0x00100073;
+const C_EBREAK: u32 = 0x9002;
+const SRET: u32 = 0x10200073;
+
+// Signals reported to GDB in stop replies.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// An instruction replaced by an ebreak while the guest runs.
+struct Patch {
+    va: u64,
+    pa: u64,
+    /// Original contents of the patched bytes, and how many there are (2 or 4).
+    original: u32,
+    len: u64,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Step {
+    /// Not single stepping.
+    None,
+    /// Single stepping on behalf of GDB. Reaching the next instruction is reported.
+    Report,
+    /// Stepping off a breakpoint before continuing. Reaching the next instruction isn't reported
+    /// unless there is a breakpoint there too.
+    Continue,
+}
+
+pub struct Debugger {
+    uart: UartWriter,
+    /// Guest virtual addresses GDB has placed breakpoints at.
+    breakpoints: ArrayVec<[u64; MAX_BREAKPOINTS]>,
+    /// Either the breakpoints or the single step targets, whichever are currently patched into guest
+    /// memory.
+    patches: ArrayVec<[Patch; MAX_BREAKPOINTS]>,
+    step: Step,
+    /// Signal the guest last stopped with, for the `?` packet.
+    signal: u8,
+    packet: ArrayVec<[u8; PACKET_SIZE]>,
+}
+
+impl Debugger {
+    pub fn new(address: u64, ty: UartType) -> Self {
+        Self {
+            uart: UartWriter {
+                pa: address,
+                va: Some(pmap::pa2va(address)),
+                inner: match ty {
+                    UartType::Ns16550a => UartWriterInner::Ns16550a { initialized: false },
+                    UartType::SiFive => UartWriterInner::SiFive,
+                },
+            },
+            breakpoints: ArrayVec::new(),
+            patches: ArrayVec::new(),
+            step: Step::None,
+            signal: SIGTRAP,
+            packet: ArrayVec::new(),
+        }
+    }
+
+    fn getchar(&mut self) -> u8 {
+        loop {
+            if let Some(ch) = self.uart.getchar() {
+                return ch;
+            }
+        }
+    }
+
+    /// Receive the next packet from GDB into `self.packet`, acknowledging it. If `started` is set
+    /// the leading '$' has already been consumed.
+    fn receive_packet(&mut self, mut started: bool) {
+        loop {
+            if !started {
+                while self.getchar() != b'$' {}
+            }
+            started = false;
+
+            self.packet.clear();
+            let mut checksum = 0u8;
+            let mut overflow = false;
+            loop {
+                match self.getchar() {
+                    b'#' => break,
+                    ch => {
+                        checksum = checksum.wrapping_add(ch);
+                        overflow |= self.packet.try_push(ch).is_err();
+                    }
+                }
+            }
+
+            let expected = [self.getchar(), self.getchar()];
+            if !overflow && parse_hex(&expected) == Some(checksum as u64) {
+                self.uart.putchar(b'+');
+                return;
+            }
+            self.uart.putchar(b'-');
+        }
+    }
+
+    /// Send `data` as a packet, retransmitting it until GDB acknowledges it.
+    fn send_packet(&mut self, data: &[u8]) {
+        loop {
+            self.uart.putchar(b'$');
+            let mut checksum = 0u8;
+            for &ch in data {
+                self.uart.putchar(ch);
+                checksum = checksum.wrapping_add(ch);
+            }
+            self.uart.putchar(b'#');
+            self.uart.putchar(HEX_DIGITS[(checksum >> 4) as usize]);
+            self.uart.putchar(HEX_DIGITS[(checksum & 0xf) as usize]);
+
+            if self.getchar() != b'-' {
+                return;
+            }
+        }
+    }
+
+    fn send_stop_reply(&mut self) {
+        let signal = self.signal;
+        self.send_packet(&[b'S', HEX_DIGITS[(signal >> 4) as usize], HEX_DIGITS[(signal & 0xf) as usize]]);
+    }
+
+    /// Stop the guest and serve GDB until it resumes the guest or detaches. `started` is set if GDB
+    /// has already begun sending a packet, in which case no stop reply is sent.
+    fn stop(&mut self, state: &mut Context, signal: u8, started: bool) {
+        let shared = state.shared;
+        shared.debug_halt.store(state.vcpu as u64 + 1, Ordering::SeqCst);
+        for i in (0..shared.vcpus.len()).filter(|&i| i != state.vcpu) {
+            shared.kick(i);
+        }
+
+        self.remove_patches(state);
+        self.step = Step::None;
+        self.signal = signal;
+        if !started {
+            self.send_stop_reply();
+        }
+
+        let mut started = started;
+        loop {
+            self.receive_packet(started);
+            started = false;
+
+            let mut reply = ArrayVec::<[u8; PACKET_SIZE]>::new();
+            let packet = self.packet.clone();
+            let (command, args) = match packet.split_first() {
+                Some((&command, args)) => (command, args),
+                None => (0, &[][..]),
+            };
+            match command {
+                b'?' => {
+                    self.send_stop_reply();
+                    continue;
+                }
+                b'g' => {
+                    for reg in 0..32 {
+                        let value = trap::get_register(state, reg);
+                        push_hex_le(&mut reply, value, 8);
+                    }
+                    push_hex_le(&mut reply, csrr!(sepc), 8);
+                }
+                b'G' => {
+                    if args.len() < 33 * 16 {
+                        reply_error(&mut reply);
+                    } else {
+                        for (reg, chunk) in args.chunks(16).take(33).enumerate() {
+                            if let Some(value) = parse_hex_le(chunk) {
+                                set_register(state, reg as u64, value);
+                            }
+                        }
+                        reply_ok(&mut reply);
+                    }
+                }
+                b'p' => match parse_hex(args) {
+                    Some(reg) if reg < 32 => push_hex_le(&mut reply, trap::get_register(state, reg as u32), 8),
+                    Some(32) => push_hex_le(&mut reply, csrr!(sepc), 8),
+                    _ => reply_error(&mut reply),
+                }
+                b'P' => {
+                    let mut parts = args.splitn(2, |&ch| ch == b'=');
+                    match (parts.next().and_then(parse_hex), parts.next().and_then(parse_hex_le)) {
+                        (Some(reg), Some(value)) if reg <= 32 => {
+                            set_register(state, reg, value);
+                            reply_ok(&mut reply);
+                        }
+                        _ => reply_error(&mut reply),
+                    }
+                }
+                b'm' => match parse_address_length(args) {
+                    Some((addr, len)) => {
+                        let mut memory = GuestMemory::unchecked(state);
+                        let mut byte = [0];
+                        for va in addr..addr.saturating_add(len.min(PACKET_SIZE as u64 / 2)) {
+                            match memory.read(va, &mut byte) {
+                                Ok(()) => push_hex_le(&mut reply, byte[0] as u64, 1),
+                                Err(_) => break,
+                            }
+                        }
+                        if reply.is_empty() && len > 0 {
+                            reply_error(&mut reply);
+                        }
+                    }
+                    None => reply_error(&mut reply),
+                }
+                b'M' => {
+                    let mut parts = args.splitn(2, |&ch| ch == b':');
+                    match (parts.next().and_then(parse_address_length), parts.next()) {
+                        (Some((addr, len)), Some(data)) if data.len() as u64 == 2 * len => {
+                            let mut memory = GuestMemory::unchecked(state);
+                            let mut ok = true;
+                            for (va, byte) in (addr..).zip(data.chunks(2)) {
+                                let written = parse_hex(byte).map(|value| memory.write(va, &[value as u8]).is_ok());
+                                if written != Some(true) {
+                                    ok = false;
+                                    break;
+                                }
+                            }
+                            if ok { reply_ok(&mut reply) } else { reply_error(&mut reply) }
+                        }
+                        _ => reply_error(&mut reply),
+                    }
+                }
+                b'Z' | b'z' if args.starts_with(b"0,") => {
+                    match parse_address_length(&args[2..]).map(|(addr, _kind)| addr) {
+                        Some(addr) if command == b'z' => {
+                            self.breakpoints.retain(|&mut va| va != addr);
+                            reply_ok(&mut reply);
+                        }
+                        Some(addr) if self.breakpoints.contains(&addr) => reply_ok(&mut reply),
+                        Some(addr) => match self.breakpoints.try_push(addr) {
+                            Ok(()) => reply_ok(&mut reply),
+                            Err(_) => reply_error(&mut reply),
+                        }
+                        None => reply_error(&mut reply),
+                    }
+                }
+                b'c' | b's' => {
+                    if let Some(addr) = parse_hex(args) {
+                        riscv::set_sepc(addr);
+                    }
+                    self.resume(state, command == b's');
+                    return;
+                }
+                b'D' => {
+                    self.breakpoints.clear();
+                    self.send_packet(b"OK");
+                    self.resume(state, false);
+                    return;
+                }
+                b'k' => {
+                    // There is nothing to kill, so just let the guest carry on without the debugger.
+                    self.breakpoints.clear();
+                    self.resume(state, false);
+                    return;
+                }
+                b'H' => reply_ok(&mut reply),
+                b'q' if args.starts_with(b"Supported") => reply.extend(b"PacketSize=400".iter().cloned()),
+                b'q' if args.starts_with(b"Attached") => reply.push(b'1'),
+                _ => {} // Unsupported packets get an empty reply.
+            }
+            self.send_packet(&reply);
+        }
+    }
+
+    /// Patch breakpoints into guest memory and let the guest run again.
+    fn resume(&mut self, state: &mut Context, single_step: bool) {
+        let pc = csrr!(sepc);
+        if single_step {
+            self.insert_step_breakpoints(state, pc);
+            self.step = Step::Report;
+        } else if self.breakpoints.contains(&pc) {
+            self.insert_step_breakpoints(state, pc);
+            self.step = Step::Continue;
+        } else {
+            self.insert_breakpoints(state);
+            self.step = Step::None;
+        }
+
+        riscv::fence_i();
+        state.remote_request(!0, REQUEST_FENCE_I);
+
+        // While stepping only this vCPU runs, so that no other vCPU can hit a temporary breakpoint.
+        if self.step == Step::None {
+            state.shared.debug_halt.store(0, Ordering::SeqCst);
+        }
+    }
+
+    fn insert_breakpoints(&mut self, state: &mut Context) {
+        for i in 0..self.breakpoints.len() {
+            let va = self.breakpoints[i];
+            self.insert_patch(state, va);
+        }
+    }
+
+    /// Place temporary breakpoints at every instruction that could execute after the one at `pc`.
+    fn insert_step_breakpoints(&mut self, state: &mut Context, pc: u64) {
+        let (instruction, len) = match read_instruction(state, pc) {
+            Some(instruction) => instruction,
+            None => return,
+        };
+
+        self.insert_patch(state, pc + len);
+        // Any instruction might trap or be interrupted, after which the guest continues at stvec.
+        let stvec = state.csrs.stvec & TVEC_BASE;
+        self.insert_patch(state, stvec);
+
+        let rs1 = (instruction >> 15) & 0x1f;
+        let target = if len == 4 {
+            match instruction & 0x7f {
+                0x6f => { // jal
+                    let imm = ((instruction >> 31) & 0x1) << 20 | ((instruction >> 21) & 0x3ff) << 1
+                        | ((instruction >> 20) & 0x1) << 11 | ((instruction >> 12) & 0xff) << 12;
+                    Some(pc.wrapping_add(sign_extend(imm as u64, 21)))
+                }
+                0x67 => { // jalr
+                    let imm = sign_extend((instruction >> 20) as u64, 12);
+                    Some(trap::get_register(state, rs1).wrapping_add(imm) & !0x1)
+                }
+                0x63 => { // branches
+                    let imm = ((instruction >> 31) & 0x1) << 12 | ((instruction >> 25) & 0x3f) << 5
+                        | ((instruction >> 8) & 0xf) << 1 | ((instruction >> 7) & 0x1) << 11;
+                    Some(pc.wrapping_add(sign_extend(imm as u64, 13)))
+                }
+                _ if instruction == SRET => Some(state.csrs.sepc),
+                _ => None,
+            }
+        } else {
+            let rs1 = (instruction >> 7) & 0x1f;
+            match (instruction & 0x3, (instruction >> 13) & 0x7) {
+                (0b01, 0b101) => { // c.j
+                    let imm = ((instruction >> 12) & 0x1) << 11 | ((instruction >> 11) & 0x1) << 4
+                        | ((instruction >> 9) & 0x3) << 8 | ((instruction >> 8) & 0x1) << 10
+                        | ((instruction >> 7) & 0x1) << 6 | ((instruction >> 6) & 0x1) << 7
+                        | ((instruction >> 3) & 0x7) << 1 | ((instruction >> 2) & 0x1) << 5;
+                    Some(pc.wrapping_add(sign_extend(imm as u64, 12)))
+                }
+                (0b01, 0b110) | (0b01, 0b111) => { // c.beqz, c.bnez
+                    let imm = ((instruction >> 12) & 0x1) << 8 | ((instruction >> 10) & 0x3) << 3
+                        | ((instruction >> 5) & 0x3) << 6 | ((instruction >> 3) & 0x3) << 1
+                        | ((instruction >> 2) & 0x1) << 5;
+                    Some(pc.wrapping_add(sign_extend(imm as u64, 9)))
+                }
+                (0b10, 0b100) if (instruction >> 2) & 0x1f == 0 && rs1 != 0 => { // c.jr, c.jalr
+                    Some(trap::get_register(state, rs1) & !0x1)
+                }
+                _ => None,
+            }
+        };
+        if let Some(target) = target {
+            self.insert_patch(state, target);
+        }
+    }
+
+    /// Replace the instruction at `va` with an ebreak. Addresses that aren't mapped to guest memory,
+    /// or are already patched, are skipped.
+    fn insert_patch(&mut self, state: &mut Context, va: u64) {
+        if va & 0x1 != 0 || self.patches.is_full() || self.patches.iter().any(|p| p.va == va) {
+            return;
+        }
+        let mut memory = GuestMemory::unchecked(state);
+        let pa = match memory.translate(va, AccessType::Execute) {
+            Ok(pa) => pa,
+            Err(_) => return,
+        };
+
+        // A full size ebreak is only used where it can't straddle a page. Anywhere else, the guest
+        // must be using compressed instructions so c.ebreak is available.
+        let mut bytes = [0; 4];
+        if memory.read_phys(pa, &mut bytes[..2]).is_err() {
+            return;
+        }
+        let (ebreak, len) = if bytes[0] & 0x3 == 0x3 && va & 0x3 == 0 {
+            (EBREAK, 4)
+        } else {
+            (C_EBREAK, 2)
+        };
+
+        if memory.read_phys(pa, &mut bytes[..len as usize]).is_err() ||
+            memory.write_phys(pa, &ebreak.to_le_bytes()[..len as usize]).is_err() {
+            return;
+        }
+        self.patches.push(Patch { va, pa, original: u32::from_le_bytes(bytes), len });
+    }
+
+    fn remove_patches(&mut self, state: &mut Context) {
+        let mut memory = GuestMemory::unchecked(state);
+        for patch in self.patches.drain(..) {
+            let _ = memory.write_phys(patch.pa, &patch.original.to_le_bytes()[..patch.len as usize]);
+        }
+        riscv::fence_i();
+    }
+}
+
+/// Called at the end of every trap. Waits while another vCPU has the guest stopped, and otherwise
+/// stops the guest if GDB has sent anything.
+pub fn poll(state: &mut Context) {
+    let shared = state.shared;
+    let debugger = match shared.debugger {
+        Some(ref debugger) => debugger,
+        None => return,
+    };
+
+    loop {
+        let owner = shared.debug_halt.load(Ordering::SeqCst);
+        if owner == 0 || owner == state.vcpu as u64 + 1 {
+            break;
+        }
+        state.process_requests();
+    }
+
+    if let Some(mut debugger) = debugger.try_lock() {
+        match debugger.uart.getchar() {
+            Some(0x03) => debugger.stop(state, SIGINT, false),
+            Some(b'$') => debugger.stop(state, SIGINT, true),
+            _ => {}
+        }
+    }
+}
+
+/// Handle a breakpoint exception. Returns false if the guest executed an ebreak of its own, which
+/// should be forwarded to it.
+pub fn handle_breakpoint(state: &mut Context) -> bool {
+    let shared = state.shared;
+    let debugger = match shared.debugger {
+        Some(ref debugger) => debugger,
+        None => return false,
+    };
+    let mut debugger = loop {
+        if let Some(debugger) = debugger.try_lock() {
+            break debugger;
+        }
+        state.process_requests();
+    };
+
+    let pc = csrr!(sepc);
+    if !debugger.patches.iter().any(|p| p.va == pc) {
+        // If the instruction is no longer an ebreak, this vCPU hit a breakpoint that has since been
+        // removed and only needs to execute the original instruction.
+        return match read_instruction(state, pc) {
+            Some((instruction, _)) => instruction != EBREAK && instruction != C_EBREAK,
+            None => false,
+        };
+    }
+
+    if debugger.step == Step::Continue && !debugger.breakpoints.contains(&pc) {
+        debugger.remove_patches(state);
+        debugger.resume(state, false);
+    } else {
+        debugger.stop(state, SIGTRAP, false);
+    }
+    true
+}
+
+/// Read the instruction at guest virtual address `va`, returning it along with its length.
+fn read_instruction(state: &mut Context, va: u64) -> Option<(u32, u64)> {
+    GuestMemory::unchecked(state).read_instruction(va).ok()
+}
+
+/// Set register `reg` in GDB's numbering, where 32 is the pc.
+fn set_register(state: &mut Context, reg: u64, value: u64) {
+    match reg {
+        0..=31 => trap::set_register(state, reg as u32, value),
+        32 => riscv::set_sepc(value),
+        _ => {}
+    }
+}
+
+fn sign_extend(value: u64, bits: u64) -> u64 {
+    (((value << (64 - bits)) as i64) >> (64 - bits)) as u64
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    let mut value = 0;
+    for &digit in digits {
+        value = value << 4 | (digit as char).to_digit(16)? as u64;
+    }
+    Some(value)
+}
+
+/// Parse a value sent as little endian bytes, the way GDB transfers register contents.
+fn parse_hex_le(digits: &[u8]) -> Option<u64> {
+    if digits.len() % 2 != 0 || digits.len() > 16 {
+        return None;
+    }
+    let mut value = 0;
+    for (i, byte) in digits.chunks(2).enumerate() {
+        value |= parse_hex(byte)? << (8 * i);
+    }
+    Some(value)
+}
+
+/// Parse the `addr,length` arguments of a memory or breakpoint packet.
+fn parse_address_length(args: &[u8]) -> Option<(u64, u64)> {
+    let mut parts = args.splitn(2, |&ch| ch == b',');
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+fn push_hex_le(reply: &mut ArrayVec<[u8; PACKET_SIZE]>, value: u64, bytes: u64) {
+    for i in 0..bytes {
+        let byte = (value >> (8 * i)) as u8;
+        reply.push(HEX_DIGITS[(byte >> 4) as usize]);
+        reply.push(HEX_DIGITS[(byte & 0xf) as usize]);
+    }
+}
+
+fn reply_ok(reply: &mut ArrayVec<[u8; PACKET_SIZE]>) {
+    reply.extend(b"OK".iter().cloned());
+}
+
+fn reply_error(reply: &mut ArrayVec<[u8; PACKET_SIZE]>) {
+    reply.extend(b"E01".iter().cloned());
+}
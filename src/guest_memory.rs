@@ -0,0 +1,243 @@
+//! Access to guest memory for the parts of the hypervisor that act on behalf of a guest or inspect
+//! it, like device models, the debugger and the monitor.
+//!
+//! Guest virtual addresses are translated through the guest's own page tables (those selected by
+//! its satp), and checked against the permissions of the vCPU's current privilege mode unless the
+//! accessor was created with `GuestMemory::unchecked`. Accesses can be of any length and cross
+//! page boundaries. Addresses that aren't mapped, or that lie outside of guest memory, are reported
+//! as an `AccessError` rather than faulting the hypervisor.
+//!
+//! Checked accesses set the accessed and dirty bits of the guest page table entries they go
+//! through, as the vCPU's own accesses would. Writes are recorded in the dirty log (see dirty.rs),
+//! and writes to guest page tables invalidate the shadow mappings derived from them (see
+//! ptwatch.rs).
+
+use crate::context::Context;
+use crate::pmap::{self, pte_flags::*};
+use crate::ptwatch;
+use crate::trap::constants::{STATUS_MXR, STATUS_SUM};
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AccessError {
+    /// The guest virtual address isn't mapped, or not with the permissions the access needs.
+    PageFault(u64),
+    /// The guest physical address isn't in guest memory.
+    NotMemory(u64),
+    /// An instruction was read from a guest virtual address that isn't 2 byte aligned.
+    Misaligned(u64),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AccessType {
+    Read,
+    Write,
+    Execute,
+}
+
+/// What a vCPU in a given privilege mode may access through its page tables.
+#[derive(Copy, Clone)]
+struct Privilege {
+    user: bool,
+    /// Whether S-mode may read and write pages accessible to U-mode.
+    sum: bool,
+    /// Whether pages that are only executable may be read.
+    mxr: bool,
+}
+impl Privilege {
+    fn allows(&self, pte: u64, access: AccessType) -> bool {
+        let perm = match access {
+            AccessType::Read if self.mxr => PTE_READ | PTE_EXECUTE,
+            AccessType::Read => PTE_READ,
+            AccessType::Write => PTE_WRITE,
+            AccessType::Execute => PTE_EXECUTE,
+        };
+        if pte & perm == 0 {
+            return false;
+        }
+
+        if self.user {
+            pte & PTE_USER != 0
+        } else {
+            pte & PTE_USER == 0 || (self.sum && access != AccessType::Execute)
+        }
+    }
+}
+
+/// Guest memory as seen by one vCPU.
+pub struct GuestMemory<'a> {
+    state: &'a mut Context,
+    /// Permissions to check accesses through virtual addresses against, if any.
+    privilege: Option<Privilege>,
+}
+impl<'a> GuestMemory<'a> {
+    /// Access guest memory with the permissions `state`'s vCPU currently has.
+    pub fn new(state: &'a mut Context) -> Self {
+        let privilege = Privilege {
+            user: !state.smode,
+            sum: state.csrs.sstatus & STATUS_SUM != 0,
+            mxr: state.csrs.sstatus & STATUS_MXR != 0,
+        };
+        let mut memory = Self::unchecked(state);
+        memory.privilege = Some(privilege);
+        memory
+    }
+
+    /// Access guest memory through `state`'s page tables, ignoring their permissions, as a debugger
+    /// does.
+    pub fn unchecked(state: &'a mut Context) -> Self {
+        Self { state, privilege: None }
+    }
+
+    /// Translate guest virtual address `va` to a guest physical address in guest memory, for an
+    /// access of the given type. Addresses are used as is while guest paging is disabled.
+    pub fn translate(&mut self, va: u64, access: AccessType) -> Result<u64, AccessError> {
+        self.translate_as(va, access, true)
+    }
+
+    /// Fill `buf` from guest virtual memory starting at `va`.
+    pub fn read(&mut self, va: u64, buf: &mut [u8]) -> Result<(), AccessError> {
+        self.read_as(va, buf, AccessType::Read)
+    }
+
+    /// Write `data` to guest virtual memory starting at `va`. Nothing is written unless all of it
+    /// can be.
+    pub fn write(&mut self, va: u64, data: &[u8]) -> Result<(), AccessError> {
+        let mut done = 0;
+        while done < data.len() {
+            let (va, len) = next_chunk(va, done, data.len());
+            self.translate_as(va, AccessType::Write, false)?;
+            done += len;
+        }
+
+        let mut done = 0;
+        while done < data.len() {
+            let (va, len) = next_chunk(va, done, data.len());
+            let pa = self.translate(va, AccessType::Write)?;
+            self.write_phys(pa, &data[done..(done + len)])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn read_u64(&mut self, va: u64) -> Result<u64, AccessError> {
+        let mut bytes = [0; 8];
+        self.read(va, &mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Read the instruction at guest virtual address `va`, returning it along with its length.
+    pub fn read_instruction(&mut self, va: u64) -> Result<(u32, u64), AccessError> {
+        if va & 0x1 != 0 {
+            return Err(AccessError::Misaligned(va));
+        }
+
+        let mut bytes = [0; 4];
+        self.read_as(va, &mut bytes[..2], AccessType::Execute)?;
+        if bytes[0] & 0x3 != 0x3 {
+            return Ok((u32::from_le_bytes(bytes), 2));
+        }
+        self.read_as(va + 2, &mut bytes[2..], AccessType::Execute)?;
+        Ok((u32::from_le_bytes(bytes), 4))
+    }
+
+    /// Fill `buf` from guest physical memory starting at `pa`.
+    pub fn read_phys(&self, pa: u64, buf: &mut [u8]) -> Result<(), AccessError> {
+        self.check_phys(pa, buf.len())?;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let pa = pa + i as u64;
+            *byte = (self.state.guest_memory[pa & !0x7] >> (8 * (pa & 0x7))) as u8;
+        }
+        Ok(())
+    }
+
+    /// Write `data` to guest physical memory starting at `pa`. Nothing is written unless all of it
+    /// is in guest memory.
+    pub fn write_phys(&mut self, pa: u64, data: &[u8]) -> Result<(), AccessError> {
+        self.check_phys(pa, data.len())?;
+        for (i, &byte) in data.iter().enumerate() {
+            let pa = pa + i as u64;
+            let shift = 8 * (pa & 0x7);
+            let word = &mut self.state.guest_memory[pa & !0x7];
+            *word = (*word & !(0xff << shift)) | (byte as u64) << shift;
+        }
+        self.state.shared.dirty_log.mark(pa, data.len() as u64);
+        ptwatch::handle_hypervisor_write(self.state, pa, data.len() as u64);
+        Ok(())
+    }
+
+    /// Translate `va` for an access of the given type, like `translate`. The accessed and dirty bits
+    /// are only set if `update` is true.
+    fn translate_as(&mut self, va: u64, access: AccessType, update: bool) -> Result<u64, AccessError> {
+        let satp = self.state.csrs.satp;
+        let pa = match pmap::paging_levels(satp) {
+            Some(_) => loop {
+                let translation = pmap::translate_guest_address(&self.state.guest_memory, satp, va & !0xfff)
+                    .ok_or(AccessError::PageFault(va))?;
+                let pa = (translation.guest_pa & !0xfff) | (va & 0xfff);
+                let privilege = match self.privilege {
+                    Some(privilege) => privilege,
+                    None => break pa,
+                };
+                if !privilege.allows(translation.pte_value, access) {
+                    return Err(AccessError::PageFault(va));
+                }
+                if !update || !self.state.guest_memory.in_region(pa) {
+                    break pa;
+                }
+
+                // Set the A and D bits, retrying the walk if the guest changes the entry first.
+                let new_pte = match access {
+                    AccessType::Write => translation.pte_value | PTE_AD,
+                    _ => translation.pte_value | PTE_ACCESSED,
+                };
+                if new_pte != translation.pte_value {
+                    if !pmap::compare_and_swap_guest_pte(&mut self.state.guest_memory, translation.pte_addr,
+                                                        translation.pte_value, new_pte) {
+                        continue;
+                    }
+                    self.state.shared.dirty_log.mark(translation.pte_addr, 8);
+                }
+                break pa;
+            },
+            None => va,
+        };
+
+        if self.state.guest_memory.in_region(pa) { Ok(pa) } else { Err(AccessError::NotMemory(pa)) }
+    }
+
+    fn read_as(&mut self, va: u64, buf: &mut [u8], access: AccessType) -> Result<(), AccessError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let (va, len) = next_chunk(va, done, buf.len());
+            let pa = self.translate(va, access)?;
+            self.read_phys(pa, &mut buf[done..(done + len)])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Check that the `len` bytes at `pa` are all in guest memory.
+    fn check_phys(&self, pa: u64, len: usize) -> Result<(), AccessError> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        let last = pa.wrapping_add(len as u64 - 1);
+        if !self.state.guest_memory.in_region(pa) {
+            Err(AccessError::NotMemory(pa))
+        } else if last < pa || !self.state.guest_memory.in_region(last) {
+            Err(AccessError::NotMemory(last))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Address and length of the part of a `len` byte access at `va` that follows its first `done`
+/// bytes and lies on a single page.
+fn next_chunk(va: u64, done: usize, len: usize) -> (u64, usize) {
+    let va = va.wrapping_add(done as u64);
+    (va, ((PAGE_SIZE - (va & 0xfff)) as usize).min(len - done))
+}
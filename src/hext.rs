@@ -0,0 +1,346 @@
+//! Execution backend for hosts implementing the RISC-V hypervisor extension.
+//!
+//! Without the extension, guests run in U-mode: every privileged instruction traps so that it can
+//! be emulated, and the hypervisor maintains shadow page tables on the guest's behalf (see pmap.rs
+//! and pfault.rs). With it, guests run in VS-mode, where the hardware provides their supervisor
+//! CSRs and walks their page tables itself. Guest physical addresses are translated by per-vCPU
+//! G-stage page tables, leaving as exits only SBI calls, host interrupts, and accesses to guest
+//! physical addresses the G-stage doesn't map: emulated devices and pages holding virtio queues.
+//! Those go through the same device models as with the other backend.
+//!
+//! The backend is chosen at boot, by the M-mode code probing misa.
+
+use core::ptr;
+use core::sync::atomic::Ordering;
+use crate::context::Context;
+use crate::error::HypervisorError;
+use crate::guest_memory::GuestMemory;
+use crate::memory_region::MemoryRegion;
+use crate::pmap::{pa2va, DIRECT_MAP_PAGES};
+use crate::pmap::pte_flags::*;
+use crate::statics::SHARED_STATICS;
+use crate::stats::ExitReason;
+use crate::trap::constants::*;
+use crate::trap::{self, U64Bits};
+use crate::{gdb, pfault, riscv, sbi, virtio};
+
+pub const HSTATUS_SPV: u64 = 1 << 7;
+
+pub const IP_VSSIP: u64 = 1 << 2;
+pub const IP_VSTIP: u64 = 1 << 6;
+pub const IP_VSEIP: u64 = 1 << 10;
+
+const SCAUSE_VIRTUAL_SUPERVISOR_ENV_CALL: u64 = 10;
+const SCAUSE_INSN_GUEST_PAGE_FAULT: u64 = 20;
+const SCAUSE_LOAD_GUEST_PAGE_FAULT: u64 = 21;
+const SCAUSE_VIRTUAL_INSN: u64 = 22;
+const SCAUSE_STORE_GUEST_PAGE_FAULT: u64 = 23;
+
+/// Exceptions that go straight to the guest: misaligned accesses, access faults, illegal
+/// instructions, breakpoints, environment calls from VU-mode and page faults.
+const GUEST_EXCEPTIONS: u64 = 0xb1ff;
+
+const HGATP_MODE_SV39X4: u64 = 8 << 60;
+
+const PAGE_SIZE: u64 = 4096;
+const HPAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Size of an Sv39x4 root page table, which must also be aligned to its size.
+const ROOT_SIZE: u64 = 16 * 1024;
+
+/// Number of G-stage leaf page tables per vCPU. Only the 2 MB regions of guest memory that hold
+/// virtio queues need one, since the rest are mapped with superpages.
+const LEAF_TABLES: u64 = 16;
+
+/// Maximum number of gigabytes of guest physical address space that guest memory may span, each of
+/// which needs a level 1 table. Guest memory is never larger than the host memory in the direct map
+/// (see pmap::layout_guests), and may start partway into a gigabyte.
+const MAX_GIGAPAGES: u64 = DIRECT_MAP_PAGES + 1;
+
+/// Memory needed for the G-stage page tables of one vCPU: the root, the level 1 tables covering
+/// guest memory, and the leaf tables.
+pub const GSTAGE_SIZE: u64 = ROOT_SIZE + MAX_GIGAPAGES * PAGE_SIZE + LEAF_TABLES * PAGE_SIZE;
+pub const GSTAGE_ALIGN: u64 = ROOT_SIZE;
+
+/// Whether guests run in VS-mode.
+pub fn enabled() -> bool {
+    SHARED_STATICS.hypervisor_extension.load(Ordering::Relaxed)
+}
+
+/// G-stage page tables of one vCPU, translating guest physical addresses to host physical ones.
+///
+/// They start out empty and are filled in as the guest touches its memory. Pages holding virtio
+/// queues stay unmapped so that accesses to them still trap, which means the page tables have to be
+/// flushed whenever the set of those pages changes, just like shadow page tables.
+pub struct GStage {
+    /// Host physical address of the root page table. The level 1 tables and the leaf tables follow.
+    root: u64,
+    /// First gigabyte of guest physical address space that guest memory occupies, and how many.
+    first_gigapage: u64,
+    gigapages: u64,
+    /// Number of leaf tables in use.
+    leaf_tables_used: u64,
+    guest_shift: u64,
+}
+impl GStage {
+    /// Create empty G-stage page tables in the `GSTAGE_SIZE` bytes of memory at `base_pa`.
+    pub unsafe fn new(base_pa: u64, guest_memory: &MemoryRegion, guest_shift: u64) -> Self {
+        assert_eq!(base_pa % GSTAGE_ALIGN, 0);
+
+        let first_gigapage = guest_memory.base() >> 30;
+        let gigapages = ((guest_memory.base() + guest_memory.len() - 1) >> 30) - first_gigapage + 1;
+        assert!(gigapages <= MAX_GIGAPAGES);
+
+        ptr::write_bytes(pa2va(base_pa) as *mut u8, 0, GSTAGE_SIZE as usize);
+        let gstage = Self {
+            root: base_pa,
+            first_gigapage,
+            gigapages,
+            leaf_tables_used: 0,
+            guest_shift,
+        };
+        for gigapage in first_gigapage..(first_gigapage + gigapages) {
+            gstage.set_pte(base_pa + gigapage * 8, (gstage.level1(gigapage << 30) >> 2) | PTE_VALID);
+        }
+        gstage
+    }
+
+    /// Level 1 table covering `guest_pa`.
+    fn level1(&self, guest_pa: u64) -> u64 {
+        self.root + ROOT_SIZE + ((guest_pa >> 30) - self.first_gigapage) * PAGE_SIZE
+    }
+
+    fn leaf_table(&self, index: u64) -> u64 {
+        self.root + ROOT_SIZE + MAX_GIGAPAGES * PAGE_SIZE + index * PAGE_SIZE
+    }
+
+    fn set_pte(&self, pte_addr: u64, value: u64) {
+        unsafe { ptr::write_volatile(pa2va(pte_addr) as *mut u64, value) }
+    }
+
+    /// Value of hgatp that selects these page tables.
+    pub fn hgatp(&self) -> u64 {
+        HGATP_MODE_SV39X4 | (self.root >> 12)
+    }
+
+    /// Map the 2 MB region of guest memory containing `guest_pa`, except for the pages listed in
+    /// `unmapped`. It is only writable if `writable` is set.
+    fn map(&mut self, guest_pa: u64, unmapped: &[u64], writable: bool) {
+        let region = guest_pa & !(HPAGE_SIZE - 1);
+        let pte_addr = self.level1(region) + ((region >> 21) & 0x1ff) * 8;
+        // G-stage leaves always have the U bit set, since every guest access is treated as a
+        // user-level one.
+        let flags = PTE_AD | PTE_USER | PTE_RXV | if writable { PTE_WRITE } else { 0 };
+
+        if !unmapped.iter().any(|&page| page & !(HPAGE_SIZE - 1) == region) {
+            self.set_pte(pte_addr, ((region + self.guest_shift) >> 2) | flags);
+        } else {
+            if self.leaf_tables_used == LEAF_TABLES {
+                self.flush();
+            }
+            let table = self.leaf_table(self.leaf_tables_used);
+            self.leaf_tables_used += 1;
+
+            for i in 0..512 {
+                let page = region + i * PAGE_SIZE;
+                let pte = if unmapped.contains(&page) { 0 } else { ((page + self.guest_shift) >> 2) | flags };
+                self.set_pte(table + i * 8, pte);
+            }
+            self.set_pte(pte_addr, (table >> 2) | PTE_VALID);
+        }
+        riscv::hfence_gvma();
+    }
+
+    /// Remove every mapping, so that they are recreated on demand.
+    pub fn flush(&mut self) {
+        let level1 = self.level1(self.first_gigapage << 30);
+        unsafe { ptr::write_bytes(pa2va(level1) as *mut u8, 0, (self.gigapages * PAGE_SIZE) as usize) }
+        self.leaf_tables_used = 0;
+        riscv::hfence_gvma();
+        riscv::hfence_vvma();
+    }
+}
+
+/// Set up the calling hart to run `state`'s vCPU in VS-mode. Called once, before first entering the
+/// guest.
+pub fn init_vcpu(state: &mut Context) {
+    // The debugger needs to see the breakpoints it placed.
+    let mut hedeleg = GUEST_EXCEPTIONS;
+    if state.shared.debugger.is_some() {
+        hedeleg &= !(1 << SCAUSE_BREAKPOINT);
+    }
+
+    unsafe {
+        csrw!(hedeleg, hedeleg);
+        csrw!(hideleg, IP_VSSIP | IP_VSTIP | IP_VSEIP);
+        csrw!(hcounteren, COUNTEREN_CY | COUNTEREN_TM | COUNTEREN_IR);
+        csrw!(hgatp, state.gstage.as_ref().unwrap().hgatp());
+    }
+    riscv::hfence_gvma();
+    reset_vcpu(state);
+}
+
+/// Return the guest's VS-mode CSRs to their reset state, and arrange for the next sret to enter the
+/// guest in VS-mode.
+pub fn reset_vcpu(state: &mut Context) {
+    unsafe {
+        csrw!(vsstatus, 0);
+        csrw!(vsie, 0);
+        csrw!(vstvec, 0);
+        csrw!(vsscratch, 0);
+        csrw!(vsepc, 0);
+        csrw!(vscause, 0);
+        csrw!(vstval, 0);
+        csrw!(vsatp, 0);
+        csrw!(hvip, 0);
+
+        // Guest time counts from when it booted.
+        csrw!(htimedelta, 0u64.wrapping_sub(state.shared.time_offset.load(Ordering::Relaxed)));
+
+        csrs!(hstatus, HSTATUS_SPV);
+        csrs!(sstatus, STATUS_SPP);
+
+        // Floating point is only usable in VS-mode if it is also enabled for HS-mode.
+        csrs!(sstatus, STATUS_FS);
+    }
+    riscv::hfence_vvma();
+}
+
+/// Handle a trap from a guest running in VS- or VU-mode.
+pub fn handle_trap(state: &mut Context, cause: u64) {
+    load_csrs(state);
+
+    let reason = match cause {
+        c if (c as isize) < 0 => ExitReason::Interrupt(c & 0xff),
+        SCAUSE_VIRTUAL_SUPERVISOR_ENV_CALL => sbi::exit_reason(state),
+        SCAUSE_INSN_GUEST_PAGE_FAULT |
+        SCAUSE_LOAD_GUEST_PAGE_FAULT |
+        SCAUSE_STORE_GUEST_PAGE_FAULT => ExitReason::ShadowFill,
+        _ => ExitReason::Exception,
+    };
+    state.stats.begin(reason);
+
+    match cause {
+        c if (c as isize) < 0 => trap::handle_interrupt(state, cause),
+        SCAUSE_VIRTUAL_SUPERVISOR_ENV_CALL => {
+            riscv::set_sepc(csrr!(sepc) + 4);
+            sbi::handle_ecall(state);
+        }
+        SCAUSE_INSN_GUEST_PAGE_FAULT |
+        SCAUSE_LOAD_GUEST_PAGE_FAULT |
+        SCAUSE_STORE_GUEST_PAGE_FAULT => if let Err(error) = handle_guest_page_fault(state, cause) {
+            trap::log_error(state, error, csrr!(htval) << 2 | csrr!(stval) & 0x3, csrr!(sepc));
+            let cause = match cause {
+                SCAUSE_INSN_GUEST_PAGE_FAULT => SCAUSE_INSN_ACCESS_FAULT,
+                SCAUSE_LOAD_GUEST_PAGE_FAULT => SCAUSE_LOAD_ACCESS_FAULT,
+                _ => SCAUSE_STORE_ACCESS_FAULT,
+            };
+            forward_exception(state, cause, csrr!(stval));
+        }
+        SCAUSE_BREAKPOINT => if !gdb::handle_breakpoint(state) {
+            forward_exception(state, cause, csrr!(stval));
+        }
+        SCAUSE_VIRTUAL_INSN => forward_exception(state, SCAUSE_ILLEGAL_INSN, csrr!(stval)),
+        _ => {
+            println!("Forward exception (cause = {}, smode={})!", cause, state.smode);
+            forward_exception(state, cause, csrr!(stval));
+        }
+    }
+}
+
+/// Finish handling a trap, just before returning to the guest.
+pub fn return_to_guest(state: &mut Context) {
+    update_interrupts(state);
+    unsafe {
+        if state.smode {
+            csrs!(sstatus, STATUS_SPP);
+        } else {
+            csrc!(sstatus, STATUS_SPP);
+        }
+    }
+}
+
+/// Copy the guest's VS-mode CSRs into `state.csrs`, where the SBI implementation, the debugger and
+/// the monitor expect to find them.
+fn load_csrs(state: &mut Context) {
+    state.csrs.sstatus = csrr!(vsstatus);
+    state.csrs.sie = csrr!(vsie);
+    state.csrs.sip.set(IP_SSIP, csrr!(hvip).get(IP_VSSIP));
+    state.csrs.stvec = csrr!(vstvec);
+    state.csrs.sscratch = csrr!(vsscratch);
+    state.csrs.sepc = csrr!(vsepc);
+    state.csrs.scause = csrr!(vscause);
+    state.csrs.stval = csrr!(vstval);
+    state.csrs.satp = csrr!(vsatp);
+    state.smode = csrr!(sstatus).get(STATUS_SPP);
+}
+
+/// Make the interrupts pending in `state.csrs.sip` visible to the guest through hvip. The guest
+/// clears software interrupts itself, by writing to its sip, which updates hvip directly.
+fn update_interrupts(state: &mut Context) {
+    // Like a real PLIC, assert SEIP exactly when there is a pending interrupt this vCPU could
+    // claim.
+    let plic_context = state.plic_context();
+    let seip = state.shared.plic.lock().interrupt_pending(plic_context);
+    state.csrs.sip.set(IP_SEIP, seip);
+
+    let mut hvip = 0;
+    hvip.set(IP_VSSIP, state.csrs.sip.get(IP_SSIP));
+    hvip.set(IP_VSTIP, state.csrs.sip.get(IP_STIP));
+    hvip.set(IP_VSEIP, seip);
+    unsafe { csrw!(hvip, hvip) }
+}
+
+/// Handle an access to a guest physical address not mapped by the G-stage page tables: guest
+/// memory that hasn't been touched since they were last flushed (or written, while logging dirty
+/// pages), a page holding a virtio queue, or
+/// an emulated device. Errors are returned for accesses the guest should see as access faults.
+fn handle_guest_page_fault(state: &mut Context, cause: u64) -> Result<(), HypervisorError> {
+    let guest_pa = csrr!(htval) << 2 | csrr!(stval) & 0x3;
+    let in_memory = state.guest_memory.in_region(guest_pa);
+
+    if in_memory {
+        let shared = state.shared;
+        let virtio = shared.virtio.lock();
+        if !virtio.queue_guest_pages.contains(&(guest_pa & !0xfff)) {
+            // While logging dirty pages, regions are only made writable once they have been written.
+            let writable = !shared.dirty_log.enabled() || cause == SCAUSE_STORE_GUEST_PAGE_FAULT;
+            if writable {
+                shared.dirty_log.mark(guest_pa & !(HPAGE_SIZE - 1), HPAGE_SIZE);
+            }
+            state.gstage.as_mut().unwrap().map(guest_pa, &virtio.queue_guest_pages, writable);
+            return Ok(());
+        }
+    }
+
+    state.stats.set_reason(ExitReason::Mmio);
+    if cause == SCAUSE_INSN_GUEST_PAGE_FAULT {
+        return Err(HypervisorError::UnmappedAddress);
+    }
+    let (instruction, _) = GuestMemory::new(state).read_instruction(csrr!(sepc))
+        .map_err(|_| HypervisorError::UnsupportedInstruction)?;
+
+    if in_memory {
+        virtio::handle_queue_access(state, guest_pa, instruction)
+    } else if pfault::emulate_device_access(state, guest_pa, instruction)? {
+        Ok(())
+    } else {
+        Err(HypervisorError::UnmappedAddress)
+    }
+}
+
+/// Have the guest take exception `cause`, as if it had been delegated to it.
+fn forward_exception(state: &mut Context, cause: u64, tval: u64) {
+    let mut vsstatus = csrr!(vsstatus);
+    vsstatus.set(STATUS_SPP, state.smode);
+    vsstatus.set(STATUS_SPIE, vsstatus.get(STATUS_SIE));
+    vsstatus.set(STATUS_SIE, false);
+    unsafe {
+        csrw!(vsstatus, vsstatus);
+        csrw!(vsepc, csrr!(sepc));
+        csrw!(vscause, cause);
+        csrw!(vstval, tval);
+    }
+    state.smode = true;
+    riscv::set_sepc(csrr!(vstvec) & TVEC_BASE);
+}
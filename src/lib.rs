@@ -41,30 +41,22 @@
 //!  0x 80830000 - 0x 80840000  hart 3 M-mode stack
 //!  0x 808xxxxx - 0x 808xxxxx  ...
 //!  0x 808f0000 - 0x 80900000  hart 15 M-mode stack
-//!  0x c0000000 - 0x c0200000  hart 1 stack
-//!  0x c0200000 - 0x c0400000  hart 1 data segment
-//!  0x c0400000 - 0x c4000000  hart 1 heap
-//!  0x c2000000 - 0x c4000000  hart 1 page tables
-//!  0x c4000000 - 0x100000000  hart 1 guest memory
-//!  0x100000000 - 0x100200000  hart 2 stack
-//!  0x100200000 - 0x100400000  hart 2 data segment
-//!  0x100400000 - 0x104000000  hart 2 heap
-//!  0x102000000 - 0x104000000  hart 2 page tables
-//!  0x104000000 - 0x140000000  hart 2 guest memory
-//!  0x140000000 - 0x140200000  hart 3 stack
-//!  0x140200000 - 0x140400000  hart 3 data segment
-//!  0x140400000 - 0x144000000  hart 3 heap
-//!  0x142000000 - 0x144000000  hart 3 page tables
-//!  0x144000000 - 0x180000000  hart 3 guest memory
+//!  0x 81000000 - ...           guest segments, laid out at boot (see pmap::GuestLayout)
 //! ```
 //!
+//! Guest segments start after the initrd if it extends past 0x81000000. Each guest's segment holds,
+//! in order: state shared by its vCPUs and a copy of the host device tree (2MB), a data segment and
+//! stack for each vCPU (2MB each), its dirty page bitmaps, a copy of the guest kernel image, page
+//! tables (32MB per vCPU), and finally guest memory. Guest memory is sized by `rvirt.memory`, or else
+//! host memory is split evenly between guests.
+//!
 //! ## Initial supervisor virtual memory layout (boot page table)
 //!    note: the Sv39 addressing mode is in use here
 //! ```text
 //!  VIRTUAL START      - VIRTUAL END          PHYS START   PHYS END     MODE   REGION
 //!  0x        00000000 - 0x        40000000   0x00000000 - 0x40000000   RWX    QEMU memory sections
 //!  0x        80000000 - 0x        c0000000   0x80000000 - 0xC0000000   RWX    hypervisor memory
-//!  0xffffffffc0000000 - 0xffffffffffffffff   0x80000000 - 0xC0000000   RWX    hypervisor memory
+//!  0xfffffff3c0000000 - 0xfffffff400000000   0x80000000 - 0xC0000000   RWX    hypervisor memory
 //! ```
 //!
 //! ## Linux address space layout (with Sv39 addressing)
@@ -72,7 +64,7 @@
 //! In this addressing mode, Linux does not reserve any address space for a hypervisor. However, the
 //! direct map region is 128GB (one quarter of the addres space) but physical memory takes up at
 //! most a handful of GBs and Linux never accesses any higher addresses. Thus rvirt is able to use
-//! the top 16GB of virtual addresses for its own code and data.
+//! 16GB of virtual addresses starting 64GB below the top of memory for its own code and data.
 //!
 //! ```text
 //!  VIRTUAL START      - VIRTUAL END          REGION
@@ -80,6 +72,18 @@
 //!  0xffffffbfffffffff - 0xffffffdfffffffff   Kernel memory
 //!  0xffffffdfffffffff - 0xffffffffffffffff   Direct map region
 //! ```
+//!
+//! ## Guests using Sv48
+//!
+//! Guests may also select Sv48 if the host supports it, in which case their shadow page tables use
+//! Sv48 as well. The hypervisor's 16GB window is at the same virtual addresses in both modes. With
+//! Sv48, Linux places its kernel and modules in the top 4GB and its direct map far below the top
+//! 512GB, so the window does not collide with either.
+//!
+//! ```text
+//!  VIRTUAL START      - VIRTUAL END          REGION
+//!  0xfffffff000000000 - 0xfffffff400000000   Reserved for the hypervisor (both modes)
+//! ```
 
 #![no_std]
 #![feature(asm)]
@@ -100,17 +104,28 @@ pub mod riscv;
 pub mod print;
 
 pub mod backtrace;
+pub mod config;
 pub mod constants;
 pub mod context;
 pub mod csr;
+pub mod dirty;
 pub mod elf;
+pub mod error;
 pub mod fdt;
+pub mod fp;
+pub mod gdb;
+pub mod guest_memory;
+pub mod hext;
 pub mod memory_region;
+pub mod mmio;
+pub mod monitor;
 pub mod pfault;
 pub mod plic;
 pub mod pmap;
+pub mod ptwatch;
+pub mod sbi;
+pub mod stats;
 pub mod statics;
-pub mod sum;
 pub mod trap;
 pub mod virtio;
 
@@ -119,4 +134,4 @@ pub use constants::SYMBOL_PA2VA_OFFSET;
 pub use fdt::*;
 pub use trap::constants::*;
 pub use pmap::{pa2va};
-pub use statics::{__SHARED_STATICS_IMPL, IpiReason, SHARED_STATICS};
+pub use statics::{__SHARED_STATICS_IMPL, HartMemory, IpiReason, SHARED_STATICS};
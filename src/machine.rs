@@ -28,7 +28,10 @@ const TEST_PMP: bool = false;
 const M_MODE_STACK_BASE: u64 = 0x80810000;
 const M_MODE_STACK_STRIDE: u64 = 0x10000;
 
-const SUPERVISOR_START_ADDRESS: u64 = 0xffffffffc0100000;
+const SUPERVISOR_START_ADDRESS: u64 = 0xfffffff3c0100000;
+
+/// Bit of misa indicating the hypervisor extension.
+const MISA_H: u64 = 1 << ('H' as u64 - 'A' as u64);
 
 
 global_asm!(include_str!("mcode.S"));
@@ -58,6 +61,12 @@ unsafe fn mstart(hartid: u64, device_tree_blob: u64) {
     // Initialize some control registers
     csrs!(mideleg, 0x0222);
     csrs!(medeleg, 0xb1ff);
+    if csrr!(misa) & MISA_H != 0 {
+        // Guests will run in VS-mode, and their environment calls, guest page faults and virtual
+        // instruction exceptions are handled by the hypervisor.
+        csrs!(medeleg, 1 << 10 | 0xf << 20);
+        SHARED_STATICS.hypervisor_extension.store(true, Ordering::SeqCst);
+    }
     csrw!(mie, 0x088);
     csrc!(mstatus, STATUS_MPP_M);
     csrs!(mstatus, STATUS_MPP_S);
@@ -65,6 +74,11 @@ unsafe fn mstart(hartid: u64, device_tree_blob: u64) {
     csrw!(mcounteren, 0xffffffff);
     csrw!(mscratch, M_MODE_STACK_BASE + M_MODE_STACK_STRIDE * hartid);
 
+    // Text segment
+    pmp::install_pmp_napot(0, pmp::LOCK | pmp::READ | pmp::EXEC, pmp::TEXT.0, pmp::TEXT.1 - pmp::TEXT.0);
+    // Shared data segment
+    pmp::install_pmp_napot(1, pmp::LOCK | pmp::READ | pmp::WRITE, pmp::SHARED_DATA.0, pmp::SHARED_DATA.1 - pmp::SHARED_DATA.0);
+    // Everything else, until handle_ipi restricts the hart to what its guest needs
     pmp::install_pmp_allmem(7, pmp::READ | pmp::WRITE | pmp::EXEC);
 
     if SHARED_STATICS.hart_lottery.swap(false,  Ordering::SeqCst) {
@@ -74,11 +88,6 @@ unsafe fn mstart(hartid: u64, device_tree_blob: u64) {
 
         print::early_guess_uart();
 
-        // Text segment
-        pmp::install_pmp_napot(0, pmp::LOCK | pmp::READ | pmp::EXEC, 0x80000000, 0x200000);
-        // Shared data segment
-        pmp::install_pmp_napot(1, pmp::LOCK | pmp::READ | pmp::WRITE, 0x80200000, 0x200000);
-
         // Minimal page table to boot into S mode. See [1] for FU540 errata related to mixing huge
         // pages and PMP.
         //
@@ -87,7 +96,7 @@ unsafe fn mstart(hartid: u64, device_tree_blob: u64) {
         *((boot_page_table_pa) as *mut u64) = 0x00000000 | 0xcf;
         *((boot_page_table_pa+16) as *mut u64) = ((boot_page_table_pa + 4096) >> 2) | 0x01;
         *((boot_page_table_pa+24) as *mut u64) = 0x30000000 | 0xcf;
-        *((boot_page_table_pa+4088) as *mut u64) = ((boot_page_table_pa + 4096) >> 2) | 0x01;
+        *((boot_page_table_pa+0xe78) as *mut u64) = ((boot_page_table_pa + 4096) >> 2) | 0x01;
         *((boot_page_table_pa+4096) as *mut u64) = 0x20000000 | 0xcb;
         for i in 1..512 {
             *((boot_page_table_pa + 4096 + i*8) as *mut u64) = (0x20000000 + (i<<19)) | 0xc7;
@@ -121,15 +130,18 @@ pub unsafe fn handle_ipi() {
     let reason = { SHARED_STATICS.ipi_reason_array.get_unchecked(hartid as usize).lock().take() };
 
     match reason {
-        Some(IpiReason::EnterSupervisor{ a0, a1, a2, a3, sp, satp, mepc}) => {
+        Some(IpiReason::EnterSupervisor{ a0, a1, a2, a3, a4, sp, satp, mepc, memory }) => {
+            pmp::install_hart_pmp(&memory);
             csrw!(mepc, mepc);
             csrw!(satp, satp);
+            riscv::sfence_vma();
             asm!("mv a0, $0
                   mv a1, $1
                   mv a2, $2
                   mv a3, $3
-                  mv sp, $4
-                  mret" :: "r"(a0), "r"(a1), "r"(a2), "r"(a3), "r"(sp) : "a0", "a1", "a2", "a3", "sp" : "volatile");
+                  mv a4, $4
+                  mv sp, $5
+                  mret" :: "r"(a0), "r"(a1), "r"(a2), "r"(a3), "r"(a4), "r"(sp) : "a0", "a1", "a2", "a3", "a4", "sp" : "volatile");
         }
         None => {
             machdebug::machine_debug_abort("Got IPI but reason wasn't specified?");
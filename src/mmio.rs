@@ -0,0 +1,91 @@
+//! Emulation of guest loads and stores that target memory mapped devices.
+//!
+//! Instructions are decoded here rather than by each device model, so that every device accepts the
+//! same set of instructions: all RV64 integer loads and stores, including their compressed forms.
+
+use crate::context::Context;
+use crate::error::HypervisorError;
+use crate::{riscv, trap};
+
+/// A device model that guest loads and stores can be directed at.
+pub trait MmioDevice {
+    /// Read `width` bytes (1, 2, 4 or 8) at guest physical address `addr`. Only the low `width`
+    /// bytes of the result are used.
+    fn read(&mut self, state: &mut Context, addr: u64, width: u64) -> Result<u64, HypervisorError>;
+
+    /// Write the low `width` bytes of `value` to guest physical address `addr`.
+    fn write(&mut self, state: &mut Context, addr: u64, width: u64, value: u64) -> Result<(), HypervisorError>;
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Access {
+    /// Load `width` bytes into register `rd`.
+    Load { rd: u32, width: u64, sign_extend: bool },
+    /// Store the low `width` bytes of register `rs2`.
+    Store { rs2: u32, width: u64 },
+}
+
+/// Decode a load or store instruction, returning the access it performs along with the length of
+/// the instruction in bytes. Returns None for any other instruction.
+pub fn decode(instruction: u32) -> Option<(Access, u64)> {
+    if instruction & 0x3 == 0x3 {
+        let funct3 = (instruction >> 12) & 0x7;
+        let access = match instruction & 0x7f {
+            0x03 => match funct3 {
+                0..=3 => Access::Load { rd: (instruction >> 7) & 0x1f, width: 1 << funct3, sign_extend: true },
+                4..=6 => Access::Load { rd: (instruction >> 7) & 0x1f, width: 1 << (funct3 - 4), sign_extend: false },
+                _ => return None,
+            }
+            0x23 => match funct3 {
+                0..=3 => Access::Store { rs2: (instruction >> 20) & 0x1f, width: 1 << funct3 },
+                _ => return None,
+            }
+            _ => return None,
+        };
+        return Some((access, 4));
+    }
+
+    // Compressed instructions. Registers in the three bit fields are x8 through x15.
+    let funct3 = (instruction >> 13) & 0x7;
+    let access = match (instruction & 0x3, funct3) {
+        (0b00, 0b010) => Access::Load { rd: 8 + ((instruction >> 2) & 0x7), width: 4, sign_extend: true }, // c.lw
+        (0b00, 0b011) => Access::Load { rd: 8 + ((instruction >> 2) & 0x7), width: 8, sign_extend: false }, // c.ld
+        (0b00, 0b110) => Access::Store { rs2: 8 + ((instruction >> 2) & 0x7), width: 4 }, // c.sw
+        (0b00, 0b111) => Access::Store { rs2: 8 + ((instruction >> 2) & 0x7), width: 8 }, // c.sd
+        (0b10, 0b010) if (instruction >> 7) & 0x1f != 0 => Access::Load { rd: (instruction >> 7) & 0x1f, width: 4, sign_extend: true }, // c.lwsp
+        (0b10, 0b011) if (instruction >> 7) & 0x1f != 0 => Access::Load { rd: (instruction >> 7) & 0x1f, width: 8, sign_extend: false }, // c.ldsp
+        (0b10, 0b110) => Access::Store { rs2: (instruction >> 2) & 0x1f, width: 4 }, // c.swsp
+        (0b10, 0b111) => Access::Store { rs2: (instruction >> 2) & 0x1f, width: 8 }, // c.sdsp
+        _ => return None,
+    };
+    Some((access, 2))
+}
+
+/// Emulate `instruction`, which accessed guest physical address `addr`, by forwarding the access to
+/// `device`. On success any destination register is written and sepc is advanced past the
+/// instruction. On failure the guest's registers are left unchanged.
+pub fn emulate<D: MmioDevice + ?Sized>(state: &mut Context, device: &mut D, addr: u64, instruction: u32) -> Result<(), HypervisorError> {
+    let (access, len) = decode(instruction).ok_or(HypervisorError::UnsupportedInstruction)?;
+
+    match access {
+        Access::Load { rd, width, sign_extend } => {
+            let value = device.read(state, addr, width)?;
+
+            let shift = 64 - 8 * width;
+            let value = if sign_extend {
+                (((value << shift) as i64) >> shift) as u64
+            } else {
+                (value << shift) >> shift
+            };
+            trap::set_register(state, rd, value);
+        }
+        Access::Store { rs2, width } => {
+            let shift = 64 - 8 * width;
+            let value = (trap::get_register(state, rs2) << shift) >> shift;
+            device.write(state, addr, width, value)?;
+        }
+    }
+
+    riscv::set_sepc(csrr!(sepc) + len);
+    Ok(())
+}
@@ -0,0 +1,354 @@
+//! Interactive monitor on the host console, for inspecting and controlling guests.
+//!
+//! Typing Ctrl-A on the host UART enters the monitor rather than passing the byte on to a guest.
+//! The monitor runs on whichever hart next finishes handling a trap, and no guest receives input
+//! until it is left again. Commands that need the private state of a vCPU are posted to that vCPU
+//! with `REQUEST_MONITOR`, and carried out on its own hart. Harts can't access the memory of guests
+//! other than their own (see machine.rs), so commands concerning another guest are first posted as a
+//! `Request` to a host hart of that guest, through a mailbox in `SHARED_STATICS`.
+
+use arrayvec::ArrayVec;
+use core::ptr;
+use core::sync::atomic::Ordering;
+use crate::constants::MAX_HOST_HARTS;
+use crate::context::{Context, SharedContext, REQUEST_MONITOR};
+use crate::sbi::constants::*;
+use crate::statics::SHARED_STATICS;
+use crate::trap::constants::SATP_PPN;
+use crate::{backtrace, dirty, pmap, trap};
+
+/// Byte that enters (and leaves) the monitor.
+pub const ESCAPE: u8 = 0x01;
+
+// Values of `Shared::monitor_state`.
+pub const MONITOR_IDLE: u64 = 0;
+pub const MONITOR_REQUESTED: u64 = 1;
+pub const MONITOR_RUNNING: u64 = 2;
+
+/// A command carried out by a vCPU on behalf of the monitor.
+#[derive(Copy, Clone, Debug)]
+pub enum Command {
+    Registers,
+    PageTable,
+    Backtrace,
+    Stats,
+    DirtyLogStart,
+    DirtyLogStop,
+    DirtyLogFetch,
+}
+
+/// A request the monitor posts to a host hart of the guest it concerns (see `serve`).
+#[derive(Copy, Clone, Debug)]
+pub enum Request {
+    /// List the guest, which has the given id, and its vCPUs.
+    Info(u64),
+    Pause,
+    Resume,
+    /// Have any running vCPU of the guest carry out the command.
+    AnyVcpu(Command),
+    Vcpu(usize, Command),
+}
+
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+const HELP: &str = "\
+help                  Show this message
+info                  List guests and their vCPUs
+regs <guest> [vcpu]   Show the CSRs and registers of a vCPU
+pt <guest> [vcpu]     Walk the guest page table a vCPU is using
+bt <guest> [vcpu]     Print a backtrace of a vCPU
+stats <guest> [vcpu]  Show exit statistics of a vCPU
+dirty <guest> <op>    Start, stop or fetch (and clear) the log of written guest pages
+pause <guest>         Stop running a guest
+resume <guest>        Continue running a paused guest
+quit                  Leave the monitor (as does Ctrl-A)";
+
+/// Whether guests may currently read from the host UART.
+pub fn guest_input_enabled() -> bool {
+    SHARED_STATICS.monitor_state.load(Ordering::Relaxed) == MONITOR_IDLE
+}
+
+/// Whether the monitor has been asked for, but isn't running yet.
+pub fn requested() -> bool {
+    SHARED_STATICS.monitor_state.load(Ordering::Relaxed) == MONITOR_REQUESTED
+}
+
+/// Ask for the monitor to be entered, once the current trap has been handled.
+pub fn request() {
+    SHARED_STATICS.monitor_state.compare_and_swap(MONITOR_IDLE, MONITOR_REQUESTED, Ordering::SeqCst);
+}
+
+/// Called at the end of every trap. Runs the monitor if it has been requested, and then waits for
+/// as long as this vCPU's guest is paused.
+///
+/// A paused guest doesn't read the host UART, so the first running vCPU of the guest keeps watching
+/// it for the escape while waiting. Otherwise the monitor could never be entered again to resume the
+/// guest once every guest has been paused.
+pub fn poll(state: &mut Context) {
+    let monitor_state = &SHARED_STATICS.monitor_state;
+    loop {
+        let shared = state.shared;
+        let hartid = shared.vcpus[state.vcpu].hartid;
+        serve(shared, hartid, Some(state));
+
+        if monitor_state.load(Ordering::Relaxed) == MONITOR_REQUESTED &&
+            monitor_state.compare_and_swap(MONITOR_REQUESTED, MONITOR_RUNNING, Ordering::SeqCst) == MONITOR_REQUESTED {
+            run(state);
+            monitor_state.store(MONITOR_IDLE, Ordering::SeqCst);
+        }
+
+        if !state.shared.paused.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let watcher = state.shared.vcpus.iter().position(|v| v.status.load(Ordering::SeqCst) == HSM_STATUS_STARTED);
+        if watcher == Some(state.vcpu) {
+            state.shared.uart.lock().poll_escape();
+        }
+        state.process_requests();
+    }
+}
+
+/// Carry out the request the monitor posted to host hart `hartid`, which runs a vCPU of the guest
+/// `shared`. `state` is that vCPU, or None while it is stopped.
+pub fn serve(shared: &'static SharedContext, hartid: u64, state: Option<&mut Context>) {
+    let mailbox = &SHARED_STATICS.monitor_mailbox[hartid as usize];
+    let request = *mailbox.lock();
+    if let Some(request) = request {
+        carry_out(shared, request, state);
+        *mailbox.lock() = None;
+    }
+}
+
+/// Carry out `command` on the calling vCPU.
+pub fn execute(state: &mut Context, command: Command) {
+    match command {
+        Command::Registers => print_registers(state),
+        Command::PageTable => match pmap::paging_levels(state.csrs.satp) {
+            Some(levels) => pmap::print_guest_page_table(&state.guest_memory, (state.csrs.satp & SATP_PPN) << 12, levels as u8 - 1, 0),
+            None => println!("Paging is disabled"),
+        }
+        Command::Backtrace => unsafe { backtrace::print_guest_backtrace(state, csrr!(sepc)) },
+        Command::Stats => state.stats.print(state.vcpu, state.shadow_page_tables.occupancy()),
+        Command::DirtyLogStart => dirty::start(state),
+        Command::DirtyLogStop => dirty::stop(state),
+        Command::DirtyLogFetch => if state.shared.dirty_log.enabled() {
+            let mut pages = 0;
+            dirty::fetch_and_clear(state, |_| pages += 1);
+            println!("{} pages written since the log was last fetched", pages);
+        } else {
+            println!("Dirty logging is not enabled");
+        }
+    }
+}
+
+fn run(state: &mut Context) {
+    println!("Entering the rvirt monitor. Type 'help' for a list of commands.");
+
+    let mut line = ArrayVec::<[u8; 64]>::new();
+    loop {
+        print!("(rvirt) ");
+        if !read_line(state, &mut line) {
+            break;
+        }
+
+        let line = core::str::from_utf8(&line).unwrap_or("");
+        let mut words = line.split_whitespace();
+        let command = words.next();
+        let guest = words.next().map(|w| w.parse::<u64>().ok().filter(|&id| guest_exists(id)));
+        let arg = words.next();
+        let vcpu = arg.map(|w| w.parse::<usize>().ok()).unwrap_or(Some(0));
+
+        match (command, guest) {
+            (None, _) => {}
+            (Some("help"), _) => println!("{}", HELP),
+            (Some("quit"), _) => break,
+            (Some("info"), _) => for guestid in 1..=MAX_HOST_HARTS as u64 {
+                if guest_exists(guestid) {
+                    dispatch(state, guestid, Request::Info(guestid));
+                }
+            }
+            (Some(_), Some(None)) => println!("No such guest"),
+            (Some("dirty"), Some(Some(guestid))) => {
+                let command = match arg {
+                    Some("start") => Command::DirtyLogStart,
+                    Some("stop") => Command::DirtyLogStop,
+                    Some("fetch") => Command::DirtyLogFetch,
+                    _ => {
+                        println!("Expected 'start', 'stop' or 'fetch'");
+                        continue;
+                    }
+                };
+                dispatch(state, guestid, Request::AnyVcpu(command));
+            }
+            (Some(command), Some(Some(guestid))) => {
+                let vcpu = match vcpu {
+                    Some(vcpu) => vcpu,
+                    None => {
+                        println!("No such vCPU");
+                        continue;
+                    }
+                };
+                let request = match command {
+                    "regs" => Request::Vcpu(vcpu, Command::Registers),
+                    "pt" => Request::Vcpu(vcpu, Command::PageTable),
+                    "bt" => Request::Vcpu(vcpu, Command::Backtrace),
+                    "stats" => Request::Vcpu(vcpu, Command::Stats),
+                    "pause" => Request::Pause,
+                    "resume" => Request::Resume,
+                    _ => {
+                        println!("Unknown command '{}'", command);
+                        continue;
+                    }
+                };
+                dispatch(state, guestid, request);
+            }
+            (Some(command), None) => println!("Unknown command '{}' (or missing guest)", command),
+        }
+    }
+
+    println!("Leaving the rvirt monitor");
+}
+
+/// Read a line of input into `line`, echoing it back. Returns false if the monitor escape was typed
+/// instead. Requests from other vCPUs are handled while waiting.
+fn read_line(state: &mut Context, line: &mut ArrayVec<[u8; 64]>) -> bool {
+    line.clear();
+    loop {
+        let ch = SHARED_STATICS.uart_writer.lock().getchar();
+        match ch {
+            None => state.process_requests(),
+            Some(ESCAPE) => {
+                println!("");
+                return false;
+            }
+            Some(b'\r') | Some(b'\n') => {
+                println!("");
+                return true;
+            }
+            Some(0x08) | Some(0x7f) => if line.pop().is_some() {
+                let mut writer = SHARED_STATICS.uart_writer.lock();
+                for &ch in b"\x08 \x08" {
+                    writer.putchar(ch);
+                }
+            }
+            Some(ch) if ch >= 0x20 && ch < 0x7f => if line.try_push(ch).is_ok() {
+                SHARED_STATICS.uart_writer.lock().putchar(ch);
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+fn guest_exists(guestid: u64) -> bool {
+    guestid > 0 && guestid <= MAX_HOST_HARTS as u64 &&
+        SHARED_STATICS.guests[guestid as usize - 1].load(Ordering::SeqCst) != 0
+}
+
+/// Have `request` carried out for guest `guestid`, and wait for it to finish. Requests for another
+/// guest than the one `state` belongs to are posted to a host hart of that guest.
+fn dispatch(state: &mut Context, guestid: u64, request: Request) {
+    let address = SHARED_STATICS.guests[guestid as usize - 1].load(Ordering::SeqCst);
+    if address == state.shared as *const SharedContext as u64 {
+        let shared = state.shared;
+        carry_out(shared, request, Some(state));
+        return;
+    }
+
+    let hartid = SHARED_STATICS.monitor_harts[guestid as usize - 1].load(Ordering::SeqCst);
+    let mailbox = &SHARED_STATICS.monitor_mailbox[hartid as usize];
+    *mailbox.lock() = Some(request);
+    state.shared.kick_hart(hartid);
+    while mailbox.lock().is_some() {
+        state.process_requests();
+    }
+}
+
+/// Carry out `request` for the guest `shared`, on a host hart running one of its vCPUs. `state` is
+/// that vCPU, or None if it is stopped.
+fn carry_out(shared: &'static SharedContext, request: Request, state: Option<&mut Context>) {
+    match request {
+        Request::Info(guestid) => print_guest(shared, guestid),
+        Request::Pause => {
+            shared.paused.store(true, Ordering::SeqCst);
+            for i in 0..shared.vcpus.len() {
+                shared.kick(i);
+            }
+        }
+        Request::Resume => shared.paused.store(false, Ordering::SeqCst),
+        // Any running vCPU can act for the whole guest.
+        Request::AnyVcpu(command) => {
+            match shared.vcpus.iter().position(|v| v.status.load(Ordering::SeqCst) == HSM_STATUS_STARTED) {
+                Some(vcpu) => on_vcpu(state, shared, vcpu, command),
+                None => println!("No vCPU of the guest is running"),
+            }
+        }
+        Request::Vcpu(vcpu, _) if vcpu >= shared.vcpus.len() => println!("No such vCPU"),
+        Request::Vcpu(vcpu, command) => on_vcpu(state, shared, vcpu, command),
+    }
+}
+
+fn print_guest(shared: &SharedContext, guestid: u64) {
+    let paused = if shared.paused.load(Ordering::SeqCst) { " (paused)" } else { "" };
+    println!("guest {}: {} vCPUs{}", guestid, shared.vcpus.len(), paused);
+    for (i, vcpu) in shared.vcpus.iter().enumerate() {
+        let status = match vcpu.status.load(Ordering::SeqCst) {
+            HSM_STATUS_STARTED => "started",
+            HSM_STATUS_STOPPED => "stopped",
+            HSM_STATUS_START_PENDING => "start pending",
+            HSM_STATUS_STOP_PENDING => "stop pending",
+            _ => "unknown",
+        };
+        println!("  vCPU {} on hart {}: {}", i, vcpu.hartid, status);
+    }
+}
+
+/// Have `vcpu` of the guest `shared` carry out `command`, and wait for it to finish. `state` is the
+/// calling vCPU of the same guest, if any.
+fn on_vcpu(mut state: Option<&mut Context>, shared: &'static SharedContext, vcpu: usize, command: Command) {
+    if let Some(ref mut state) = state {
+        if ptr::eq(shared, state.shared) && vcpu == state.vcpu {
+            execute(state, command);
+            return;
+        }
+    }
+
+    let target = &shared.vcpus[vcpu];
+    if target.status.load(Ordering::SeqCst) != HSM_STATUS_STARTED {
+        println!("vCPU {} is not running", vcpu);
+        return;
+    }
+
+    *shared.monitor_command.lock() = Some(command);
+    target.requests.fetch_or(REQUEST_MONITOR, Ordering::SeqCst);
+    shared.kick(vcpu);
+    while target.requests.load(Ordering::SeqCst) & REQUEST_MONITOR != 0 {
+        if let Some(ref mut state) = state {
+            state.process_requests();
+        }
+    }
+}
+
+fn print_registers(state: &mut Context) {
+    println!("vCPU {} ({}-mode)", state.vcpu, if state.smode { "S" } else { "U" });
+    println!("  pc       = {:#018x}", csrr!(sepc));
+    println!("  sstatus  = {:#018x}  sie      = {:#018x}  sip      = {:#018x}",
+             state.csrs.sstatus, state.csrs.sie, state.csrs.sip);
+    println!("  stvec    = {:#018x}  sepc     = {:#018x}  sscratch = {:#018x}",
+             state.csrs.stvec, state.csrs.sepc, state.csrs.sscratch);
+    println!("  scause   = {:#018x}  stval    = {:#018x}  satp     = {:#018x}",
+             state.csrs.scause, state.csrs.stval, state.csrs.satp);
+    println!("  scounteren = {:#x}  mtimecmp = {:#x}", state.csrs.scounteren, state.csrs.mtimecmp);
+    for reg in 0..32 {
+        let value = trap::get_register(state, reg);
+        print!("  {:<8} = {:#018x}", REGISTER_NAMES[reg as usize], value);
+        if reg % 3 == 2 || reg == 31 {
+            println!("");
+        }
+    }
+}
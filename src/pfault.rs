@@ -1,15 +1,28 @@
 use crate::context::Context;
-use crate::trap::{self, constants::SATP_PPN};
-use crate::{pmap::*, riscv, virtio};
-use riscv_decode::Instruction;
+use crate::error::HypervisorError;
+use crate::stats::ExitReason;
+use crate::trap::constants::STATUS_MXR;
+use crate::{dirty, mmio, pmap::*, ptwatch, riscv, virtio};
 
 /// Perform any handling required in response to a guest page fault. Returns true if the fault could
-/// be handled, or false if it should be forwarded on to the guest.
-pub fn handle_page_fault(state: &mut Context, cause: u64, instruction: Option<u32>) -> bool {
+/// be handled, or false if it should be forwarded on to the guest. Errors are returned for accesses
+/// to emulated devices that the hypervisor refused, which the guest should see as access faults.
+pub fn handle_page_fault(state: &mut Context, cause: u64, instruction: Option<u32>) -> Result<bool, HypervisorError> {
     let shadow = state.shadow();
     if shadow == PageTableRoot::MPA {
+        // Queue pages are left unmapped by the MPA root (see PageTables::set_mpa_holes).
+        let guest_pa = csrr!(stval);
+        if virtio::is_queue_access(state, guest_pa & !0xfff) {
+            state.stats.set_reason(ExitReason::Mmio);
+            let instruction = instruction.ok_or(HypervisorError::UnsupportedInstruction)?;
+            virtio::handle_queue_access(state, guest_pa, instruction)?;
+            return Ok(true);
+        }
+        if cause == 15 && dirty::handle_mpa_write(state, guest_pa) {
+            return Ok(true);
+        }
         println!("Page fault without guest paging enabled?");
-        return false;
+        return Ok(false);
     }
 
     let guest_va = csrr!(stval);
@@ -22,24 +35,27 @@ pub fn handle_page_fault(state: &mut Context, cause: u64, instruction: Option<u3
         _ => unreachable!(),
     };
 
+    // With MXR set, loads are also permitted from pages that are only executable.
+    let mxr = state.csrs.sstatus & STATUS_MXR != 0;
+
+    // The walk is retried if the guest changes the leaf entry before its A and D bits are updated.
     let page = guest_va & !0xfff;
-    if let Some(translation) = translate_guest_address(&state.guest_memory, (state.csrs.satp & SATP_PPN) << 12, page) {
+    while let Some(translation) = translate_guest_address(&state.guest_memory, state.csrs.satp, page) {
         // Check R/W/X bits
-        if translation.pte_value & access == 0 {
-            return false;
+        let allowed = if access == PTE_READ && mxr { PTE_READ | PTE_EXECUTE } else { access };
+        if translation.pte_value & allowed == 0 {
+            return Ok(false);
         }
 
         // Check U bit
         match shadow {
-            PageTableRoot::UVA => if translation.pte_value & PTE_USER == 0 { return false; }
-            PageTableRoot::KVA => if translation.pte_value & PTE_USER != 0 { return false; }
+            PageTableRoot::UVA => if translation.pte_value & PTE_USER == 0 { return Ok(false); }
+            PageTableRoot::KVA => if translation.pte_value & PTE_USER != 0 { return Ok(false); }
             PageTableRoot::MVA => {}
             _ => unreachable!(),
         }
 
         if state.guest_memory.in_region(translation.guest_pa) {
-            let host_pa = translation.guest_pa + state.guest_shift;
-
             // Set A and D bits
             let new_pte = if (translation.pte_value & PTE_DIRTY) == 0 && access == PTE_WRITE {
                 translation.pte_value | PTE_DIRTY | PTE_ACCESSED
@@ -49,104 +65,125 @@ pub fn handle_page_fault(state: &mut Context, cause: u64, instruction: Option<u3
                 translation.pte_value
             };
 
+            if new_pte != translation.pte_value && !compare_and_swap_guest_pte(
+                &mut state.guest_memory, translation.pte_addr, translation.pte_value, new_pte) {
+                continue;
+            }
             if new_pte != translation.pte_value {
-                // TODO: do this atomically
-                state.guest_memory[translation.pte_addr] = new_pte;
+                state.shared.dirty_log.mark(translation.pte_addr, 8);
             }
 
-            let perm = if (new_pte & PTE_DIRTY) == 0 && access != PTE_WRITE {
+            let mut perm = if (new_pte & PTE_DIRTY) == 0 && access != PTE_WRITE {
                 (new_pte & (PTE_READ | PTE_EXECUTE))
             } else {
                 (new_pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE))
             };
+            if mxr && perm & PTE_EXECUTE != 0 {
+                perm |= PTE_READ;
+            }
 
             if virtio::is_queue_access(state, translation.guest_pa) {
+                state.stats.set_reason(ExitReason::Mmio);
                 let guest_pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
-                let host_pa = (host_pa & !0xfff) | (guest_va & 0xfff);
-                let instruction = instruction.expect("attempted to execute code from virtio queue page");
-                return virtio::handle_queue_access(state, guest_pa, host_pa, instruction);
+                let instruction = instruction.ok_or(HypervisorError::UnsupportedInstruction)?;
+                virtio::handle_queue_access(state, guest_pa, instruction)?;
+                return Ok(true);
             }
 
+            ptwatch::track_walk(state, page);
+            if ptwatch::is_tracked(state, translation.guest_pa) {
+                if access == PTE_WRITE {
+                    state.stats.set_reason(ExitReason::PageTableWrite);
+                    let guest_pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
+                    ptwatch::handle_write(state, guest_pa, instruction);
+                    return Ok(true);
+                }
+                perm &= !PTE_WRITE;
+            }
+
+            // While logging dirty pages, only pages already marked dirty may be mapped writable, and
+            // then only individually.
+            let shared = state.shared;
+            let dirty_log = &shared.dirty_log;
+            if dirty_log.enabled() {
+                if access == PTE_WRITE {
+                    dirty_log.mark(translation.guest_pa, 1);
+                } else if !dirty_log.is_dirty(translation.guest_pa) {
+                    perm &= !PTE_WRITE;
+                }
+            }
+
+            let shift = if perm & PTE_WRITE != 0 && dirty_log.enabled() {
+                12
+            } else {
+                shadow_page_shift(state, translation.guest_pa, translation.level.shift())
+            };
+            let mask = (1 << shift) - 1;
+            let host_pa = (translation.guest_pa & !mask) + state.guest_shift;
             state.shadow_page_tables.set_mapping(
-                shadow, page, (host_pa >> 2) | perm | PTE_AD | PTE_USER | PTE_VALID);
-            riscv::sfence_vma_addr(guest_va);
-            return true;
+                shadow, guest_va & !mask, (host_pa >> 2) | perm | PTE_AD | PTE_USER | PTE_VALID, shift);
+            if shift == 12 {
+                riscv::sfence_vma_addr(guest_va);
+            } else {
+                riscv::sfence_vma();
+            }
+            return Ok(true);
         } else if access != PTE_EXECUTE && state.smode {
             let pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
             if let Some(instruction) = instruction {
-                if is_uart_access(pa) {
-                    return handle_uart_access(state, pa, instruction);
+                state.stats.set_reason(ExitReason::Mmio);
+                if emulate_device_access(state, pa, instruction)? {
+                    return Ok(true);
                 }
+            }
+        }
+        break;
+    }
 
-                if is_plic_access(pa) {
-                    return handle_plic_access(state, pa, instruction)
-                }
+    Ok(false)
+}
 
-                if virtio::is_device_access(state, pa) {
-                    return virtio::handle_device_access(state, pa, instruction);
-                }
-            }
+/// Size (as a shift) of the largest page that can shadow the guest page at `guest_pa`, given that the
+/// guest maps it with a page of size `1 << guest_shift`. Pages larger than 4 KiB must lie entirely
+/// within guest memory and be aligned in host memory, and can't contain virtio queues or tracked
+/// page tables since those need mappings of their own.
+fn shadow_page_shift(state: &mut Context, guest_pa: u64, guest_shift: u64) -> u64 {
+    let mut shift = guest_shift.min(30);
+    while shift > 12 {
+        let start = guest_pa & !((1 << shift) - 1);
+        let end = start + (1 << shift);
+        if state.guest_memory.in_region(start) && state.guest_memory.in_region(end - 1) &&
+            (start + state.guest_shift) % (1 << shift) == 0 &&
+            !virtio::has_queue_in(state, start, end) && !ptwatch::has_tracked_in(state, start, end) {
+            return shift;
         }
+        shift -= 9;
     }
+    12
+}
 
-    false
+/// Emulate an access by `instruction` to guest physical address `pa`. Returns false if there is no
+/// emulated device at that address.
+pub fn emulate_device_access(state: &mut Context, pa: u64, instruction: u32) -> Result<bool, HypervisorError> {
+    let shared = state.shared;
+    if is_uart_access(pa) {
+        mmio::emulate(state, &mut *shared.uart.lock(), pa, instruction)?;
+    } else if is_plic_access(pa) {
+        mmio::emulate(state, &mut *shared.plic.lock(), pa, instruction)?;
+    } else if virtio::is_device_access(state, pa) {
+        virtio::handle_device_access(state, pa, instruction)?;
+    } else {
+        return Ok(false);
+    }
+    Ok(true)
 }
 
 #[inline(always)]
 fn is_uart_access(guest_pa: u64) -> bool {
     guest_pa >= 0x10000000 && guest_pa < 0x10000100
 }
-fn handle_uart_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
-    match riscv_decode::decode(instruction).ok() {
-        Some(Instruction::Lb(i)) => {
-            let value = state.uart.read(&state.host_clint, guest_pa) as u64;
-            trap::set_register(state, i.rd(), value);
-        }
-        Some(Instruction::Sb(i)) => {
-            let value = (trap::get_register(state, i.rs2()) & 0xff) as u8;
-            state.uart.write(&state.host_clint, guest_pa, value);
-        }
-        Some(instr) => {
-            println!("UART: Instruction {:?} used to target addr {:#x} from pc {:#x}", instr, guest_pa, csrr!(sepc));
-            loop {}
-        }
-        _ => return false,
-    }
-    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
-    true
-}
 
 #[inline(always)]
 fn is_plic_access(guest_pa: u64) -> bool {
     guest_pa >= 0x0c000000 && guest_pa < 0x10000000
 }
-fn handle_plic_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
-    match riscv_decode::decode(instruction).ok() {
-        Some(Instruction::Lw(i)) => {
-            let value = state.plic.read_u32(guest_pa) as i32 as i64 as u64;
-            // println!("PLIC: Read value {:#x} at address {:#x}", value, guest_pa);
-            trap::set_register(state, i.rd(), value)
-        }
-        Some(Instruction::Sw(i)) => {
-            let value = trap::get_register(state, i.rs2()) as u32;
-            // println!("PLIC: Writing {:#x} to address {:#x}", value, guest_pa);
-
-            let mut clear_seip = false;
-            state.plic.write_u32(guest_pa, value, &mut clear_seip);
-            if clear_seip {
-                state.csrs.sip &= !0x200;
-            }
-            state.no_interrupt = false;
-        }
-        Some(instr) => {
-            println!("PLIC: Instruction {:?} used to target addr {:#x} from pc {:#x}", instr, guest_pa, csrr!(sepc));
-            loop {}
-        }
-        _ => {
-            println!("Unrecognized instruction targetting PLIC {:#x} at {:#x}!", instruction, csrr!(sepc));
-            loop {}
-        }
-    }
-    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
-    true
-}
@@ -1,5 +1,8 @@
 
 use crate::constants::MAX_GUEST_HARTS;
+use crate::context::Context;
+use crate::error::HypervisorError;
+use crate::mmio::MmioDevice;
 
 /// Number of contexts for the PLIC. Value is twice the max number of harts because each hart will
 /// have one M-mode context and one S-mode context.
@@ -55,7 +58,7 @@ impl PlicState {
                         }
 
                         for j in 0..32 {
-                            if self.pending[i] & (1 << j) != 0 {
+                            if self.pending[i] & self.enable[hart][i] & (1 << j) != 0 {
                                 let interrupt = i*32 + j;
                                 if self.source_priority[interrupt] > max_priority {
                                     max_priority = self.source_priority[interrupt];
@@ -75,7 +78,7 @@ impl PlicState {
         }
     }
 
-    pub fn write_u32(&mut self, addr: u64, value: u32, clear_seip: &mut bool) {
+    pub fn write_u32(&mut self, addr: u64, value: u32) {
         let offset = addr.wrapping_sub(self.base);
         if offset <= 0x800 {
             self.source_priority[offset as usize >> 2] = value;
@@ -97,7 +100,6 @@ impl PlicState {
                 if self.claim_complete[hart as usize] == value {
                     self.set_pending(value, false);
                     self.claim_complete[hart as usize] = 0;
-                    *clear_seip = true;
                 }
             }
         }
@@ -114,17 +116,16 @@ impl PlicState {
         }
     }
 
-    pub fn interrupt_pending(&self) -> bool {
-        const CONTEXT: usize = 1; // TODO: shouldn't be a constant
-
-        let threshold = self.thresholds[CONTEXT];
+    /// Whether an interrupt enabled for `context` is pending with priority above its threshold.
+    pub fn interrupt_pending(&self, context: usize) -> bool {
+        let threshold = self.thresholds[context];
         for i in 0..self.pending.len() {
-            if self.pending[i] == 0 {
+            if self.pending[i] & self.enable[context][i] == 0 {
                 continue;
             }
 
             for j in 0..32 {
-                if self.pending[i] & (1 << j) != 0 {
+                if self.pending[i] & self.enable[context][i] & (1 << j) != 0 {
                     if self.source_priority[i*32 + j] > threshold {
                         return true;
                     }
@@ -135,3 +136,24 @@ impl PlicState {
         false
     }
 }
+
+impl MmioDevice for PlicState {
+    fn read(&mut self, _state: &mut Context, addr: u64, width: u64) -> Result<u64, HypervisorError> {
+        match width {
+            4 if addr % 4 == 0 => Ok(self.read_u32(addr) as u64),
+            _ => Err(HypervisorError::UnsupportedAccess),
+        }
+    }
+    fn write(&mut self, state: &mut Context, addr: u64, width: u64, value: u64) -> Result<(), HypervisorError> {
+        match width {
+            4 if addr % 4 == 0 => {
+                self.write_u32(addr, value as u32);
+
+                // SEIP will be recomputed from the new PLIC state.
+                state.no_interrupt = false;
+                Ok(())
+            }
+            _ => Err(HypervisorError::UnsupportedAccess),
+        }
+    }
+}
@@ -1,26 +1,44 @@
-use crate::fdt::MachineMeta;
-use crate::context::Context;
+use arrayvec::ArrayVec;
+use crate::constants::MAX_HOST_HARTS;
+use crate::fdt::{device_span, MachineMeta};
 use crate::constants::SYMBOL_PA2VA_OFFSET;
 use crate::memory_region::{MemoryRegion, PageTableRegion};
-use crate::{riscv, statics, trap};
+use crate::hext::{self, GStage};
+use crate::stats::PoolOccupancy;
+use crate::{dirty, riscv, statics};
+use crate::trap::constants::{SATP_ASID, SATP_MODE, SATP_PPN};
 use core::ptr;
-use riscv_decode::types::RType;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 const PAGE_SIZE: u64 = 4096;
 const HPAGE_SIZE: u64 = 2 * 1024 * 1024;
 
 #[allow(unused)]
 mod segment_layout {
-    pub const HART_SEGMENT_SIZE: u64 = 1 << 30; // 1 GB
+    // Each guest gets a contiguous segment of host memory (see GuestLayout). It starts with state
+    // shared by all of the guest's vCPUs (see context::SharedContext), followed by a copy of the
+    // host device tree to build the guest's from whenever it boots.
+    pub const SHARED_OFFSET: u64 = 0;
+    pub const SHARED_FDT_OFFSET: u64 = SHARED_OFFSET + (1 << 20);
+    pub const SHARED_SIZE: u64 = 2 << 20;
+
+    // Then each vCPU has its own data segment and stack. The set for vCPU `i` starts
+    // `VCPUS_OFFSET + i * VCPU_STRIDE` bytes into the segment.
+    pub const VCPUS_OFFSET: u64 = SHARED_OFFSET + SHARED_SIZE;
     pub const DATA_OFFSET: u64 = 0;
     pub const DATA_SIZE: u64 = 2 << 20;
     pub const STACK_OFFSET: u64 = DATA_OFFSET + DATA_SIZE;
     pub const STACK_SIZE: u64 = 2 << 20;
-    pub const HEAP_OFFSET: u64 = STACK_OFFSET + STACK_SIZE;
-    pub const HEAP_SIZE: u64 = 28 << 20;
-    pub const PT_REGION_OFFSET: u64 = HEAP_OFFSET + HEAP_SIZE;
-    pub const PT_REGION_SIZE: u64 = 32 << 20;
-    pub const VM_RESERVATION_SIZE: u64 = PT_REGION_OFFSET + PT_REGION_SIZE; // 64MB
+    pub const VCPU_STRIDE: u64 = STACK_OFFSET + STACK_SIZE;
+
+    // Memory for the shadow (or G-stage) page tables of each vCPU.
+    pub const PT_REGION_SIZE_PER_VCPU: u64 = 32 << 20;
+
+    // The start of host memory belongs to the hypervisor itself: its text, shared data, the boot
+    // hart's data and stack, and the M-mode stacks (see linker.ld and machine.rs). Guest segments
+    // are placed after it, and after whatever the bootloader put in memory, like the initrd.
+    pub const HYPERVISOR_RESERVED_SIZE: u64 = 16 << 20;
+    pub const MIN_GUEST_MEMORY: u64 = 64 << 20;
 }
 pub use segment_layout::*;
 
@@ -43,10 +61,15 @@ pub mod pte_flags {
 }
 pub use pte_flags::*;
 
+// The hypervisor reserves 16 GB of virtual address space starting 64 GB below the top of memory.
+// Offsets are of PTEs within an Sv39 root page table, or the last level 3 page table for Sv48.
 mod page_table_constants {
-    pub const DIRECT_MAP_PT_INDEX: u64 = 0xf80;
+    pub const DIRECT_MAP_PT_INDEX: u64 = 0xe00;
     pub const DIRECT_MAP_OFFSET: u64 = DIRECT_MAP_PT_INDEX << 27 | ((!0) << 39);
-    pub const DIRECT_MAP_PAGES: u64 = 8; // Uses 1 GB pages
+    pub const DIRECT_MAP_PAGES: u64 = (HYPERVISOR_PT_INDEX - DIRECT_MAP_PT_INDEX) / 8; // Gigabytes of host physical memory covered
+    pub const HYPERVISOR_PT_INDEX: u64 = 0xe78;
+    pub const RESERVED_PT_END: u64 = 0xe80;
+    pub const RESERVED_END: u64 = RESERVED_PT_END << 27 | ((!0) << 39);
 }
 pub use page_table_constants::*;
 
@@ -54,12 +77,16 @@ pub use page_table_constants::*;
 #[derive(Copy, Clone)]
 pub struct BootPageTable([u64; 512]);
 impl BootPageTable {
+    /// Map the direct map and hypervisor with gigapages, which on the FU540 may not respect PMP
+    /// boundaries within them (see `map_direct`). This is harmless while the table is in use: the
+    /// hart only touches the hypervisor text and shared data and its own segment until `init`
+    /// switches to page tables with smaller pages, so it never makes an access PMP should deny.
     pub fn init(&mut self) {
         for i in 0..DIRECT_MAP_PAGES {
             self.0[(DIRECT_MAP_PT_INDEX/8 + i) as usize] = (i << 28) | PTE_AD | PTE_RWXV;
         }
 
-        self.0[511] = 0x20000000 | 0xcf;
+        self.0[(HYPERVISOR_PT_INDEX/8) as usize] = 0x20000000 | 0xcf;
     }
 }
 
@@ -72,12 +99,105 @@ pub fn pa2sa(pa: u64) -> u64 {
     pa + SYMBOL_PA2VA_OFFSET
 }
 pub fn sa2pa(sa: u64) -> u64 {
-    if sa < 0xffffffffc0000000 {
+    if sa < SYMBOL_PA2VA_OFFSET + 0x80000000 {
         panic!("pa2sa given invalid address");
     }
     sa - SYMBOL_PA2VA_OFFSET
 }
 
+/// Where a guest's segment of host memory is, and how it is divided up: after the shared state and
+/// the per-vCPU data segments and stacks come the dirty log, a copy of the guest kernel image, the
+/// page table region, and finally guest memory. Every part is 2 MB aligned.
+#[derive(Copy, Clone, Debug)]
+pub struct GuestLayout {
+    /// Host physical address of the segment.
+    pub base: u64,
+    pub num_vcpus: u64,
+    /// Host physical address and size of the bitmaps of the dirty log (see dirty.rs).
+    pub dirty_log: u64,
+    pub dirty_log_size: u64,
+    /// Host physical address and size of the copy of the guest kernel image.
+    pub kernel_image: u64,
+    pub kernel_image_size: u64,
+    /// Host physical address and size of the page table region, which is split between the vCPUs.
+    pub page_tables: u64,
+    pub page_tables_size: u64,
+    /// Host physical address and size of guest memory.
+    pub memory: u64,
+    pub memory_size: u64,
+}
+impl GuestLayout {
+    /// Lay out a segment at `base` for a guest with `num_vcpus` vCPUs and `memory_size` bytes of
+    /// memory, booting a kernel image of `kernel_image_size` bytes.
+    pub fn new(base: u64, num_vcpus: u64, kernel_image_size: u64, memory_size: u64) -> Self {
+        assert_eq!(base % HPAGE_SIZE, 0);
+        let dirty_log = base + VCPUS_OFFSET + VCPU_STRIDE * num_vcpus;
+        let dirty_log_size = (dirty::DirtyLog::bitmaps_size(memory_size).max(1) + HPAGE_SIZE - 1) & !(HPAGE_SIZE - 1);
+        let kernel_image = dirty_log + dirty_log_size;
+        let kernel_image_size = (kernel_image_size.max(1) + HPAGE_SIZE - 1) & !(HPAGE_SIZE - 1);
+        let page_tables = kernel_image + kernel_image_size;
+        let page_tables_size = PT_REGION_SIZE_PER_VCPU * num_vcpus;
+        Self {
+            base,
+            num_vcpus,
+            dirty_log,
+            dirty_log_size,
+            kernel_image,
+            kernel_image_size,
+            page_tables,
+            page_tables_size,
+            memory: page_tables + page_tables_size,
+            memory_size,
+        }
+    }
+
+    /// Host physical address of the data segment of `vcpu`, which its stack follows.
+    pub fn vcpu_base(&self, vcpu: usize) -> u64 {
+        self.base + VCPUS_OFFSET + VCPU_STRIDE * vcpu as u64
+    }
+
+    pub fn end(&self) -> u64 {
+        self.memory + self.memory_size
+    }
+}
+
+/// Host physical address that the first guest segment starts at: past the hypervisor's own
+/// reservation at the start of host memory, and past the initrd.
+pub fn guests_start(machine: &MachineMeta) -> u64 {
+    let start = (machine.physical_memory_offset + HYPERVISOR_RESERVED_SIZE).max(machine.initrd_end);
+    (start + HPAGE_SIZE - 1) & !(HPAGE_SIZE - 1)
+}
+
+/// Lay out the segments of guests with the given numbers of vCPUs, one after another from
+/// `guests_start`. Each guest gets `rvirt.memory` bytes of memory if that option is set, and
+/// otherwise an equal share of the rest of host memory. Guests that don't fit are left out.
+pub fn layout_guests(machine: &MachineMeta, guest_vcpus: &[usize]) -> ArrayVec<[GuestLayout; MAX_HOST_HARTS]> {
+    let kernel_image_size = machine.initrd_end - machine.initrd_start;
+    let mut memory_end = machine.physical_memory_offset + machine.physical_memory_size;
+    if memory_end > DIRECT_MAP_PAGES << 30 {
+        println!("WARN: Host memory above {} GB can't be mapped and is left unused", DIRECT_MAP_PAGES);
+        memory_end = DIRECT_MAP_PAGES << 30;
+    }
+    let start = guests_start(machine);
+    let share = memory_end.saturating_sub(start) / guest_vcpus.len().max(1) as u64;
+
+    let mut layouts = ArrayVec::new();
+    let mut base = start;
+    for (i, &num_vcpus) in guest_vcpus.iter().enumerate() {
+        let overhead = GuestLayout::new(base, num_vcpus as u64, kernel_image_size, 0).memory - base;
+        let memory_size = machine.config.guest_memory.unwrap_or(share.saturating_sub(overhead)) & !(HPAGE_SIZE - 1);
+        let layout = GuestLayout::new(base, num_vcpus as u64, kernel_image_size, memory_size);
+        if memory_size < MIN_GUEST_MEMORY || layout.end() > memory_end {
+            println!("WARN: Not enough host memory for guest {} ({} MB)", i + 1, memory_size >> 20);
+            break;
+        }
+
+        layouts.push(layout);
+        base = layout.end();
+    }
+    layouts
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum PageTableRoot {
     UVA,
@@ -89,10 +209,57 @@ use PageTableRoot::*;
 
 const NULL_PAGE_PTR: u64 = 2;
 
+/// Number of guest address spaces to keep shadow page tables for.
+const ADDRESS_SPACES: usize = 8;
+
+/// Key for address spaces that aren't in use. No valid satp value has all mode bits set.
+const INVALID_SATP: u64 = !0;
+
+/// Most page tables that a single call to `PageTables::set_mapping` can allocate.
+const MAX_TABLES_PER_MAPPING: u64 = 3;
+
+/// Shadow page tables for one guest address space.
+#[derive(Copy, Clone)]
+struct AddressSpace {
+    /// Guest satp value identifying the address space.
+    satp: u64,
+    /// Sv39 root page tables for UVA, KVA and MVA. These hold the hypervisor's reserved mappings.
+    roots: [u64; 3],
+    /// Sv48 root page tables for UVA, KVA and MVA, used instead of `roots` when the guest is using
+    /// Sv48. The last entry of each points to the corresponding Sv39 root, which covers the same
+    /// top 512 GB of the address space.
+    roots48: [u64; 3],
+    /// Value of `PageTables::clock` when this address space was last switched to.
+    last_used: u64,
+}
+impl AddressSpace {
+    fn levels(&self) -> u64 {
+        paging_levels(self.satp).unwrap_or(3)
+    }
+
+    fn root_pa(&self, index: usize) -> u64 {
+        if self.levels() == 4 { self.roots48[index] } else { self.roots[index] }
+    }
+}
+
 pub struct PageTables {
     region: PageTableRegion,
-    root_page_tables: [u64; 4],
+    mpa_root: u64,
+    address_spaces: [AddressSpace; ADDRESS_SPACES],
+    current: usize,
+    clock: u64,
+    sv48_supported: bool,
     free_list_head: u64,
+
+    /// Pool occupancy, in pages. `total_pages` excludes any pages overlapping the init RAM disk.
+    free_pages: u64,
+    total_pages: u64,
+    peak_pages: u64,
+    /// Number of times the pool ran dry and shadow mappings had to be reclaimed.
+    reclaims: u64,
+    /// Next root page table entry of the current address space to be cleared by `reclaim`, counting
+    /// across the entries of the UVA, KVA and MVA roots in turn.
+    reclaim_hand: u64,
 }
 impl PageTables {
     /// Create a set of page tables from a memory region.
@@ -107,8 +274,17 @@ impl PageTables {
 
         let mut ret = Self {
             region,
-            root_page_tables: [0, 0, 0, 0],
+            mpa_root: 0,
+            address_spaces: [AddressSpace { satp: INVALID_SATP, roots: [0; 3], roots48: [0; 3], last_used: 0 }; ADDRESS_SPACES],
+            current: 0,
+            clock: 0,
+            sv48_supported: false,
             free_list_head: NULL_PAGE_PTR,
+            free_pages: 0,
+            total_pages: 0,
+            peak_pages: 0,
+            reclaims: 0,
+            reclaim_hand: 0,
         };
 
         // initialize free list
@@ -117,69 +293,337 @@ impl PageTables {
         while addr < end {
             if addr + PAGE_SIZE <= initrd_start || addr >= initrd_end {
                 ret.free_page(addr);
+                ret.total_pages += 1;
             }
 
             addr += PAGE_SIZE;
         }
 
         // initialize root page tables
-        for i in 0..4 {
-            ret.root_page_tables[i] = ret.alloc_page();
+        ret.mpa_root = ret.alloc_page();
+        for i in 0..ADDRESS_SPACES {
+            for j in 0..3 {
+                let root = ret.alloc_page();
+                let root48 = ret.alloc_page();
+                ret.region.set_nonleaf_pte(root48 + 511 * 8, (root >> 2) | PTE_VALID);
+                ret.address_spaces[i].roots[j] = root;
+                ret.address_spaces[i].roots48[j] = root48;
+            }
         }
 
         ret
     }
 
+    /// Physical address of the given root page table of the current address space.
     pub fn root_pa(&self, root: PageTableRoot) -> u64 {
-        let i = match root {
-            MPA => 0,
-            UVA => 1,
-            KVA => 2,
-            MVA => 3,
+        let address_space = &self.address_spaces[self.current];
+        match root {
+            MPA => self.mpa_root,
+            UVA => address_space.root_pa(0),
+            KVA => address_space.root_pa(1),
+            MVA => address_space.root_pa(2),
+        }
+    }
+
+    /// Copy the hypervisor's reserved mappings from the MPA root page table into the roots of every
+    /// address space, and then check whether the host can use them with Sv48.
+    pub fn copy_reserved_mappings(&mut self) {
+        for i in 0..ADDRESS_SPACES {
+            for j in 0..3 {
+                let root = self.address_spaces[i].roots[j];
+                for offset in (DIRECT_MAP_PT_INDEX..RESERVED_PT_END).step_by(8) {
+                    let pte = self.region[self.mpa_root + offset];
+                    unsafe { self.region.set_pte_unchecked(root + offset, pte) }
+                }
+            }
+        }
+
+        // Writes to satp selecting an unsupported mode have no effect.
+        let old_satp = csrr!(satp);
+        let satp = (9 << 60) | (self.address_spaces[0].roots48[0] >> 12);
+        unsafe { csrw!(satp, satp) }
+        riscv::sfence_vma();
+        self.sv48_supported = csrr!(satp) == satp;
+        unsafe { csrw!(satp, old_satp) }
+        riscv::sfence_vma();
+    }
+
+    /// Whether the host supports Sv48, and so can shadow guest page tables that use it.
+    pub fn sv48_supported(&self) -> bool {
+        self.sv48_supported
+    }
+
+    /// Switch to the shadow page tables for the guest address space identified by `satp`. They are
+    /// reused if still cached, and otherwise those of the least recently used address space are
+    /// cleared and recycled.
+    pub fn switch_address_space(&mut self, satp: u64) {
+        self.clock += 1;
+
+        let index = match self.address_spaces.iter().position(|a| a.satp == satp) {
+            Some(index) => index,
+            None => {
+                let index = (0..ADDRESS_SPACES).min_by_key(|&i| self.address_spaces[i].last_used).unwrap();
+                self.clear_address_space(index);
+                self.address_spaces[index].satp = satp;
+                index
+            }
         };
-        self.root_page_tables[i]
+
+        self.address_spaces[index].last_used = self.clock;
+        self.current = index;
+    }
+
+    /// Remove all guest mappings from address spaces with the given ASID, or from every address
+    /// space if `asid` is None.
+    pub fn flush(&mut self, asid: Option<u64>) {
+        for i in 0..ADDRESS_SPACES {
+            if self.address_space_matches(i, asid) {
+                self.clear_address_space(i);
+            }
+        }
+    }
+
+    /// Remove the shadow mappings of the `1 << shift` bytes of guest virtual address space starting
+    /// at `va` from every address space, where `shift` is the size mapped by a single entry at some
+    /// level of the guest's page tables.
+    pub fn invalidate(&mut self, va: u64, shift: u64) {
+        if is_reserved(va) {
+            return;
+        }
+
+        for i in 0..ADDRESS_SPACES {
+            let levels = self.address_spaces[i].levels();
+            if self.address_spaces[i].satp == INVALID_SATP || !is_canonical(va, levels) {
+                continue;
+            }
+
+            // Only an Sv48 root entry covers the hypervisor's reserved range without being part of
+            // it. The mappings it leads to are shared with the Sv39 roots, so just clear them all.
+            if va < RESERVED_END && va + ((1 << shift) - 1) >= DIRECT_MAP_OFFSET {
+                self.clear_address_space(i);
+                continue;
+            }
+
+            for j in 0..3 {
+                let mut page_table = self.address_spaces[i].root_pa(j);
+                for level in 0..levels {
+                    let level_shift = 12 + 9 * (levels - 1 - level);
+                    let index = (va >> level_shift) & 0x1ff;
+                    let pte = self.region[page_table + index * 8];
+                    if pte & PTE_VALID == 0 {
+                        break;
+                    }
+                    if pte & PTE_RWXV != PTE_VALID || level_shift <= shift {
+                        self.clear_page_table_range(page_table, index, index + 1);
+                        break;
+                    }
+                    page_table = (pte >> 10) << 12;
+                }
+            }
+        }
+    }
+
+    /// Remove write permission from every shadow mapping of the host page at `host_pa`. Larger pages
+    /// containing it are removed altogether, so that the rest of their range gets remapped around it.
+    pub fn write_protect(&mut self, host_pa: u64) {
+        self.write_protect_host_range(host_pa, host_pa + PAGE_SIZE);
+    }
+
+    /// Remove write permission from every shadow mapping. Writable larger pages are removed, so that
+    /// writes get noticed a page at a time.
+    pub fn write_protect_all(&mut self) {
+        self.write_protect_host_range(0, !0);
+    }
+
+    fn write_protect_host_range(&mut self, host_start: u64, host_end: u64) {
+        for i in 0..ADDRESS_SPACES {
+            if self.address_spaces[i].satp == INVALID_SATP {
+                continue;
+            }
+
+            for j in 0..3 {
+                let root = self.address_spaces[i].roots[j];
+                self.write_protect_range(root, 0, DIRECT_MAP_PT_INDEX/8, 30, host_start, host_end);
+                self.write_protect_range(root, RESERVED_PT_END/8, 512, 30, host_start, host_end);
+
+                if self.address_spaces[i].levels() == 4 {
+                    let root48 = self.address_spaces[i].roots48[j];
+                    self.write_protect_range(root48, 0, 511, 39, host_start, host_end);
+                }
+            }
+        }
+    }
+
+    fn write_protect_range(&mut self, pa: u64, start_index: u64, end_index: u64, shift: u64,
+                           host_start: u64, host_end: u64) {
+        for i in start_index..end_index {
+            let pte = self.region[pa + i * 8];
+            let page = (pte >> 10) << 12;
+            if pte & PTE_RWXV == PTE_VALID {
+                self.write_protect_range(page, 0, 512, shift - 9, host_start, host_end);
+            } else if pte & PTE_VALID == 0 || pte & PTE_WRITE == 0 ||
+                host_end <= page || host_start >= page + (1 << shift) {
+                continue;
+            } else if shift > 12 {
+                self.region.set_invalid_pte(pa + i * 8, 0);
+            } else {
+                self.region.set_leaf_pte(pa + i * 8, pte & !PTE_WRITE);
+            }
+        }
+    }
+
+    /// Set whether guest memory may be written through the MPA root, which maps it with 2 MB pages.
+    /// Only the page containing `guest_pa` is changed, or every page if it is None.
+    pub fn set_mpa_writable(&mut self, guest_pa: Option<u64>, writable: bool) {
+        for i in 0..DIRECT_MAP_PT_INDEX/8 {
+            let root_pte = self.region[self.mpa_root + i * 8];
+            if root_pte & PTE_RWXV != PTE_VALID {
+                continue;
+            }
+
+            let page_table = (root_pte >> 10) << 12;
+            for j in 0..512 {
+                let pte = self.region[page_table + j * 8];
+                let va = (i << 30) | (j << 21);
+                if pte & PTE_VALID == 0 || guest_pa.map(|pa| pa >> 21 != va >> 21).unwrap_or(false) {
+                    continue;
+                }
+
+                // Pages split by set_mpa_holes have their 4 KB leaves changed instead.
+                let (table, entries) = if pte & PTE_RWXV == PTE_VALID {
+                    ((pte >> 10) << 12, 512)
+                } else {
+                    (page_table + j * 8, 1)
+                };
+                for k in 0..entries {
+                    let pte = self.region[table + k * 8];
+                    if pte & PTE_VALID != 0 {
+                        let pte = if writable { pte | PTE_WRITE } else { pte & !PTE_WRITE };
+                        self.region.set_leaf_pte(table + k * 8, pte);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Leave the 4 KB guest physical pages in `holes` unmapped by the MPA root, so that accesses to
+    /// them fault and can be emulated. The 2 MB pages containing them are split into 4 KB pages, and
+    /// any split for holes that are no longer needed are merged back.
+    pub fn set_mpa_holes(&mut self, holes: &[u64]) {
+        for i in 0..DIRECT_MAP_PT_INDEX/8 {
+            let root_pte = self.region[self.mpa_root + i * 8];
+            if root_pte & PTE_RWXV != PTE_VALID {
+                continue;
+            }
+
+            let page_table = (root_pte >> 10) << 12;
+            for j in 0..512 {
+                let pte = self.region[page_table + j * 8];
+                if pte & PTE_RWXV != PTE_VALID {
+                    continue;
+                }
+
+                // Every leaf of a split page has the same permissions, and at most a few are holes.
+                let split = (pte >> 10) << 12;
+                let (k, leaf) = (0..512).map(|k| (k, self.region[split + k * 8]))
+                    .find(|&(_, leaf)| leaf & PTE_VALID != 0).unwrap();
+                self.region.set_leaf_pte(page_table + j * 8, leaf - (k << 10));
+                self.free_page(split);
+            }
+        }
+
+        for &hole in holes {
+            let root_pte = self.region[self.mpa_root + (hole >> 30) * 8];
+            let pte_addr = ((root_pte >> 10) << 12) + ((hole >> 21) & 0x1ff) * 8;
+            let pte = self.region[pte_addr];
+            if pte & PTE_VALID == 0 {
+                continue;
+            } else if pte & PTE_RWXV != PTE_VALID {
+                let split = self.alloc_page();
+                for k in 0..512 {
+                    self.region.set_leaf_pte(split + k * 8, pte + (k << 10));
+                }
+                self.region.set_nonleaf_pte(pte_addr, (split >> 2) | PTE_VALID);
+            }
+
+            let split = (self.region[pte_addr] >> 10) << 12;
+            self.region.set_invalid_pte(split + ((hole >> 12) & 0x1ff) * 8, 0);
+        }
+    }
+
+    fn address_space_matches(&self, index: usize, asid: Option<u64>) -> bool {
+        let satp = self.address_spaces[index].satp;
+        satp != INVALID_SATP && asid.map(|asid| (satp & SATP_ASID) >> 44 == asid).unwrap_or(true)
+    }
+
+    fn clear_address_space(&mut self, index: usize) {
+        for j in 0..3 {
+            let root = self.address_spaces[index].roots[j];
+            self.clear_page_table_range(root, 0, DIRECT_MAP_PT_INDEX/8);
+            self.clear_page_table_range(root, RESERVED_PT_END/8, 512);
+
+            let root48 = self.address_spaces[index].roots48[j];
+            self.clear_page_table_range(root48, 0, 511);
+        }
     }
 
     pub fn install_root(&self, root: PageTableRoot) {
-        let new_satp = (8 << 60) | (self.root_pa(root) >> 12);
+        let mode = if root != MPA && self.address_spaces[self.current].levels() == 4 { 9 } else { 8 };
+        let new_satp = (mode << 60) | (self.root_pa(root) >> 12);
         if csrr!(satp) != new_satp {
             unsafe { csrw!(satp, new_satp) }
             riscv::sfence_vma();
         }
     }
 
-    pub fn set_mapping(&mut self, root: PageTableRoot, va: u64, pte: u64) {
-        if va >= DIRECT_MAP_OFFSET {
+    /// Map the `1 << shift` bytes of guest virtual address space starting at `va` with the single
+    /// leaf `pte`, where `shift` is 12, 21 or 30. Any smaller mappings it covers are removed.
+    pub fn set_mapping(&mut self, root: PageTableRoot, va: u64, pte: u64, shift: u64) {
+        if is_reserved(va) {
             panic!("Guest attempted to access reserved virtual address: {:x}", va);
         }
 
-        let pte_addr = self.pte_for_addr(root, va);
+        // Reclaim memory up front if needed, since it could free the page tables being walked.
+        if self.free_pages < MAX_TABLES_PER_MAPPING {
+            self.reclaim(MAX_TABLES_PER_MAPPING);
+        }
+
+        let pte_addr = self.pte_for_addr(root, va, shift);
+        let old_pte = self.region[pte_addr];
+        if old_pte & PTE_RWXV == PTE_VALID {
+            let page = (old_pte >> 10) << 12;
+            self.clear_page_table_range(page, 0, 512);
+            self.free_page(page);
+        }
         self.region.set_leaf_pte(pte_addr, pte);
     }
 
-    // Returns the physical address of the pte for a given virtual address.
-    fn pte_for_addr(&mut self, root: PageTableRoot, va: u64) -> u64 {
+    // Returns the physical address of the pte for a given virtual address, at the level whose entries
+    // map `1 << shift` bytes. Larger leaf mappings in the way are replaced by page tables.
+    fn pte_for_addr(&mut self, root: PageTableRoot, va: u64, shift: u64) -> u64 {
         // These ranges use huge pages...
-        assert!(va < DIRECT_MAP_OFFSET);
-        assert!(is_sv39(va));
+        let levels = self.address_spaces[self.current].levels();
+        assert!(!is_reserved(va));
+        assert!(is_canonical(va, levels));
         assert!(root != PageTableRoot::MPA);
 
         let mut page_table = self.root_pa(root);
-        for level in 0..2 {
-            let pte_index = (va >> (30 - 9 * level)) & 0x1ff;
+        let mut level_shift = 12 + 9 * (levels - 1);
+        while level_shift > shift {
+            let pte_index = (va >> level_shift) & 0x1ff;
             let pte_addr = page_table + pte_index * 8;
             let pte = self.region[pte_addr];
 
-            if pte & PTE_VALID != 0 {
-                assert_eq!(pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE), 0);
+            if pte & PTE_RWXV == PTE_VALID {
                 page_table = (pte >> 10) << 12;
             } else {
                 let page = self.alloc_page();
                 self.region.set_nonleaf_pte(pte_addr, (page >> 2) | PTE_VALID);
                 page_table = page;
             }
+            level_shift -= 9;
         }
-        page_table + ((va >> 12) & 0x1ff) * 8
+        page_table + ((va >> shift) & 0x1ff) * 8
     }
 
     pub fn clear_page_table_range(&mut self, pa: u64, start_index: u64, end_index: u64) {
@@ -197,6 +641,58 @@ impl PageTables {
         }
     }
 
+    /// Free shadow page tables until at least `pages` pages are available. The address spaces other
+    /// than the current one are cleared first, least recently used first. After that, the subtrees
+    /// under the current address space's root entries are cleared one at a time in clock order, so
+    /// that the mappings most recently filled in tend to survive.
+    fn reclaim(&mut self, pages: u64) {
+        self.reclaims += 1;
+
+        while self.free_pages < pages {
+            let current = self.current;
+            let victim = (0..ADDRESS_SPACES)
+                .filter(|&i| i != current && self.address_spaces[i].satp != INVALID_SATP)
+                .min_by_key(|&i| self.address_spaces[i].last_used);
+            if let Some(index) = victim {
+                self.clear_address_space(index);
+                self.address_spaces[index].satp = INVALID_SATP;
+                self.address_spaces[index].last_used = 0;
+                continue;
+            }
+
+            let mut entries_left = 3 * 512;
+            while self.free_pages < pages {
+                if entries_left == 0 {
+                    panic!("Out of hypervisor memory for page tables");
+                }
+                entries_left -= 1;
+
+                let (j, index) = (self.reclaim_hand / 512, self.reclaim_hand % 512);
+                self.reclaim_hand = (self.reclaim_hand + 1) % (3 * 512);
+
+                // The last Sv48 root entry leads to the Sv39 root, which is swept separately.
+                let address_space = self.address_spaces[current];
+                if address_space.levels() == 4 && index < 511 {
+                    self.clear_page_table_range(address_space.roots48[j as usize], index, index + 1);
+                }
+                if index < DIRECT_MAP_PT_INDEX/8 || index >= RESERVED_PT_END/8 {
+                    self.clear_page_table_range(address_space.roots[j as usize], index, index + 1);
+                }
+            }
+            riscv::sfence_vma();
+        }
+    }
+
+    /// Current occupancy of the page table pool.
+    pub fn occupancy(&self) -> PoolOccupancy {
+        PoolOccupancy {
+            used: self.total_pages - self.free_pages,
+            peak: self.peak_pages,
+            total: self.total_pages,
+            reclaims: self.reclaims,
+        }
+    }
+
     fn alloc_page(&mut self) -> u64 {
         if self.free_list_head == NULL_PAGE_PTR {
             panic!("Out of hypervisor memory for page tables");
@@ -204,6 +700,8 @@ impl PageTables {
 
         let free = self.free_list_head;
         self.free_list_head = self.region[free];
+        self.free_pages -= 1;
+        self.peak_pages = self.peak_pages.max(self.total_pages - self.free_pages);
 
         let mut addr = free;
         while addr < free + PAGE_SIZE {
@@ -217,6 +715,7 @@ impl PageTables {
     fn free_page(&mut self, page: u64) {
         self.region.set_invalid_pte(page, self.free_list_head);
         self.free_list_head = page;
+        self.free_pages += 1;
     }
 }
 
@@ -234,12 +733,49 @@ pub fn is_sv39(va: u64) -> bool {
     shifted == 0 || shifted == 0x3ffffff
 }
 
+/// Returns whether va is a sign extended 48 bit address
+pub fn is_sv48(va: u64) -> bool {
+    let shifted = va >> 47;
+    shifted == 0 || shifted == 0x1ffff
+}
+
+/// Returns whether va is valid for page tables with the given number of levels.
+fn is_canonical(va: u64, levels: u64) -> bool {
+    if levels == 4 { is_sv48(va) } else { is_sv39(va) }
+}
+
+/// Returns whether va is in the range of virtual addresses reserved by the hypervisor.
+pub fn is_reserved(va: u64) -> bool {
+    va >= DIRECT_MAP_OFFSET && va < RESERVED_END
+}
+
+/// Number of page table levels for the paging mode selected by satp, or None if the mode doesn't
+/// use page tables or isn't supported.
+pub fn paging_levels(satp: u64) -> Option<u64> {
+    match (satp & SATP_MODE) >> 60 {
+        8 => Some(3),
+        9 => Some(4),
+        _ => None,
+    }
+}
+
 pub enum PageTableLevel {
     Level4KB,
     Level2MB,
     Level1GB,
     Level512GB,
 }
+impl PageTableLevel {
+    /// Log2 of the number of bytes mapped by a leaf at this level.
+    pub fn shift(&self) -> u64 {
+        match *self {
+            PageTableLevel::Level4KB => 12,
+            PageTableLevel::Level2MB => 21,
+            PageTableLevel::Level1GB => 30,
+            PageTableLevel::Level512GB => 39,
+        }
+    }
+}
 
 pub struct AddressTranslation {
     pub pte_value: u64,
@@ -249,31 +785,29 @@ pub struct AddressTranslation {
 }
 
 // Returns the guest physical address associated with a given guest virtual address, by walking
-// guest page tables.
-pub fn translate_guest_address(guest_memory: &MemoryRegion, root_page_table: u64, addr: u64) -> Option<AddressTranslation> {
-    if !is_sv39(addr) || root_page_table % PAGE_SIZE != 0 {
+// the guest page tables selected by satp.
+pub fn translate_guest_address(guest_memory: &MemoryRegion, satp: u64, addr: u64) -> Option<AddressTranslation> {
+    let levels = paging_levels(satp)?;
+    if !is_canonical(addr, levels) {
         return None;
     }
 
-    let mut page_table = root_page_table;
-    for level in 0..3 {
-        let pte_index = (addr >> (30 - 9 * level)) & 0x1ff;
+    let mut page_table = (satp & SATP_PPN) << 12;
+    for level in 0..levels {
+        let shift = 12 + 9 * (levels - 1 - level);
+        let pte_index = (addr >> shift) & 0x1ff;
         let pte_addr = page_table + pte_index * 8;
         let pte = guest_memory.get(pte_addr)?;
 
         if pte & PTE_VALID == 0 || ((pte & PTE_WRITE) != 0 && (pte & PTE_READ) == 0) {
             return None;
         } else if pte & (PTE_READ | PTE_EXECUTE) != 0 {
-            let guest_pa = match level {
-                2 => ((pte >> 10) << 12) | (addr & 0xfff),
-                1 => ((pte >> 19) << 21) | (addr & 0x1fffff),
-                0 => ((pte >> 28) << 30) | (addr & 0x3fffffff),
-                _ => unreachable!(),
-            };
-            let level = match level {
-                0 => PageTableLevel::Level1GB,
-                1 => PageTableLevel::Level2MB,
-                2 => PageTableLevel::Level4KB,
+            let guest_pa = (((pte >> 10) << 12) & !((1 << shift) - 1)) | (addr & ((1 << shift) - 1));
+            let level = match shift {
+                39 => PageTableLevel::Level512GB,
+                30 => PageTableLevel::Level1GB,
+                21 => PageTableLevel::Level2MB,
+                12 => PageTableLevel::Level4KB,
                 _ => unreachable!(),
             };
 
@@ -291,6 +825,13 @@ pub fn translate_guest_address(guest_memory: &MemoryRegion, root_page_table: u64
     None
 }
 
+/// Atomically replace the guest page table entry at `pte_addr` with `new` if it still holds
+/// `current`, as hardware does when updating the A and D bits. Returns whether it was replaced.
+pub fn compare_and_swap_guest_pte(guest_memory: &mut MemoryRegion, pte_addr: u64, current: u64, new: u64) -> bool {
+    let pte = unsafe { &*(&mut guest_memory[pte_addr] as *mut u64 as *const AtomicU64) };
+    pte.compare_and_swap(current, new, Ordering::SeqCst) == current
+}
+
 pub unsafe fn monitor_init(shared: &statics::Shared) {
     let boot_page_table_pa = shared.boot_page_table.as_ptr() as u64;
 
@@ -306,40 +847,62 @@ pub unsafe fn monitor_init(shared: &statics::Shared) {
     riscv::sfence_vma();
 }
 
-pub unsafe fn init(hart_base_pa: u64, machine: &MachineMeta) -> (PageTables, MemoryRegion, u64) {
-    assert_eq!(hart_base_pa % HART_SEGMENT_SIZE, 0);
+/// Initialize the memory subsystem for one vCPU of the guest whose segment starts at `hart_base_pa`.
+/// The shadow page table region is split evenly between the guest's `num_vcpus` vCPUs. G-stage page
+/// tables are only created if guests run with the hypervisor extension.
+pub unsafe fn init(layout: &GuestLayout, vcpu: usize, machine: &MachineMeta) -> (PageTables, Option<GStage>, MemoryRegion, u64) {
+    assert!(vcpu < layout.num_vcpus as usize);
+    let vcpu_base_pa = layout.vcpu_base(vcpu);
 
+    // Guest memory starts at the same physical address as host memory.
     let gpm_offset = machine.physical_memory_offset;
-    let gpm_size = HART_SEGMENT_SIZE.checked_sub(VM_RESERVATION_SIZE).unwrap();
-    let guest_shift = VM_RESERVATION_SIZE + hart_base_pa.checked_sub(machine.physical_memory_offset).unwrap();
-    assert_eq!(gpm_offset, 0x80000000);
-    assert!(gpm_size > 64 * 1024 * 1024);
+    let gpm_size = layout.memory_size;
+    let guest_shift = layout.memory.checked_sub(gpm_offset).unwrap();
+    assert!(gpm_size >= MIN_GUEST_MEMORY);
 
     // Create guest memory region
-    let guest_memory = MemoryRegion::with_base_address(pa2va(gpm_offset + guest_shift), machine.physical_memory_offset, gpm_size);
+    let guest_memory = MemoryRegion::with_base_address(pa2va(layout.memory), gpm_offset, gpm_size);
+
+    // With the hypervisor extension, the start of this vCPU's share of the page table region holds
+    // its G-stage page tables.
+    let pt_size = (layout.page_tables_size / layout.num_vcpus) & !(hext::GSTAGE_ALIGN - 1);
+    let mut pt_base = layout.page_tables + pt_size * vcpu as u64;
+    let mut pt_len = pt_size;
+    let gstage = if hext::enabled() {
+        let gstage = GStage::new(pt_base, &guest_memory, guest_shift);
+        pt_base += hext::GSTAGE_SIZE;
+        pt_len -= hext::GSTAGE_SIZE;
+        Some(gstage)
+    } else {
+        None
+    };
 
     // Create shadow page tables
-    let memory_region = MemoryRegion::new(pa2va(hart_base_pa + PT_REGION_OFFSET), PT_REGION_SIZE);
+    let memory_region = MemoryRegion::new(pa2va(pt_base), pt_len);
     let mut shadow_page_tables = PageTables::new(memory_region, machine.initrd_start, machine.initrd_end);
 
     // Initialize shadow page tables
-    for &root in &[MPA, UVA, KVA, MVA] {
-        let va = pa2va(shadow_page_tables.root_pa(root));
+    {
+        let va = pa2va(shadow_page_tables.root_pa(MPA));
         ptr::write_bytes(va as *mut u8, 0, PAGE_SIZE as usize);
 
-        *((va + DIRECT_MAP_PT_INDEX + 0 * 8) as *mut u64) = (0 << 28) | PTE_AD | PTE_RWV;
+        // The second gigabyte of MMIO lies within a single PMP entry, so it can be mapped with one
+        // gigapage. The rest of the direct map can't be (see map_direct).
         *((va + DIRECT_MAP_PT_INDEX + 1 * 8) as *mut u64) = (1 << 28) | PTE_AD | PTE_RWV;
-        *((va + DIRECT_MAP_PT_INDEX + (hart_base_pa >> 30) * 8) as *mut u64) = (hart_base_pa >> 2) | PTE_AD | PTE_RWV;
 
         // Hypervisor code + data
         let hp = 2 << 18;
         let page = shadow_page_tables.alloc_page();
-        *((va + 0xff8) as *mut u64) = (page >> 2) | PTE_VALID;
+        *((va + HYPERVISOR_PT_INDEX) as *mut u64) = (page >> 2) | PTE_VALID;
         shadow_page_tables.region.set_pte_unchecked(page, 0x20000000 | PTE_AD | PTE_READ | PTE_EXECUTE | PTE_VALID);              // Code + read only data
         shadow_page_tables.region.set_pte_unchecked(page+8, (0x20000000+hp) | PTE_AD | PTE_READ | PTE_WRITE | PTE_VALID);         // Shared data
-        shadow_page_tables.region.set_pte_unchecked(page+16, ((hart_base_pa>>2)) | PTE_AD | PTE_READ | PTE_WRITE | PTE_VALID);    // Data
-        shadow_page_tables.region.set_pte_unchecked(page+32, ((hart_base_pa>>2)+hp) | PTE_AD | PTE_READ | PTE_WRITE | PTE_VALID); // Stack
+        shadow_page_tables.region.set_pte_unchecked(page+16, ((vcpu_base_pa>>2)) | PTE_AD | PTE_READ | PTE_WRITE | PTE_VALID);    // Data
+        shadow_page_tables.region.set_pte_unchecked(page+32, ((vcpu_base_pa>>2)+hp) | PTE_AD | PTE_READ | PTE_WRITE | PTE_VALID); // Stack
+
+        map_direct(&mut shadow_page_tables, 0, 1 << 30, device_span(&machine.virtio));
+        map_direct(&mut shadow_page_tables, layout.base, layout.end(), (0, 0));
     }
+    shadow_page_tables.copy_reserved_mappings();
     shadow_page_tables.install_root(MPA);
 
     // Map guest physical memory
@@ -365,7 +928,41 @@ pub unsafe fn init(hart_base_pa: u64, machine: &MachineMeta) -> (PageTables, Mem
                                                (pa >> 2) | PTE_AD | PTE_USER | PTE_RWXV);
     }
 
-    (shadow_page_tables, guest_memory, guest_shift)
+    (shadow_page_tables, gstage, guest_memory, guest_shift)
+}
+
+/// Map host physical addresses [start, end) into the direct map of the MPA root with 2 MB pages,
+/// except that any 2 MB page overlapping `fine` is mapped with 4 KB pages instead.
+///
+/// The FU540 doesn't correctly check PMP entries that only cover part of a superpage [1], so no page
+/// may be larger than the PMP entries install_hart_pmp restricts the hart with. Segments are 2 MB
+/// aligned, while the virtio devices PMP tells apart are only 4 KB aligned.
+///
+/// [1] https://github.com/riscv/riscv-isa-manual/issues/347
+unsafe fn map_direct(page_tables: &mut PageTables, start: u64, end: u64, fine: (u64, u64)) {
+    assert_eq!(start % HPAGE_SIZE, 0);
+    let root_pa = page_tables.root_pa(MPA);
+    let mut pa = start;
+    while pa < end {
+        let root_pte_addr = root_pa + DIRECT_MAP_PT_INDEX + (pa >> 30) * 8;
+        if page_tables.region[root_pte_addr] & PTE_VALID == 0 {
+            let page = page_tables.alloc_page();
+            page_tables.region.set_pte_unchecked(root_pte_addr, (page >> 2) | PTE_VALID);
+        }
+        let page_table = (page_tables.region[root_pte_addr] >> 10) << 12;
+        let pte_addr = page_table + ((pa >> 21) & 0x1ff) * 8;
+
+        if pa < fine.1 && pa + HPAGE_SIZE > fine.0 {
+            let page = page_tables.alloc_page();
+            page_tables.region.set_pte_unchecked(pte_addr, (page >> 2) | PTE_VALID);
+            for i in 0..(HPAGE_SIZE / PAGE_SIZE) {
+                page_tables.region.set_pte_unchecked(page + i * 8, ((pa + i * PAGE_SIZE) >> 2) | PTE_AD | PTE_RWV);
+            }
+        } else {
+            page_tables.region.set_pte_unchecked(pte_addr, (pa >> 2) | PTE_AD | PTE_RWV);
+        }
+        pa += HPAGE_SIZE;
+    }
 }
 
 #[allow(unused)]
@@ -385,7 +982,6 @@ pub fn print_page_table(page_table_region: &PageTableRegion, pt: u64, level: u8)
     }
 }
 
-#[allow(unused)]
 pub fn print_guest_page_table(guest_memory: &MemoryRegion, pt: u64, level: u8, base: u64) {
     if !guest_memory.in_region(pt) {
         println!("[SATP Invalid]");
@@ -399,7 +995,7 @@ pub fn print_guest_page_table(guest_memory: &MemoryRegion, pt: u64, level: u8, b
             continue;
         }
 
-        for _ in 0..(2 - level) {
+        for _ in level..3 {
             print!("__ ");
         }
 
@@ -421,37 +1017,8 @@ pub fn print_guest_page_table(guest_memory: &MemoryRegion, pt: u64, level: u8, b
     }
 }
 
+/// Flush the shadow mappings of every guest address space.
 pub fn flush_shadow_page_table(shadow_page_tables: &mut PageTables) {
-    for &root in &[UVA, KVA, MVA] {
-        shadow_page_tables.clear_page_table_range(shadow_page_tables.root_pa(root), 0, DIRECT_MAP_PT_INDEX/8);
-    }
-
+    shadow_page_tables.flush(None);
     riscv::sfence_vma();
 }
-
-#[inline]
-pub fn handle_sfence_vma(state: &mut Context, instruction: RType) {
-    if instruction.rs1() == 0 {
-        flush_shadow_page_table(&mut state.shadow_page_tables);
-    } else {
-        let va = trap::get_register(state, instruction.rs1());
-        if va < DIRECT_MAP_OFFSET {
-            for &root in &[UVA, KVA, MVA] {
-                let pte_addr = state.shadow_page_tables.pte_for_addr(root, va);
-                state.shadow_page_tables.region.set_invalid_pte(pte_addr, 0);
-            }
-            riscv::sfence_vma();
-        }
-    }
-}
-
-pub fn read64(guest_memory: &MemoryRegion, page_table_ppn: u64, guest_va: u64) -> Option<u64> {
-    let guest_page = guest_va & !0xfff;
-    if let Some(page_translation) = translate_guest_address(guest_memory, page_table_ppn << 12, guest_page) {
-        // assert!(!virtio::is_queue_access(state, page_translation.guest_pa));
-        let guest_pa = (page_translation.guest_pa & !0xfff) | (guest_va & 0xfff);
-        return guest_memory.get(guest_pa);
-    }
-
-    None
-}
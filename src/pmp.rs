@@ -2,6 +2,12 @@
 use rvirt::*;
 use crate::machdebug::*;
 
+/// Host physical addresses of the hypervisor text and shared data, which every hart may access.
+pub const TEXT: (u64, u64) = (0x80000000, 0x80200000);
+pub const SHARED_DATA: (u64, u64) = (0x80200000, 0x80400000);
+/// MMIO devices occupy the host physical addresses below main memory.
+pub const MMIO: (u64, u64) = (0, 0x80000000);
+
 pub unsafe fn write_pmp_config(entry: u8, config: u8) {
     machine_debug_assert(entry <= 15, "entry out of range");
     let shift = (entry & 7) * 8;
@@ -100,6 +106,16 @@ pub unsafe fn install_pmp_napot(entry: u8, config: u8, address: u64, size: u64)
     }
 }
 
+// covers [start, end), using the address register of entry - 1 as well. that entry must be off.
+pub unsafe fn install_pmp_tor(entry: u8, config: u8, start: u64, end: u64) {
+    machine_debug_assert(entry > 0, "no entry before the first to hold the start address");
+    if (start & 3) != 0 || (end & 3) != 0 {
+        machine_debug_abort("addresses must be 4-byte aligned");
+    }
+    install_pmp(entry - 1, MODE_OFF, start >> 2);
+    install_pmp(entry, config | MODE_TOR, end >> 2);
+}
+
 // cover everything in memory
 pub unsafe fn install_pmp_allmem(entry: u8, config: u8) {
     // 0xFFFFFFFFFFFFFFFF is reserved as of priv-1.10, but fixed in an unreleased spec, and QEMU
@@ -138,6 +154,22 @@ pub fn decode_pmp_range(config: u8, address: u64, _lastconfig: u8, lastaddress:
     }
 }
 
+/// Restrict a hart to the memory it needs to run a guest: the hypervisor text and shared data
+/// (locked entries 0 and 1, installed by mstart), `memory.segment`, and MMIO other than the virtio
+/// devices of other guests. Entries are matched in order, so the denying entry for all virtio
+/// devices follows the one granting access to the guest's own. Entry 7, which gave supervisor mode
+/// access to all memory while booting, now holds the start of the segment, and later entries are
+/// cleared.
+pub unsafe fn install_hart_pmp(memory: &HartMemory) {
+    install_pmp_tor(3, READ | WRITE, memory.guest_virtio.0, memory.guest_virtio.1);
+    install_pmp_tor(5, 0, memory.virtio.0, memory.virtio.1);
+    install_pmp_napot(6, READ | WRITE, MMIO.0, MMIO.1 - MMIO.0);
+    install_pmp_tor(8, READ | WRITE | EXEC, memory.segment.0, memory.segment.1);
+    for entry in 9..16 {
+        install_pmp(entry, MODE_OFF, 0);
+    }
+}
+
 pub const READ: u8 = 0x1;
 pub const WRITE: u8 = 0x2;
 pub const EXEC: u8 = 0x4;
@@ -148,9 +180,7 @@ const PMP_A_TOR: u8 = 0x1;
 const PMP_A_NA4: u8 = 0x2;
 const PMP_A_NAPOT: u8 = 0x3;
 // for encoding
-#[allow(unused)]
 pub const MODE_OFF: u8 = PMP_A_OFF << PMP_A_SHIFT;
-#[allow(unused)]
 pub const MODE_TOR: u8 = PMP_A_TOR << PMP_A_SHIFT;
 pub const MODE_NA4: u8 = PMP_A_NA4 << PMP_A_SHIFT;
 pub const MODE_NAPOT: u8 = PMP_A_NAPOT << PMP_A_SHIFT;
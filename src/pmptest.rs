@@ -1,79 +1,170 @@
 
 use rvirt::*;
-use crate::pagedebug;
-use crate::pmp;
+use crate::machdebug::*;
+use crate::pmp::{self, READ, WRITE, EXEC};
 
 global_asm!(include_str!("mcode.S"));
 
+// Make a single access from supervisor mode, with paging disabled, and return to the caller in
+// M-mode with the mcause of the trap that ended it. The access is followed by an ecall, so a cause
+// of 9 means it succeeded. Expects a0 = address, a1 = one of the `LOAD`, `STORE` or `FETCH` kinds.
+global_asm!(r#"
+.globl pmp_probe
+.align 4
+pmp_probe:
+	LOAD_ADDRESS t0, pmp_probe_trap
+	csrw 0x305, t0 // mtvec
+	li t0, 0x1800
+	csrc 0x300, t0
+	li t0, 0x800
+	csrs 0x300, t0 // mstatus.mpp = S
+
+	mv t1, a0
+	li t0, 2
+	beq a1, t0, 1f
+	LOAD_ADDRESS t1, pmp_probe_store
+	li t0, 1
+	beq a1, t0, 1f
+	LOAD_ADDRESS t1, pmp_probe_load
+1:	csrw 0x341, t1 // mepc
+	mret
+
+pmp_probe_load:
+	lbu t0, 0(a0)
+	ecall
+pmp_probe_store:
+	lbu t0, 0(a0)
+	sb t0, 0(a0)
+.globl pmp_probe_ecall
+pmp_probe_ecall:
+	ecall
+
+.align 4
+pmp_probe_trap:
+	csrr a0, 0x342 // mcause
+	ret
+"#);
+
+extern "C" {
+    fn pmp_probe(address: u64, kind: u64) -> u64;
+    /// An ecall instruction in the hypervisor text, for instruction fetches to land on.
+    static pmp_probe_ecall: u32;
+}
+
+const LOAD: u64 = 0;
+const STORE: u64 = 1;
+const FETCH: u64 = 2;
+
+const MCAUSE_ECALL_FROM_S: u64 = 9;
+const MCAUSE_INSN_ACCESS_FAULT: u64 = 1;
+const MCAUSE_LOAD_ACCESS_FAULT: u64 = 5;
+const MCAUSE_STORE_ACCESS_FAULT: u64 = 7;
+
+/// Encoding of the ecall instruction.
+const ECALL: u32 = 0x00000073;
+
+/// Set up the PMP configuration of a hart running the second of two guests, and check by making
+/// accesses from supervisor mode that it grants exactly what it should. Expects a QEMU virt machine
+/// with at least two harts and enough memory for two guests.
 #[inline(never)]
 pub unsafe fn pmptest_mstart(hartid: u64, device_tree_blob: u64) {
     if hartid > 0 {
         loop {}
     }
 
-    // Initialize some control registers
-    csrs!(mideleg, 0x0222);
-    csrs!(medeleg, 0xb1ff);
-    csrw!(mie, 0x888);
-    csrs!(mstatus, STATUS_MPP_S);
-    // csrw!(mepc, sstart as u64); -- TODO!!!!!!!!!!!!!!1
-    csrw!(mcounteren, 0xffffffff);
-    csrw!(mscratch, 0x80800000 + 0x1000 * (hartid+1));
-
-    asm!("LOAD_ADDRESS t0, mtrap_entry
-          csrw 0x305, t0 // mtvec"
-         ::: "t0"  : "volatile");
-
-    // Minimal page table to boot into S mode.
-    let boot_page_table_pa = SHARED_STATICS.boot_page_table.as_ptr() as u64;
-    *((boot_page_table_pa) as *mut u64) = 0x00000000 | 0xcf;
-    *((boot_page_table_pa+16) as *mut u64) = 0x20000000 | 0xcf;
-    *((boot_page_table_pa+4088) as *mut u64) = 0x20000000 | 0xcf;
-    csrw!(satp, 8 << 60 | (boot_page_table_pa >> 12));
-
-    // Text segment
-    pmp::install_pmp_napot(0, pmp::LOCK | pmp::READ | pmp::EXEC, 0x80000000, 0x200000);
-    // Shared data segment
-    pmp::install_pmp_napot(1, pmp::LOCK | pmp::READ | pmp::WRITE, 0x80200000, 0x200000);
-
-    // // M-mode stack
-    // csrw!(pmpaddr2, pmpaddr(0x80180000, 1<<19));
-    // csrs!(pmpcfg0, M_ONLY << 16);
-    // csrw!(pmpaddr3, pmpaddr(0x80200000 - (hartid+1) * 64*1024, 32*1024));
-    // csrs!(pmpcfg0, LRW << 24);
-    // csrw!(pmpaddr2, pmpaddr(0x80180000, 1<<19));
-    // csrs!(pmpcfg0, LOCKED << 32);
+    // Take every trap in M-mode, and don't let interrupts get in the way of the probes.
+    csrw!(medeleg, 0);
+    csrw!(mideleg, 0);
+    csrw!(mie, 0);
+    csrw!(satp, 0);
+
+    let machine = Fdt::new(device_tree_blob).parse();
+    let layouts = pmap::layout_guests(&machine, &[1, 1]);
+    machine_debug_assert(layouts.len() == 2, "not enough memory for two guests");
+    let memory = HartMemory::new(&machine, &layouts[1], 2);
+    let other_segment = layouts[0].base;
+    let other_virtio = if memory.guest_virtio.0 > memory.virtio.0 { memory.virtio.0 } else { memory.guest_virtio.1 };
+    let ecall = &pmp_probe_ecall as *const u32 as u64;
+
+    // Fetches from memory that should be executable (and from some that shouldn't be) land on an
+    // ecall. M-mode isn't restricted by the unlocked entries installed below.
+    for &address in &[memory.segment.0, memory.segment.1 - 4, other_segment, pmp::SHARED_DATA.1 - 4] {
+        *(address as *mut u32) = ECALL;
+    }
+    asm!("fence.i" :::: "volatile");
+
+    // Same entries as mstart.
+    pmp::install_pmp_napot(0, pmp::LOCK | READ | EXEC, pmp::TEXT.0, pmp::TEXT.1 - pmp::TEXT.0);
+    pmp::install_pmp_napot(1, pmp::LOCK | READ | WRITE, pmp::SHARED_DATA.0, pmp::SHARED_DATA.1 - pmp::SHARED_DATA.0);
+    pmp::install_pmp_allmem(7, READ | WRITE | EXEC);
+    let mut failures = check("boot", &[
+        ("write text", pmp::TEXT.0, STORE, false),
+        ("hart 0 data", pmp::SHARED_DATA.1, LOAD, true),
+        ("read guest memory", memory.segment.0, LOAD, true),
+        ("write guest memory", memory.segment.0, STORE, true),
+        ("execute guest memory", memory.segment.0, FETCH, true),
+    ]);
+
+    // Same as handle_ipi.
+    pmp::install_hart_pmp(&memory);
+    failures += check("hart", &[
+        ("read text", pmp::TEXT.0, LOAD, true),
+        ("execute text", ecall, FETCH, true),
+        ("write text", pmp::TEXT.0, STORE, false),
+        ("write shared data", pmp::SHARED_DATA.1 - 4, STORE, true),
+        ("execute shared data", pmp::SHARED_DATA.1 - 4, FETCH, false),
+        ("hart 0 data", pmp::SHARED_DATA.1, LOAD, false),
+        ("memory below segment", memory.segment.0 - 4, LOAD, false),
+        ("read segment start", memory.segment.0, LOAD, true),
+        ("write segment start", memory.segment.0, STORE, true),
+        ("execute segment start", memory.segment.0, FETCH, true),
+        ("read segment end", memory.segment.1 - 4, LOAD, true),
+        ("write segment end", memory.segment.1 - 4, STORE, true),
+        ("execute segment end", memory.segment.1 - 4, FETCH, true),
+        ("read other segment", other_segment, LOAD, false),
+        ("execute other segment", other_segment, FETCH, false),
+        ("CLINT", machine.clint_address, STORE, true),
+        ("PLIC", machine.plic_address, STORE, true),
+        ("UART scratch register", machine.uart_address + 7, STORE, true),
+        ("execute MMIO", machine.uart_address, FETCH, false),
+        ("guest virtio device", memory.guest_virtio.0, STORE, true),
+        ("last guest virtio device", memory.guest_virtio.1 - 4, STORE, true),
+        ("read other virtio device", other_virtio, LOAD, false),
+        ("write other virtio device", other_virtio, STORE, false),
+    ]);
 
     pmp::debug_pmp();
-    pagedebug::debug_paging();
-
-    asm!("mv a0, $0
-          mret" :: "r"(device_tree_blob) : "a0", "a1" : "volatile");
+    if failures == 0 {
+        machine_debug_puts("PMP tests passed\n");
+    } else {
+        machine_debug_putint(failures);
+        machine_debug_puts(" PMP tests failed\n");
+    }
+    loop {}
 }
 
-// unsafe fn sstart(device_tree_blob: u64) {
-//     asm!("li t0, $0
-//           add sp, sp, t0" :: "i"(SYMBOL_PA2VA_OFFSET)  : "t0" : "volatile");
-//     csrw!(stvec, (||{panic!("Trap on hart 0?!")}) as fn() as *const () as u64);
-
-//     // Read and process host FDT.
-//     let fdt = Fdt::new(device_tree_blob);
-//     assert!(fdt.magic_valid());
-//     assert!(fdt.version() >= 17 && fdt.last_comp_version() <= 17);
-//     assert!(fdt.total_size() < 64 * 1024);
-//     let machine = fdt.parse();
-
-//     // Initialize UART
-//     if let Some(ty) = machine.uart_type {
-//         print::UART_WRITER.lock().init(machine.uart_address, ty);
-//     }
-
-//     println!("ONLINE");
-
-//     loop {}
-// }
-
-// #[allow(unused)]
-// unsafe fn ustart() {
-
-// }
+/// Make each of `accesses` from supervisor mode, and return how many of them were (or weren't)
+/// stopped by an access fault contrary to what was expected.
+unsafe fn check(name: &str, accesses: &[(&str, u64, u64, bool)]) -> u64 {
+    let mut failures = 0;
+    for &(access, address, kind, allowed) in accesses {
+        let cause = pmp_probe(address, kind);
+        let faulted = match cause {
+            MCAUSE_INSN_ACCESS_FAULT | MCAUSE_LOAD_ACCESS_FAULT | MCAUSE_STORE_ACCESS_FAULT => true,
+            _ => false,
+        };
+        if faulted == allowed || (!faulted && cause != MCAUSE_ECALL_FROM_S) {
+            machine_debug_puts("FAIL ");
+            machine_debug_puts(name);
+            machine_debug_puts(": ");
+            machine_debug_puts(access);
+            machine_debug_puts(" at ");
+            machine_debug_puthex64(address);
+            machine_debug_puts(" (mcause ");
+            machine_debug_putint(cause);
+            machine_debug_puts(if allowed { ") should be allowed\n" } else { ") should be denied\n" });
+            failures += 1;
+        }
+    }
+    failures
+}
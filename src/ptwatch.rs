@@ -0,0 +1,395 @@
+//! Write protection of guest page tables.
+//!
+//! Shadow page tables are derived from the guest's own page tables, so they go stale whenever the
+//! guest changes those. Rather than throwing every shadow mapping away each time the guest fences,
+//! the hypervisor keeps track of which guest pages hold page tables that shadow mappings were
+//! derived from, and keeps those pages read-only in the shadow page tables of every vCPU. Guest
+//! writes to them then trap, are emulated, and invalidate only the shadow mappings that depended on
+//! the entry written.
+//!
+//! Shadow page tables are private to each vCPU, so writes are also logged for the other vCPUs to
+//! apply the next time the guest fences them. Pages stay tracked until the table of them fills up
+//! (or a write to one can't be emulated), at which point every vCPU flushes its shadow page tables
+//! and tracking starts over.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::context::{Context, REQUEST_FLUSH_PAGE_TABLES, REQUEST_WRITE_PROTECT};
+use crate::error::HypervisorError;
+use crate::memory_region::MemoryRegion;
+use crate::mmio::{self, MmioDevice};
+use crate::pmap::{self, PageTables, PTE_EXECUTE, PTE_READ, PTE_VALID};
+use crate::trap::constants::SATP_PPN;
+use crate::{riscv, trap};
+
+/// Number of slots in the table of tracked pages.
+const MAX_PAGES: usize = 1024;
+/// Tracking starts over once this many pages are tracked, to keep probe sequences short.
+const MAX_TRACKED: usize = MAX_PAGES * 3 / 4;
+/// Number of distinct uses recorded for each page. Page tables of the kernel are reachable from the
+/// root page table of every process, but at the same virtual addresses each time.
+const MAX_USES: usize = 4;
+/// Number of entries kept in the logs of writes and of newly tracked pages. vCPUs that fall
+/// further behind than this flush their shadow page tables instead.
+const LOG_SIZE: u64 = 64;
+
+// A use of a page is recorded as the base of the virtual address range it maps, ORed with the log2
+// of the size mapped by each of its entries and whether it is a root page table. Root page tables
+// are recorded with a base of zero, since they map both halves of the address space.
+const USE_SHIFT: u64 = 0x3f;
+const USE_ROOT: u64 = 0x40;
+
+#[derive(Copy, Clone)]
+struct TrackedPage {
+    /// Guest physical address of the page, or zero if the slot is unused.
+    page: u64,
+    /// Uses of the page that shadow mappings were derived from. Unused entries are zero.
+    uses: [u64; MAX_USES],
+    /// Whether the page had more uses than could be recorded.
+    overflowed: bool,
+}
+impl TrackedPage {
+    const UNUSED: Self = Self { page: 0, uses: [0; MAX_USES], overflowed: false };
+}
+
+/// Guest pages known to hold page tables, shared by all vCPUs of a guest.
+pub struct GuestPageTables {
+    pages: [TrackedPage; MAX_PAGES],
+    tracked: usize,
+
+    /// Guest physical addresses of the most recently written page table entries, and the number of
+    /// writes so far.
+    writes: [u64; LOG_SIZE as usize],
+    write_count: u64,
+    /// Most recently tracked pages, and the number of pages tracked so far.
+    new_pages: [u64; LOG_SIZE as usize],
+    new_page_count: u64,
+}
+
+/// How far through the logs of its guest's `GuestPageTables` a vCPU has caught up.
+#[derive(Default)]
+pub struct LogPosition {
+    writes: u64,
+    new_pages: u64,
+}
+
+impl GuestPageTables {
+    pub fn new() -> Self {
+        Self {
+            pages: [TrackedPage::UNUSED; MAX_PAGES],
+            tracked: 0,
+            writes: [0; LOG_SIZE as usize],
+            write_count: 0,
+            new_pages: [0; LOG_SIZE as usize],
+            new_page_count: 0,
+        }
+    }
+
+    /// Stop tracking every page. The logs are kept, since vCPUs keep their positions in them.
+    pub fn clear(&mut self) {
+        self.pages = [TrackedPage::UNUSED; MAX_PAGES];
+        self.tracked = 0;
+    }
+
+    /// Index of the slot holding `page`, or of the unused slot it would go in.
+    fn slot(&self, page: u64) -> usize {
+        let mut index = (page >> 12) as usize % MAX_PAGES;
+        while self.pages[index].page != 0 && self.pages[index].page != page {
+            index = (index + 1) % MAX_PAGES;
+        }
+        index
+    }
+
+    fn get(&self, page: u64) -> Option<&TrackedPage> {
+        let slot = &self.pages[self.slot(page)];
+        if slot.page == page { Some(slot) } else { None }
+    }
+
+    /// Record that `page` is used as described by `usage`. Returns whether the page is newly
+    /// tracked, or an error if the table is full.
+    fn track(&mut self, page: u64, usage: u64) -> Result<bool, ()> {
+        let index = self.slot(page);
+        let slot = &mut self.pages[index];
+        if slot.page == page {
+            if !slot.uses.contains(&usage) {
+                match slot.uses.iter().position(|&u| u == 0) {
+                    Some(i) => slot.uses[i] = usage,
+                    None => slot.overflowed = true,
+                }
+            }
+            return Ok(false);
+        }
+
+        if self.tracked == MAX_TRACKED {
+            return Err(());
+        }
+        let mut uses = [0; MAX_USES];
+        uses[0] = usage;
+        *slot = TrackedPage { page, uses, overflowed: false };
+        self.tracked += 1;
+
+        self.new_pages[(self.new_page_count % LOG_SIZE) as usize] = page;
+        self.new_page_count += 1;
+        Ok(true)
+    }
+
+    fn log_write(&mut self, pte_pa: u64) {
+        self.writes[(self.write_count % LOG_SIZE) as usize] = pte_pa;
+        self.write_count += 1;
+    }
+
+    /// Remove the shadow mappings derived from the guest page table entry at `pte_pa`.
+    fn invalidate(&self, shadow_page_tables: &mut PageTables, pte_pa: u64) {
+        let page = match self.get(pte_pa & !0xfff) {
+            Some(page) => page,
+            None => return,
+        };
+        if page.overflowed {
+            shadow_page_tables.flush(None);
+            return;
+        }
+
+        let index = (pte_pa & 0xfff) / 8;
+        for &usage in page.uses.iter().filter(|&&u| u != 0) {
+            let shift = usage & USE_SHIFT;
+            let mut va = (usage & !0xfff) + (index << shift);
+            if usage & USE_ROOT != 0 {
+                let unused = 64 - (shift + 9);
+                va = (((va << unused) as i64) >> unused) as u64;
+            }
+            shadow_page_tables.invalidate(va, shift);
+        }
+    }
+
+    /// Track the guest page tables walked to translate `va`. Returns whether any of them were newly
+    /// tracked, or an error if the table filled up.
+    fn track_walk(&mut self, guest_memory: &MemoryRegion, satp: u64, levels: u64, va: u64) -> Result<bool, ()> {
+        let mut new = false;
+        let mut page_table = (satp & SATP_PPN) << 12;
+        for level in 0..levels {
+            if !guest_memory.in_region(page_table) {
+                break;
+            }
+
+            let shift = 12 + 9 * (levels - 1 - level);
+            let usage = match level {
+                0 => USE_ROOT | shift,
+                _ => (va & !((1 << (shift + 9)) - 1)) | shift,
+            };
+            new |= self.track(page_table, usage)?;
+
+            let pte = guest_memory[page_table + ((va >> shift) & 0x1ff) * 8];
+            if pte & PTE_VALID == 0 || pte & (PTE_READ | PTE_EXECUTE) != 0 {
+                break;
+            }
+            page_table = (pte >> 10) << 12;
+        }
+        Ok(new)
+    }
+}
+
+/// Track the guest page tables that translating `va` goes through, so that the shadow mapping about
+/// to be created from that translation is invalidated when they change.
+pub fn track_walk(state: &mut Context, va: u64) {
+    let levels = match pmap::paging_levels(state.csrs.satp) {
+        Some(levels) => levels,
+        None => return,
+    };
+
+    loop {
+        let shared = state.shared;
+        let mut tables = shared.page_tables.lock();
+        match tables.track_walk(&state.guest_memory, state.csrs.satp, levels, va) {
+            Ok(new) => {
+                let behind = tables.new_page_count != state.page_table_log.new_pages;
+                drop(tables);
+
+                // Pages may also have been tracked by other vCPUs without this one having been
+                // asked to write-protect them yet.
+                if behind {
+                    protect_new(state);
+                }
+                if new {
+                    state.remote_request(!0, REQUEST_WRITE_PROTECT);
+                }
+                return;
+            }
+            Err(()) => {
+                drop(tables);
+                untrack_all(state);
+            }
+        }
+    }
+}
+
+/// Whether the guest page at `guest_pa` holds tracked page tables, and so may only be mapped
+/// read-only.
+pub fn is_tracked(state: &Context, guest_pa: u64) -> bool {
+    state.shared.page_tables.lock().get(guest_pa & !0xfff).is_some()
+}
+
+/// Whether any page in the guest physical address range `[start, end)` holds tracked page tables.
+pub fn has_tracked_in(state: &Context, start: u64, end: u64) -> bool {
+    state.shared.page_tables.lock().pages.iter().any(|p| p.page >= start && p.page < end)
+}
+
+/// Emulate a write by `instruction` to `guest_pa` in a tracked page, and invalidate the shadow
+/// mappings derived from the entry written. If the write can't be emulated, tracking starts over
+/// instead and the guest retries the write.
+pub fn handle_write(state: &mut Context, guest_pa: u64, instruction: Option<u32>) {
+    let result = match instruction {
+        Some(instruction) if instruction & 0x7f == 0x2f => emulate_amo(state, guest_pa, instruction),
+        Some(instruction) => mmio::emulate(state, &mut PageTableAccess, guest_pa, instruction),
+        None => Err(HypervisorError::UnsupportedInstruction),
+    };
+
+    match result {
+        Ok(()) => {
+            let shared = state.shared;
+            let mut tables = shared.page_tables.lock();
+            tables.log_write(guest_pa & !0x7);
+            tables.invalidate(&mut state.shadow_page_tables, guest_pa & !0x7);
+            riscv::sfence_vma();
+            shared.dirty_log.mark(guest_pa, 1);
+        }
+        Err(_) => untrack_all(state),
+    }
+}
+
+/// Invalidate the shadow mappings derived from entries of tracked page tables among the `len` bytes
+/// at `guest_pa`, which the hypervisor has just written on the guest's behalf.
+pub fn handle_hypervisor_write(state: &mut Context, guest_pa: u64, len: u64) {
+    let shared = state.shared;
+    let mut tables = shared.page_tables.lock();
+    let mut invalidated = false;
+    let mut pte_pa = guest_pa & !0x7;
+    while pte_pa < guest_pa + len {
+        if tables.get(pte_pa & !0xfff).is_some() {
+            tables.log_write(pte_pa);
+            tables.invalidate(&mut state.shadow_page_tables, pte_pa);
+            invalidated = true;
+        }
+        pte_pa += 8;
+    }
+    if invalidated {
+        riscv::sfence_vma();
+    }
+}
+
+/// Apply the page table writes other vCPUs have made since this one last caught up, as required
+/// when the guest executes sfence.vma. Writes made by this vCPU were applied as they happened, so
+/// neither the address nor the address space being fenced matter.
+pub fn catch_up(state: &mut Context) {
+    let shared = state.shared;
+    let tables = shared.page_tables.lock();
+    let seen = state.page_table_log.writes;
+    if tables.write_count - seen > LOG_SIZE {
+        state.shadow_page_tables.flush(None);
+    } else {
+        for i in seen..tables.write_count {
+            tables.invalidate(&mut state.shadow_page_tables, tables.writes[(i % LOG_SIZE) as usize]);
+        }
+    }
+    state.page_table_log.writes = tables.write_count;
+    riscv::sfence_vma();
+}
+
+/// Write-protect the pages tracked since this vCPU last checked.
+pub fn protect_new(state: &mut Context) {
+    let shared = state.shared;
+    let tables = shared.page_tables.lock();
+    let seen = state.page_table_log.new_pages;
+    if tables.new_page_count - seen > LOG_SIZE {
+        state.shadow_page_tables.flush(None);
+    } else {
+        for i in seen..tables.new_page_count {
+            let page = tables.new_pages[(i % LOG_SIZE) as usize];
+            state.shadow_page_tables.write_protect(page + state.guest_shift);
+        }
+    }
+    state.page_table_log.new_pages = tables.new_page_count;
+    riscv::sfence_vma();
+}
+
+/// Stop tracking every page. All vCPUs flush their shadow page tables, since they may depend on
+/// pages that are no longer write-protected.
+fn untrack_all(state: &mut Context) {
+    state.shared.page_tables.lock().clear();
+    pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
+    state.remote_request(!0, REQUEST_FLUSH_PAGE_TABLES);
+}
+
+/// Atomically replace the `width` bytes of guest memory at `guest_pa` with `f` of their current
+/// value, and return that value. Both are sign extended to 64 bits. Other vCPUs may be emulating
+/// writes to the same page table at the same time.
+fn update<F: Fn(u64) -> u64>(state: &mut Context, guest_pa: u64, width: u64, f: F) -> u64 {
+    let offset = 8 * (guest_pa % 8);
+    let shift = 64 - 8 * width;
+    let mask = (!0u64 >> shift) << offset;
+    let word = unsafe { &*(&mut state.guest_memory[guest_pa & !0x7] as *mut u64 as *const AtomicU64) };
+
+    let mut current = word.load(Ordering::SeqCst);
+    loop {
+        let old = (((((current & mask) >> offset) << shift) as i64) >> shift) as u64;
+        let new = (current & !mask) | ((f(old) << offset) & mask);
+        let previous = word.compare_and_swap(current, new, Ordering::SeqCst);
+        if previous == current {
+            return old;
+        }
+        current = previous;
+    }
+}
+
+/// Emulate an AMO instruction. LR and SC can't be emulated, since the reservation is lost.
+fn emulate_amo(state: &mut Context, guest_pa: u64, instruction: u32) -> Result<(), HypervisorError> {
+    let width = match (instruction >> 12) & 0x7 {
+        2 => 4,
+        3 => 8,
+        _ => return Err(HypervisorError::UnsupportedInstruction),
+    };
+    let funct5 = instruction >> 27;
+    match funct5 {
+        0x00 | 0x01 | 0x04 | 0x08 | 0x0c | 0x10 | 0x14 | 0x18 | 0x1c => {}
+        _ => return Err(HypervisorError::UnsupportedInstruction),
+    }
+    if guest_pa % width != 0 {
+        return Err(HypervisorError::UnsupportedAccess);
+    }
+
+    let shift = 64 - 8 * width;
+    let src = ((trap::get_register(state, (instruction >> 20) & 0x1f) << shift) as i64 >> shift) as u64;
+    let unsigned = |value: u64| value & (!0u64 >> shift);
+    let old = update(state, guest_pa, width, |value| match funct5 {
+        0x00 => value.wrapping_add(src),
+        0x01 => src,
+        0x04 => value ^ src,
+        0x08 => value | src,
+        0x0c => value & src,
+        0x10 => if (value as i64) < (src as i64) { value } else { src },
+        0x14 => if (value as i64) > (src as i64) { value } else { src },
+        0x18 => if unsigned(value) < unsigned(src) { value } else { src },
+        _ => if unsigned(value) > unsigned(src) { value } else { src },
+    });
+
+    trap::set_register(state, (instruction >> 7) & 0x1f, old);
+    riscv::set_sepc(csrr!(sepc) + 4);
+    Ok(())
+}
+
+/// Loads and stores to tracked page tables, which are carried out on guest memory.
+struct PageTableAccess;
+impl MmioDevice for PageTableAccess {
+    fn read(&mut self, state: &mut Context, guest_pa: u64, width: u64) -> Result<u64, HypervisorError> {
+        if guest_pa % 8 + width > 8 {
+            return Err(HypervisorError::UnsupportedAccess);
+        }
+        Ok(state.guest_memory[guest_pa & !0x7] >> (8 * (guest_pa % 8)))
+    }
+
+    fn write(&mut self, state: &mut Context, guest_pa: u64, width: u64, value: u64) -> Result<(), HypervisorError> {
+        if guest_pa % 8 + width > 8 {
+            return Err(HypervisorError::UnsupportedAccess);
+        }
+        update(state, guest_pa, width, |_| value);
+        Ok(())
+    }
+}
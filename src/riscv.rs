@@ -1,5 +1,4 @@
 
-use crate::trap::constants::STATUS_FS;
 
 /** atomic read from CSR */
 #[macro_export]
@@ -95,6 +94,17 @@ pub fn fence_i() {
     unsafe { asm!("fence.i" :::: "volatile") }
 }
 
+/// Flush all G-stage address translations. The instructions of the hypervisor extension are
+/// encoded by hand since the assembler doesn't know about them.
+pub fn hfence_gvma() {
+    unsafe { asm!(".word 0x62000073" ::: "memory" : "volatile") }
+}
+
+/// Flush all VS-stage address translations of the current guest.
+pub fn hfence_vvma() {
+    unsafe { asm!(".word 0x22000073" ::: "memory" : "volatile") }
+}
+
 pub fn wfi() {
     unsafe { asm!("wfi" :::: "volatile") }
 }
@@ -117,9 +127,3 @@ pub fn set_sscratch(value: u64) {
 pub fn clear_sip(mask: u64) {
     unsafe { csrc!(sip, mask) }
 }
-
-/// Set the FS bits of `sstatus`. This is safe because rvirt does not use hardware floating point
-/// support.
-pub fn set_sstatus_fs(new: u64) {
-    unsafe { csrw!(sstatus, (new & STATUS_FS) | (csrr!(sstatus) & !STATUS_FS)) }
-}
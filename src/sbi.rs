@@ -0,0 +1,416 @@
+//! Emulation of the Supervisor Binary Interface (SBI) for guests.
+//!
+//! Both the legacy (v0.1) calling convention, where the function is selected by `a7` alone, and the
+//! extension based convention introduced in v0.2 are supported. In the latter, `a7` holds the
+//! extension ID (EID), `a6` the function ID (FID) and results are returned as an `sbiret` pair with
+//! the error code in `a0` and the value in `a1`.
+
+use crate::config::ResetPolicy;
+use crate::context::{Context, REQUEST_FENCE_I, REQUEST_IPI, REQUEST_SFENCE_VMA, REQUEST_STOP};
+use crate::guest_memory::GuestMemory;
+use crate::stats::ExitReason;
+use crate::trap::constants::*;
+use crate::trap::{self, U64Bits};
+use crate::{ptwatch, riscv};
+use core::sync::atomic::{spin_loop_hint, Ordering};
+
+#[allow(unused)]
+pub mod constants {
+    pub const LEGACY_SET_TIMER: u64 = 0x00;
+    pub const LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+    pub const LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+    pub const LEGACY_CLEAR_IPI: u64 = 0x03;
+    pub const LEGACY_SEND_IPI: u64 = 0x04;
+    pub const LEGACY_REMOTE_FENCE_I: u64 = 0x05;
+    pub const LEGACY_REMOTE_SFENCE_VMA: u64 = 0x06;
+    pub const LEGACY_REMOTE_SFENCE_VMA_ASID: u64 = 0x07;
+    pub const LEGACY_SHUTDOWN: u64 = 0x08;
+
+    pub const EXT_BASE: u64 = 0x10;
+    pub const EXT_TIME: u64 = 0x54494d45;
+    pub const EXT_IPI: u64 = 0x735049;
+    pub const EXT_RFENCE: u64 = 0x52464e43;
+    pub const EXT_HSM: u64 = 0x48534d;
+    pub const EXT_SRST: u64 = 0x53525354;
+
+    /// Vendor specific extension for rvirt. Vendor extension IDs are 0x09000000 plus the vendor ID
+    /// returned by `BASE_GET_MVENDORID`, which is always 0 for guests.
+    pub const EXT_VENDOR_RVIRT: u64 = 0x09000000;
+
+    pub const BASE_GET_SPEC_VERSION: u64 = 0;
+    pub const BASE_GET_IMPL_ID: u64 = 1;
+    pub const BASE_GET_IMPL_VERSION: u64 = 2;
+    pub const BASE_PROBE_EXTENSION: u64 = 3;
+    pub const BASE_GET_MVENDORID: u64 = 4;
+    pub const BASE_GET_MARCHID: u64 = 5;
+    pub const BASE_GET_MIMPID: u64 = 6;
+
+    pub const TIME_SET_TIMER: u64 = 0;
+
+    pub const IPI_SEND_IPI: u64 = 0;
+
+    pub const RFENCE_REMOTE_FENCE_I: u64 = 0;
+    pub const RFENCE_REMOTE_SFENCE_VMA: u64 = 1;
+    pub const RFENCE_REMOTE_SFENCE_VMA_ASID: u64 = 2;
+
+    pub const HSM_HART_START: u64 = 0;
+    pub const HSM_HART_STOP: u64 = 1;
+    pub const HSM_HART_GET_STATUS: u64 = 2;
+
+    pub const HSM_STATUS_STARTED: u64 = 0;
+    pub const HSM_STATUS_STOPPED: u64 = 1;
+    pub const HSM_STATUS_START_PENDING: u64 = 2;
+    pub const HSM_STATUS_STOP_PENDING: u64 = 3;
+
+    pub const SRST_SYSTEM_RESET: u64 = 0;
+
+    pub const SRST_TYPE_SHUTDOWN: u64 = 0;
+    pub const SRST_TYPE_COLD_REBOOT: u64 = 1;
+    pub const SRST_TYPE_WARM_REBOOT: u64 = 2;
+
+    pub const RVIRT_PRINT_EXIT_STATS: u64 = 0;
+    pub const RVIRT_RESET_EXIT_STATS: u64 = 1;
+
+    pub const SBI_SUCCESS: i64 = 0;
+    pub const SBI_ERR_FAILED: i64 = -1;
+    pub const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+    pub const SBI_ERR_INVALID_PARAM: i64 = -3;
+    pub const SBI_ERR_DENIED: i64 = -4;
+    pub const SBI_ERR_INVALID_ADDRESS: i64 = -5;
+    pub const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+    /// Version of the SBI specification implemented: v0.3 (major in bits 24..31, minor in 0..24).
+    pub const SPEC_VERSION: u64 = 0 << 24 | 3;
+
+    /// Implementation ID reported to guests. IDs 0 through 5 are allocated to other SBI
+    /// implementations, so pick a value well clear of them.
+    pub const IMPL_ID: u64 = 0x7276;
+    pub const IMPL_VERSION: u64 = 1;
+}
+use self::constants::*;
+
+/// Result of an SBI call: either a value to return in `a1`, or an error code for `a0`.
+pub type SbiResult = Result<u64, i64>;
+
+/// How to account for the environment call the guest kernel is making.
+pub fn exit_reason(state: &mut Context) -> ExitReason {
+    let eid = trap::get_register(state, 17);
+    // Legacy calls ignore a6, so it may hold anything.
+    ExitReason::Sbi(eid, if eid < EXT_BASE { 0 } else { trap::get_register(state, 16) })
+}
+
+/// Handle an environment call from the guest kernel. Arguments are taken from the guest's registers
+/// and results are written back to them. The caller must already have advanced `sepc` past the
+/// `ecall` instruction, since some calls resume the guest somewhere else entirely.
+pub fn handle_ecall(state: &mut Context) {
+    let eid = trap::get_register(state, 17);
+    let fid = trap::get_register(state, 16);
+    let args = [
+        trap::get_register(state, 10),
+        trap::get_register(state, 11),
+        trap::get_register(state, 12),
+        trap::get_register(state, 13),
+    ];
+
+    // Successful calls to these functions don't return to the caller. If the hart is ever started
+    // again, its registers have been set up by `Context::reset` and must not be overwritten.
+    let result = match (eid, fid) {
+        (LEGACY_SHUTDOWN, _) => Some(system_reset(state, SRST_TYPE_SHUTDOWN)),
+        (EXT_HSM, HSM_HART_STOP) => Some(hart_stop(state)),
+        (EXT_SRST, SRST_SYSTEM_RESET) => Some(system_reset(state, args[0])),
+        _ => None,
+    };
+    if let Some(result) = result {
+        if let Err(error) = result {
+            trap::set_register(state, 10, error as u64);
+        }
+        return;
+    }
+
+    if eid < EXT_BASE {
+        let ret = handle_legacy(state, eid, args);
+        trap::set_register(state, 10, ret as u64);
+        return;
+    }
+
+    let result = match eid {
+        EXT_BASE => handle_base(fid, args),
+        EXT_TIME => handle_time(state, fid, args),
+        EXT_IPI => handle_ipi(state, fid, args),
+        EXT_RFENCE => handle_rfence(state, fid, args),
+        EXT_HSM => handle_hsm(state, fid, args),
+        EXT_SRST => Err(SBI_ERR_NOT_SUPPORTED),
+        EXT_VENDOR_RVIRT => handle_vendor(state, fid),
+        _ => {
+            println!("Guest made unsupported SBI call (eid={:#x}, fid={})", eid, fid);
+            Err(SBI_ERR_NOT_SUPPORTED)
+        }
+    };
+
+    match result {
+        Ok(value) => {
+            trap::set_register(state, 10, SBI_SUCCESS as u64);
+            trap::set_register(state, 11, value);
+        }
+        Err(error) => trap::set_register(state, 10, error as u64),
+    }
+}
+
+/// Legacy calls return a single value in `a0`.
+fn handle_legacy(state: &mut Context, eid: u64, args: [u64; 4]) -> i64 {
+    match eid {
+        LEGACY_SET_TIMER => set_timer(state, args[0]),
+        LEGACY_CONSOLE_PUTCHAR => state.shared.uart.lock().output_byte(args[0] as u8),
+        LEGACY_CONSOLE_GETCHAR => {
+            let mut uart = state.shared.uart.lock();
+            uart.fill_fifo();
+            return uart.input_byte().map(|b| b as i64).unwrap_or(-1);
+        }
+        LEGACY_CLEAR_IPI => state.csrs.sip.set(IP_SSIP, false),
+        LEGACY_SEND_IPI => {
+            let mask = match read_legacy_hart_mask(state, args[0]) {
+                Ok(mask) => mask,
+                Err(e) => return e,
+            };
+            send_ipi(state, mask);
+        }
+        LEGACY_REMOTE_FENCE_I => {
+            let mask = match read_legacy_hart_mask(state, args[0]) {
+                Ok(mask) => mask,
+                Err(e) => return e,
+            };
+            remote_fence(state, mask, REQUEST_FENCE_I);
+        }
+        LEGACY_REMOTE_SFENCE_VMA | LEGACY_REMOTE_SFENCE_VMA_ASID => {
+            // Current versions of the Linux kernel pass wrong arguments to these SBI calls. As
+            // a result, this function ignores the arguments and just does a global fence on every
+            // hart. This will eventually be fixed by https://patchwork.kernel.org/patch/10872353.
+            remote_fence(state, !0, REQUEST_SFENCE_VMA);
+        }
+        _ => {
+            println!("Guest made unsupported legacy SBI call (function={})", eid);
+            return SBI_ERR_NOT_SUPPORTED;
+        }
+    }
+    0
+}
+
+fn handle_base(fid: u64, args: [u64; 4]) -> SbiResult {
+    match fid {
+        BASE_GET_SPEC_VERSION => Ok(SPEC_VERSION),
+        BASE_GET_IMPL_ID => Ok(IMPL_ID),
+        BASE_GET_IMPL_VERSION => Ok(IMPL_VERSION),
+        BASE_PROBE_EXTENSION => Ok(match args[0] {
+            LEGACY_SET_TIMER..=LEGACY_SHUTDOWN => 1,
+            EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST => 1,
+            EXT_VENDOR_RVIRT => 1,
+            _ => 0,
+        }),
+        // The machine-level ID registers of the host are not exposed to guests.
+        BASE_GET_MVENDORID | BASE_GET_MARCHID | BASE_GET_MIMPID => Ok(0),
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Exit statistics are kept separately by each vCPU, so these only act on the calling vCPU.
+fn handle_vendor(state: &mut Context, fid: u64) -> SbiResult {
+    match fid {
+        RVIRT_PRINT_EXIT_STATS => state.stats.print(state.vcpu, state.shadow_page_tables.occupancy()),
+        RVIRT_RESET_EXIT_STATS => state.stats.reset(),
+        _ => return Err(SBI_ERR_NOT_SUPPORTED),
+    }
+    Ok(0)
+}
+
+fn handle_time(state: &mut Context, fid: u64, args: [u64; 4]) -> SbiResult {
+    match fid {
+        TIME_SET_TIMER => {
+            set_timer(state, args[0]);
+            Ok(0)
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn handle_ipi(state: &mut Context, fid: u64, args: [u64; 4]) -> SbiResult {
+    match fid {
+        IPI_SEND_IPI => {
+            let mask = hart_mask(state, args[0], args[1])?;
+            send_ipi(state, mask);
+            Ok(0)
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn handle_rfence(state: &mut Context, fid: u64, args: [u64; 4]) -> SbiResult {
+    let mask = hart_mask(state, args[0], args[1])?;
+    match fid {
+        RFENCE_REMOTE_FENCE_I => remote_fence(state, mask, REQUEST_FENCE_I),
+        RFENCE_REMOTE_SFENCE_VMA |
+        RFENCE_REMOTE_SFENCE_VMA_ASID => remote_fence(state, mask, REQUEST_SFENCE_VMA),
+        _ => return Err(SBI_ERR_NOT_SUPPORTED),
+    }
+    Ok(0)
+}
+
+fn handle_hsm(state: &mut Context, fid: u64, args: [u64; 4]) -> SbiResult {
+    let hartid = args[0];
+    if hartid >= num_harts(state) && (fid == HSM_HART_START || fid == HSM_HART_GET_STATUS) {
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+
+    match fid {
+        HSM_HART_START => hart_start(state, hartid as usize, args[1], args[2]),
+        HSM_HART_GET_STATUS => Ok(state.shared.vcpus[hartid as usize].status.load(Ordering::SeqCst)),
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn hart_start(state: &mut Context, vcpu: usize, start_addr: u64, opaque: u64) -> SbiResult {
+    if !state.guest_memory.in_region(start_addr) {
+        return Err(SBI_ERR_INVALID_ADDRESS);
+    }
+
+    if !state.shared.start_vcpu(vcpu, start_addr, opaque) {
+        return Err(SBI_ERR_ALREADY_AVAILABLE);
+    }
+    Ok(0)
+}
+
+/// Stop the calling hart, and once it is started again reset it to run from the new start address.
+fn hart_stop(state: &mut Context) -> Result<(), i64> {
+    // The first vCPU's host hart is the one that receives the guest's device interrupts, so it has
+    // to keep running.
+    if state.vcpu == 0 {
+        return Err(SBI_ERR_FAILED);
+    }
+
+    state.park();
+    Ok(())
+}
+
+fn set_timer(state: &mut Context, value: u64) {
+    state.csrs.sip.set(IP_STIP, false);
+    state.csrs.mtimecmp = state.host_time(value);
+    state.host_clint.set_mtimecmp(state.csrs.mtimecmp);
+}
+
+fn send_ipi(state: &mut Context, mask: u64) {
+    if mask & (1 << state.vcpu) != 0 {
+        state.csrs.sip.set(IP_SSIP, true);
+        state.no_interrupt = false;
+    }
+    state.remote_request(mask, REQUEST_IPI);
+}
+
+/// Execute a fence on every guest hart in `mask`. Shadow page tables are kept in sync with the
+/// guest page table writes they depend on (see ptwatch.rs), so the range being fenced doesn't
+/// matter.
+fn remote_fence(state: &mut Context, mask: u64, request: u64) {
+    if mask & (1 << state.vcpu) != 0 {
+        match request {
+            REQUEST_FENCE_I => riscv::fence_i(),
+            _ if state.gstage.is_some() => riscv::hfence_vvma(),
+            _ => ptwatch::catch_up(state),
+        }
+    }
+    state.remote_request(mask, request);
+}
+
+/// Shut down or reboot the guest, as decided by its reset policy. Every vCPU is stopped and the
+/// guest's devices are reset. On reboot, the kernel is reloaded and vCPU 0 started again at its
+/// entry point.
+fn system_reset(state: &mut Context, reset_type: u64) -> Result<(), i64> {
+    if reset_type > SRST_TYPE_WARM_REBOOT {
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+
+    let shared = state.shared;
+    let reboot = match shared.on_reset {
+        ResetPolicy::Guest => reset_type != SRST_TYPE_SHUTDOWN,
+        ResetPolicy::Halt => false,
+        ResetPolicy::Reboot => true,
+    };
+
+    // If another vCPU is already resetting the guest, it will stop this one too.
+    if shared.resetting.swap(true, Ordering::SeqCst) {
+        state.park();
+        return Ok(());
+    }
+
+    match (reset_type, reboot) {
+        (SRST_TYPE_SHUTDOWN, false) => println!("Guest requested shutdown, halting"),
+        (SRST_TYPE_SHUTDOWN, true) => println!("Guest requested shutdown, rebooting it instead"),
+        (_, false) => println!("Guest requested reboot, halting it instead"),
+        (_, true) => println!("Guest requested reboot"),
+    }
+
+    state.remote_request(!0, REQUEST_STOP);
+    shared.reset_devices();
+
+    if !reboot {
+        // Nothing will ever start this vCPU again.
+        state.park();
+        return Ok(());
+    }
+
+    let (entry, guest_dtb) = unsafe { shared.load_guest(&state.guest_memory, state.guest_shift) };
+    shared.time_offset.store(state.host_clint.get_mtime(), Ordering::SeqCst);
+
+    // A vCPU that had a start pending when it was asked to stop may not have discarded it yet (see
+    // SharedContext::wait_for_start), so it must be seen stopped before the reset is over.
+    for (i, vcpu) in shared.vcpus.iter().enumerate() {
+        while i != state.vcpu && vcpu.status.load(Ordering::SeqCst) != HSM_STATUS_STOPPED {
+            spin_loop_hint();
+        }
+    }
+    shared.resetting.store(false, Ordering::SeqCst);
+    if state.vcpu == 0 {
+        state.reset(entry, guest_dtb);
+    } else {
+        while !shared.start_vcpu(0, entry, guest_dtb) {
+            spin_loop_hint();
+        }
+        state.park();
+    }
+    Ok(())
+}
+
+fn num_harts(state: &Context) -> u64 {
+    state.shared.vcpus.len() as u64
+}
+
+/// Compute the set of guest harts selected by a `hart_mask`/`hart_mask_base` pair.
+fn hart_mask(state: &Context, mask: u64, base: u64) -> SbiResult {
+    let all = (1u64 << num_harts(state)) - 1;
+    if base == u64::max_value() {
+        return Ok(all);
+    }
+
+    let selected = if mask == 0 {
+        0
+    } else if base >= 64 || (mask << base) >> base != mask {
+        return Err(SBI_ERR_INVALID_PARAM);
+    } else {
+        mask << base
+    };
+
+    if selected & !all != 0 {
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+    Ok(selected)
+}
+
+/// Legacy calls pass a pointer to the hart mask (in guest virtual memory) rather than the mask
+/// itself. A null pointer selects every hart.
+fn read_legacy_hart_mask(state: &mut Context, ptr: u64) -> Result<u64, i64> {
+    if ptr == 0 {
+        return hart_mask(state, 0, u64::max_value());
+    }
+
+    match GuestMemory::new(state).read_u64(ptr) {
+        Ok(mask) => hart_mask(state, mask, 0),
+        Err(_) => Err(SBI_ERR_INVALID_ADDRESS),
+    }
+}
@@ -1,8 +1,11 @@
 
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, AtomicU64};
 use spin::Mutex;
 use crate::print::{self, UartWriter};
 use crate::constants::*;
+use crate::fdt::{device_span, MachineMeta};
+use crate::pmap::GuestLayout;
+use crate::monitor;
 
 #[derive(Copy, Clone, Debug)]
 pub enum IpiReason {
@@ -11,9 +14,41 @@ pub enum IpiReason {
         a1: u64,
         a2: u64,
         a3: u64,
+        a4: u64,
         sp: u64,
         satp: u64,
         mepc: u64,
+        memory: HartMemory,
+    }
+}
+
+/// Host physical memory that a hart may access once it runs a guest, besides the hypervisor's text
+/// and shared data. Everything else is made inaccessible to it with PMP (see machine.rs). Ranges are
+/// given as [start, end).
+#[derive(Copy, Clone, Debug)]
+pub struct HartMemory {
+    /// Segment of the guest the hart runs.
+    pub segment: (u64, u64),
+    /// MMIO registers of all virtio devices...
+    pub virtio: (u64, u64),
+    /// ...of which only those assigned to the guest may be accessed. All other MMIO may be.
+    pub guest_virtio: (u64, u64),
+}
+
+impl HartMemory {
+    /// Memory that harts running guest `guestid`, whose segment is laid out as `layout`, may access.
+    pub fn new(machine: &MachineMeta, layout: &GuestLayout, guestid: u64) -> Self {
+        let guest_devices = machine.guest_virtio(guestid);
+        let memory = HartMemory {
+            segment: (layout.base, layout.end()),
+            virtio: device_span(&machine.virtio),
+            guest_virtio: device_span(guest_devices),
+        };
+        // PMP can only grant access to the guest's devices if no other device lies between them.
+        assert_eq!(machine.virtio.iter()
+                   .filter(|d| d.base_address < memory.guest_virtio.1 && d.base_address + d.size > memory.guest_virtio.0)
+                   .count(), guest_devices.len());
+        memory
     }
 }
 
@@ -23,6 +58,19 @@ pub struct Shared {
     pub uart_writer: Mutex<UartWriter>,
     pub ipi_reason_array: [Mutex<Option<IpiReason>>; MAX_HOST_HARTS],
     pub hart_lottery: AtomicBool,
+    /// Whether the host harts implement the hypervisor extension, in which case guests run in
+    /// VS-mode (see hext.rs).
+    pub hypervisor_extension: AtomicBool,
+    /// State of the hypervisor monitor (one of `monitor::MONITOR_*`).
+    pub monitor_state: AtomicU64,
+    /// Address of the SharedContext of each guest, indexed by guestid - 1. Zero for guests that
+    /// don't exist.
+    pub guests: [AtomicU64; MAX_HOST_HARTS],
+    /// Host hart that monitor requests concerning each guest are posted to, indexed by guestid - 1.
+    pub monitor_harts: [AtomicU64; MAX_HOST_HARTS],
+    /// Request posted to each host hart by the monitor, indexed by hartid. Cleared by the hart once
+    /// it has been carried out.
+    pub monitor_mailbox: [Mutex<Option<monitor::Request>>; MAX_HOST_HARTS],
 }
 
 pub struct ConditionalPointer(u64);
@@ -45,6 +93,8 @@ impl core::ops::Deref for ConditionalPointer {
 
 
 const MR: Mutex<Option<IpiReason>> = Mutex::new(None);
+const G: AtomicU64 = AtomicU64::new(0);
+const MB: Mutex<Option<monitor::Request>> = Mutex::new(None);
 
 /// This static is never accessed directly, but is needed so that the memory backing SHARED_STATICS
 /// is properly initialized.
@@ -64,4 +114,9 @@ pub static __SHARED_STATICS_IMPL: Shared = Shared {
     ipi_reason_array: [MR, MR, MR, MR, MR, MR, MR, MR, MR, MR, MR, MR, MR, MR, MR, MR,],
     boot_page_table: [0; 1024],
     hart_lottery: AtomicBool::new(true),
+    hypervisor_extension: AtomicBool::new(false),
+    monitor_state: AtomicU64::new(0),
+    guests: [G, G, G, G, G, G, G, G, G, G, G, G, G, G, G, G,],
+    monitor_harts: [G, G, G, G, G, G, G, G, G, G, G, G, G, G, G, G,],
+    monitor_mailbox: [MB, MB, MB, MB, MB, MB, MB, MB, MB, MB, MB, MB, MB, MB, MB, MB,],
 };
@@ -0,0 +1,202 @@
+//! Accounting of why, and for how long, each vCPU exits to the hypervisor.
+//!
+//! Every trap is attributed to a single `ExitReason`, along with the number of cycles spent handling
+//! it (as measured by the `cycle` CSR). The counters can be dumped by the guest with the rvirt SBI
+//! vendor extension (see sbi.rs).
+
+use arrayvec::ArrayVec;
+
+/// Why a vCPU trapped into the hypervisor.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExitReason {
+    /// An interrupt, identified by its cause code.
+    Interrupt(u64),
+    /// A page fault handled by adding a mapping to the shadow (or G-stage) page tables.
+    ShadowFill,
+    /// A page fault caused by accessing an emulated device or a virtio queue.
+    Mmio,
+    /// A write to a guest page table, which is write-protected while shadow mappings depend on it.
+    PageTableWrite,
+    /// A page fault that was forwarded to the guest.
+    ForwardedFault,
+    /// An access to the CSR with the given number.
+    Csr(u32),
+    Sret,
+    SfenceVma,
+    Wfi,
+    /// A read of a counter CSR by guest user code.
+    CounterRead,
+    /// A first use of the floating point registers, which loaded them.
+    FpLoad,
+    /// An SBI call, identified by its extension and function IDs.
+    Sbi(u64, u64),
+    /// Any other exception, all of which are forwarded to the guest.
+    Exception,
+}
+
+/// Occupancy of a vCPU's pool of shadow page tables, in pages.
+#[derive(Copy, Clone, Default)]
+pub struct PoolOccupancy {
+    pub used: u64,
+    /// Highest value of `used` since boot.
+    pub peak: u64,
+    pub total: u64,
+    /// Number of times the pool ran dry and shadow mappings had to be reclaimed.
+    pub reclaims: u64,
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct Counter {
+    pub exits: u64,
+    pub cycles: u64,
+}
+impl Counter {
+    fn add(&mut self, cycles: u64) {
+        self.exits += 1;
+        self.cycles += cycles;
+    }
+
+    fn print(&self, name: &str) {
+        if self.exits > 0 {
+            println!("  {:<20} {:>10} exits {:>14} cycles", name, self.exits, self.cycles);
+        }
+    }
+}
+
+/// Counters for each value of a key, like a CSR number. Keys beyond the first few to be seen share
+/// a single counter.
+struct CounterMap {
+    counters: ArrayVec<[(u64, Counter); 32]>,
+    other: Counter,
+}
+impl CounterMap {
+    fn new() -> Self {
+        Self {
+            counters: ArrayVec::new(),
+            other: Counter::default(),
+        }
+    }
+
+    fn get_mut(&mut self, key: u64) -> &mut Counter {
+        let index = match self.counters.iter().position(|&(k, _)| k == key) {
+            Some(index) => index,
+            None if !self.counters.is_full() => {
+                self.counters.push((key, Counter::default()));
+                self.counters.len() - 1
+            }
+            None => return &mut self.other,
+        };
+        &mut self.counters[index].1
+    }
+}
+
+pub struct ExitStats {
+    /// Reason for the exit currently being handled, and the value of `cycle` when it began.
+    reason: ExitReason,
+    start: u64,
+
+    interrupts: [Counter; 16],
+    shadow_fills: Counter,
+    mmio: Counter,
+    page_table_writes: Counter,
+    forwarded_faults: Counter,
+    csrs: CounterMap,
+    sret: Counter,
+    sfence_vma: Counter,
+    wfi: Counter,
+    counter_reads: Counter,
+    fp_loads: Counter,
+    sbi: CounterMap,
+    exceptions: Counter,
+}
+
+impl ExitStats {
+    pub fn new() -> Self {
+        Self {
+            reason: ExitReason::Exception,
+            start: 0,
+            interrupts: [Counter::default(); 16],
+            shadow_fills: Counter::default(),
+            mmio: Counter::default(),
+            page_table_writes: Counter::default(),
+            forwarded_faults: Counter::default(),
+            csrs: CounterMap::new(),
+            sret: Counter::default(),
+            sfence_vma: Counter::default(),
+            wfi: Counter::default(),
+            counter_reads: Counter::default(),
+            fp_loads: Counter::default(),
+            sbi: CounterMap::new(),
+            exceptions: Counter::default(),
+        }
+    }
+
+    /// Start timing an exit, initially attributed to `reason`.
+    pub fn begin(&mut self, reason: ExitReason) {
+        self.reason = reason;
+        self.start = csrr!(cycle);
+    }
+
+    /// Attribute the exit currently being handled to a different reason, once more is known about it.
+    pub fn set_reason(&mut self, reason: ExitReason) {
+        self.reason = reason;
+    }
+
+    /// Finish timing the current exit, and add it to the counters.
+    pub fn end(&mut self) {
+        let cycles = csrr!(cycle).wrapping_sub(self.start);
+        let counter = match self.reason {
+            ExitReason::Interrupt(cause) => &mut self.interrupts[(cause & 0xf) as usize],
+            ExitReason::ShadowFill => &mut self.shadow_fills,
+            ExitReason::Mmio => &mut self.mmio,
+            ExitReason::PageTableWrite => &mut self.page_table_writes,
+            ExitReason::ForwardedFault => &mut self.forwarded_faults,
+            ExitReason::Csr(csr) => self.csrs.get_mut(csr as u64),
+            ExitReason::Sret => &mut self.sret,
+            ExitReason::SfenceVma => &mut self.sfence_vma,
+            ExitReason::Wfi => &mut self.wfi,
+            ExitReason::CounterRead => &mut self.counter_reads,
+            ExitReason::FpLoad => &mut self.fp_loads,
+            ExitReason::Sbi(eid, fid) => self.sbi.get_mut(eid << 32 | (fid & 0xffffffff)),
+            ExitReason::Exception => &mut self.exceptions,
+        };
+        counter.add(cycles);
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn print(&self, vcpu: usize, pool: PoolOccupancy) {
+        println!("Exit statistics for vCPU {}:", vcpu);
+        for (cause, counter) in self.interrupts.iter().enumerate() {
+            let name = match cause {
+                1 => "software interrupt",
+                5 => "timer interrupt",
+                9 => "external interrupt",
+                _ => "other interrupt",
+            };
+            counter.print(name);
+        }
+        self.shadow_fills.print("shadow page fill");
+        self.mmio.print("mmio");
+        self.page_table_writes.print("page table write");
+        self.forwarded_faults.print("forwarded page fault");
+        for &(csr, ref counter) in &self.csrs.counters {
+            println!("  csr {:<#16x} {:>10} exits {:>14} cycles", csr, counter.exits, counter.cycles);
+        }
+        self.csrs.other.print("other csrs");
+        self.sret.print("sret");
+        self.sfence_vma.print("sfence.vma");
+        self.wfi.print("wfi");
+        self.counter_reads.print("user counter read");
+        self.fp_loads.print("fp load");
+        for &(key, ref counter) in &self.sbi.counters {
+            println!("  sbi {:#x}/{:<8} {:>10} exits {:>14} cycles", key >> 32, key & 0xffffffff, counter.exits, counter.cycles);
+        }
+        self.sbi.other.print("other sbi calls");
+        self.exceptions.print("other exception");
+        println!("Shadow page tables: {} of {} pages used (peak {}), {} reclaims",
+                 pool.used, pool.total, pool.peak, pool.reclaims);
+    }
+}
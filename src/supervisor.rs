@@ -10,7 +10,9 @@
 #![feature(start)]
 #![feature(try_blocks)]
 
+use arrayvec::ArrayVec;
 use rvirt::*;
+use rvirt::constants::MAX_HOST_HARTS;
 
 // mandatory rust environment setup
 #[lang = "eh_personality"] extern fn eh_personality() {}
@@ -43,8 +45,6 @@ unsafe fn sstart(hartid: u64, device_tree_blob: u64) {
 
     // Do some sanity checks now that the UART is initialized and we have a better chance of
     // successfully printing output.
-    assert!(machine.initrd_end <= machine.physical_memory_offset + pmap::HART_SEGMENT_SIZE);
-    assert!(machine.initrd_end - machine.initrd_start <= pmap::HEAP_SIZE);
     assert!(machine.harts.iter().any(|h| h.hartid == hartid));
     if machine.initrd_end == 0 {
         println!("WARN: No guest kernel provided. Make sure to pass one with `-initrd ...`");
@@ -67,57 +67,78 @@ unsafe fn sstart(hartid: u64, device_tree_blob: u64) {
     if !single_hart {
         guest_harts.retain(|h| h.hartid != hartid);
     }
-    let single_guest = guest_harts.len() == 1;
     assert!(guest_harts.len() != 0);
 
+    // Each guest gets a group of consecutive harts, one per vCPU.
+    let vcpus_per_guest = (machine.config.guest_vcpus as usize).min(guest_harts.len());
+    let single_guest = guest_harts.len() <= vcpus_per_guest;
+
+    let guest_vcpus: ArrayVec<[usize; MAX_HOST_HARTS]> = guest_harts.chunks(vcpus_per_guest).map(|h| h.len()).collect();
+    let layouts = pmap::layout_guests(&machine, &guest_vcpus);
+
     let mut guestid = 1;
-    for hart in guest_harts {
-        let hart_base_pa = machine.physical_memory_offset + pmap::HART_SEGMENT_SIZE * guestid;
+    for (harts, layout) in guest_harts.chunks(vcpus_per_guest).zip(&layouts) {
+
+        let guest_devices = machine.guest_virtio(guestid);
 
         let mut irq_mask = 0;
-        for j in 0..4 {
-            let index = ((guestid-1) * 4 + j) as usize;
-            if index < machine.virtio.len() {
-                let irq = machine.virtio[index].irq;
-                assert!(irq < 32);
-                irq_mask |= 1u32 << irq;
-            }
+        for device in guest_devices {
+            assert!(device.irq < 32);
+            irq_mask |= 1u32 << device.irq;
         }
 
-        *(pa2va(machine.plic_address + 0x200000 + 0x1000 * hart.plic_context) as *mut u32) = 0;
-        *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context) as *mut u32) = irq_mask;
-        *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context + 4) as *mut u32) = 0;
+        let memory = HartMemory::new(&machine, layout, guestid);
+
+        // Device interrupts for the guest are only delivered to the hart running its first vCPU.
+        for (vcpu, hart) in harts.iter().enumerate() {
+            let irq_mask = if vcpu == 0 { irq_mask } else { 0 };
+            *(pa2va(machine.plic_address + 0x200000 + 0x1000 * hart.plic_context) as *mut u32) = 0;
+            *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context) as *mut u32) = irq_mask;
+            *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context + 4) as *mut u32) = 0;
+        }
 
-        (*(pa2va(hart_base_pa) as *mut pmap::BootPageTable)).init();
-        core::ptr::copy(pa2va(device_tree_blob) as *const u8,
-                        pa2va(hart_base_pa + 4096) as *mut u8,
-                        fdt.total_size() as usize);
         core::ptr::copy(pa2va(machine.initrd_start) as *const u8,
-                        pa2va(hart_base_pa + pmap::HEAP_OFFSET) as *mut u8,
+                        pa2va(layout.kernel_image) as *mut u8,
                         (machine.initrd_end - machine.initrd_start) as usize);
 
-        let reason = IpiReason::EnterSupervisor {
-            a0: hart.hartid,
-            a1: hart_base_pa + 4096,
-            a2: hart_base_pa,
-            a3: if !single_guest { guestid as u64 } else { u64::max_value() },
-            sp: hart_base_pa + (4<<20) + pmap::DIRECT_MAP_OFFSET,
-            satp: 8 << 60 | (hart_base_pa >> 12),
-            mepc: hart_entry as u64,
-        };
-
-        if single_hart {
-            match reason {
-                IpiReason::EnterSupervisor { a0, a1, a2, a3, sp, satp, mepc: _ } => {
-                    csrw!(satp, satp);
-                    asm!("mv sp, $0" :: "r"(sp) :: "volatile");
-                    hart_entry(a0, a1, a2, a3);
+        let guest = if !single_guest { Some(guestid) } else { None };
+        context::initialize_shared(&machine, fdt, layout, harts, guest);
+
+        for (vcpu, hart) in harts.iter().enumerate() {
+            let vcpu_base_pa = layout.vcpu_base(vcpu);
+
+            (*(pa2va(vcpu_base_pa) as *mut pmap::BootPageTable)).init();
+            core::ptr::copy(pa2va(device_tree_blob) as *const u8,
+                            pa2va(vcpu_base_pa + 4096) as *mut u8,
+                            fdt.total_size() as usize);
+
+            let reason = IpiReason::EnterSupervisor {
+                a0: hart.hartid,
+                a1: vcpu_base_pa + 4096,
+                a2: layout.base,
+                a3: guest.unwrap_or(u64::max_value()),
+                a4: vcpu as u64,
+                sp: vcpu_base_pa + (4<<20) + pmap::DIRECT_MAP_OFFSET,
+                satp: 8 << 60 | (vcpu_base_pa >> 12),
+                mepc: hart_entry as u64,
+                memory,
+            };
+
+            if single_hart {
+                // Without going through handle_ipi, this hart keeps its access to all of memory.
+                // There are no other guests to keep it away from.
+                match reason {
+                    IpiReason::EnterSupervisor { a0, a1, a2, a3, a4, sp, satp, .. } => {
+                        csrw!(satp, satp);
+                        asm!("mv sp, $0" :: "r"(sp) :: "volatile");
+                        hart_entry(a0, a1, a2, a3, a4);
+                    }
                 }
+            } else {
+                // Send IPI
+                *SHARED_STATICS.ipi_reason_array[hart.hartid as usize].lock() = Some(reason);
+                *(pa2va(machine.clint_address + hart.hartid*4) as *mut u32) = 1;
             }
-        } else {
-            // Send IPI
-            *SHARED_STATICS.ipi_reason_array[hart.hartid as usize].lock() = Some(reason);
-            *(pa2va(machine.clint_address + hart.hartid*4) as *mut u32) = 1;
         }
 
         guestid += 1;
@@ -127,17 +148,15 @@ unsafe fn sstart(hartid: u64, device_tree_blob: u64) {
 }
 
 #[no_mangle]
-unsafe fn hart_entry(hartid: u64, device_tree_blob: u64, hart_base_pa: u64, guestid: u64) {
+unsafe fn hart_entry(hartid: u64, device_tree_blob: u64, segment_base_pa: u64, _guestid: u64, vcpu: u64) {
     csrw!(stvec, crate::trap::strap_entry as *const () as u64);
     csrw!(sie, 0x222);
+    csrw!(scounteren, trap::constants::COUNTEREN_CY | trap::constants::COUNTEREN_IR);
     csrs!(sstatus, trap::constants::STATUS_SUM);
     csrc!(sstatus, trap::constants::STATUS_SPP);
 
-    let guestid = if guestid == u64::max_value() {
-        None
-    } else {
-        Some(guestid)
-    };
+    let vcpu = vcpu as usize;
+    let shared = &*(pa2va(segment_base_pa + pmap::SHARED_OFFSET) as *const context::SharedContext);
 
     // Read and process host FDT.
     let fdt = Fdt::new(pa2va(device_tree_blob));
@@ -146,33 +165,23 @@ unsafe fn hart_entry(hartid: u64, device_tree_blob: u64, hart_base_pa: u64, gues
     let machine = fdt.parse();
 
     // Initialize memory subsystem.
-    let (shadow_page_tables, guest_memory, guest_shift) = pmap::init(hart_base_pa, &machine);
-
-    // Load guest binary
-    let (entry, max_addr) = sum::access_user_memory(||{
-        elf::load_elf(pa2va(hart_base_pa + pmap::HEAP_OFFSET) as *const u8,
-                      machine.physical_memory_offset as *mut u8)
-    });
-    let guest_dtb = (max_addr | 0x1fffff) + 1;
-    csrw!(sepc, entry);
+    let (shadow_page_tables, gstage, guest_memory, guest_shift) = pmap::init(&shared.layout, vcpu, &machine);
 
-    // Load guest FDT.
-    let guest_machine = sum::access_user_memory(||{
-        core::ptr::copy(pa2va(device_tree_blob) as *const u8,
-                        guest_dtb as *mut u8,
-                        fdt.total_size() as usize);
-        let guest_fdt = Fdt::new(guest_dtb);
-        guest_fdt.mask(guest_memory.len());
-        guest_fdt.parse()
-    });
+    let (entry, arg) = if vcpu == 0 {
+        // Load guest binary and FDT.
+        shared.load_guest(&guest_memory, guest_shift)
+    } else {
+        // Other vCPUs stay parked until the guest starts them with SBI HSM hart_start.
+        shared.wait_for_start(vcpu)
+    };
+    csrw!(sepc, entry);
 
     // Initialize context
-    context::initialize(&machine, &guest_machine, shadow_page_tables, guest_memory, guest_shift, hartid, guestid);
-
-    // Jump into the guest kernel.
-    asm!("mv a1, $0 // dtb = guest_dtb
+    context::initialize(&machine, shadow_page_tables, gstage, guest_memory, guest_shift, hartid, vcpu, shared);
 
-          li ra, 0
+    // Jump into the guest kernel with a0 = hartid and a1 = guest_dtb (or the opaque argument to
+    // hart_start for secondary vCPUs).
+    asm!("li ra, 0
           li sp, 0
           li gp, 0
           li tp, 0
@@ -181,7 +190,6 @@ unsafe fn hart_entry(hartid: u64, device_tree_blob: u64, hart_base_pa: u64, gues
           li t2, 0
           li s0, 0
           li s1, 0
-          li a0, 0  // hartid = 0
           li a2, 0
           li a3, 0
           li a4, 0
@@ -202,7 +210,7 @@ unsafe fn hart_entry(hartid: u64, device_tree_blob: u64, hart_base_pa: u64, gues
           li t4, 0
           li t5, 0
           li t6, 0
-          sret" :: "r"(guest_dtb) : "memory" : "volatile");
+          sret" :: "{a0}"(vcpu), "{a1}"(arg) : "memory" : "volatile");
 
     unreachable!();
 }
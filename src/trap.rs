@@ -1,6 +1,10 @@
+use core::sync::atomic::Ordering;
 use riscv_decode::Instruction;
 use crate::context::{Context, CONTEXT};
-use crate::{pfault, pmap, riscv, sum};
+use crate::error::HypervisorError;
+use crate::guest_memory::GuestMemory;
+use crate::stats::ExitReason;
+use crate::{csr, fp, gdb, hext, monitor, pfault, ptwatch, riscv, sbi};
 
 #[allow(unused)]
 pub mod constants {
@@ -30,7 +34,6 @@ pub mod constants {
         STATUS_SPP |
         STATUS_SPIE |
         STATUS_SIE;
-    pub const SSTATUS_DYNAMIC_MASK: u64 = STATUS_SD | STATUS_FS;
 
     pub const IP_SSIP: u64 = 1 << 1;
     pub const IP_STIP: u64 = 1 << 5;
@@ -40,11 +43,15 @@ pub mod constants {
     pub const IE_STIE: u64 = 1 << 5;
     pub const IE_SEIE: u64 = 1 << 9;
 
+    pub const COUNTEREN_CY: u64 = 1 << 0;
+    pub const COUNTEREN_TM: u64 = 1 << 1;
+    pub const COUNTEREN_IR: u64 = 1 << 2;
+
     pub const SATP_MODE: u64 = 0xf << 60;
     pub const SATP_ASID: u64 = 0xffff << 44;
     pub const SATP_PPN: u64 = 0xfff_ffffffff;
 
-    pub const SSTACK_BASE: u64 = 0xffffffffc0a00000 - 32*8;
+    pub const SSTACK_BASE: u64 = 0xfffffff3c0a00000 - 32*8;
 
     pub const SCAUSE_INSN_MISALIGNED: u64 = 0;
     pub const SCAUSE_INSN_ACCESS_FAULT: u64 = 1;
@@ -192,9 +199,13 @@ pub unsafe fn strap_entry() -> ! {
 #[no_mangle]
 pub fn strap() {
     let cause = csrr!(scause);
-    let status = csrr!(sstatus);
+    let from_hypervisor = if hext::enabled() {
+        !csrr!(hstatus).get(hext::HSTATUS_SPV)
+    } else {
+        csrr!(sstatus).get(STATUS_SPP)
+    };
 
-    if status.get(STATUS_SPP) {
+    if from_hypervisor {
         println!("Trap from within hypervisor?!");
         println!("sepc = {:#x}", csrr!(sepc));
         println!("stval = {:#x}", csrr!(stval));
@@ -218,34 +229,63 @@ pub fn strap() {
     let mut state = CONTEXT.lock();
     let mut state = (&mut *state).as_mut().unwrap();
 
+    if state.gstage.is_some() {
+        hext::handle_trap(&mut state, cause);
+        finish_trap(&mut state);
+        hext::return_to_guest(&mut state);
+        return;
+    }
+
     // For the processor to have generated a load/store page fault or an illegal instruction fault,
-    // the processor must have been able to load the relevant instruction (or else an access fault
-    // or instruction page fault would have been triggered). Thus, it is safe to access memory
-    // pointed to by `sepc`.
+    // it must have been able to fetch the relevant instruction, so it can normally be read back
+    // from `sepc`. It can only be missing if another vCPU has changed the guest page tables since.
     let instruction = match cause {
         SCAUSE_LOAD_PAGE_FAULT |
         SCAUSE_STORE_PAGE_FAULT |
-        SCAUSE_ILLEGAL_INSN => unsafe {
-            Some(load_instruction_at_address(&mut state, csrr!(sepc)))
-        }
+        SCAUSE_ILLEGAL_INSN => GuestMemory::new(&mut state).read_instruction(csrr!(sepc)).ok(),
         _ => None,
     };
 
+    let reason = match cause {
+        c if (c as isize) < 0 => ExitReason::Interrupt(c & 0xff),
+        SCAUSE_INSN_PAGE_FAULT | SCAUSE_LOAD_PAGE_FAULT | SCAUSE_STORE_PAGE_FAULT => ExitReason::ShadowFill,
+        SCAUSE_ENV_CALL if state.smode => sbi::exit_reason(state),
+        _ => ExitReason::Exception,
+    };
+    state.stats.begin(reason);
+
     if (cause as isize) < 0 {
         handle_interrupt(&mut state, cause);
         maybe_forward_interrupt(&mut state, csrr!(sepc));
     } else if cause == SCAUSE_INSN_PAGE_FAULT || cause == SCAUSE_LOAD_PAGE_FAULT || cause == SCAUSE_STORE_PAGE_FAULT {
         let pc = csrr!(sepc);
-        if pfault::handle_page_fault(&mut state, cause, instruction.map(|i|i.0)) {
-            maybe_forward_interrupt(&mut state, pc);
-        } else {
-            forward_exception(&mut state, cause, pc);
+        match pfault::handle_page_fault(&mut state, cause, instruction.map(|i|i.0)) {
+            Ok(true) => maybe_forward_interrupt(&mut state, pc),
+            Ok(false) => {
+                state.stats.set_reason(ExitReason::ForwardedFault);
+                forward_exception(&mut state, cause, pc)
+            }
+            Err(error) => inject_access_fault(&mut state, cause, error, pc),
         }
+    } else if cause == SCAUSE_ILLEGAL_INSN && instruction.is_none() {
+        // The instruction is no longer mapped, so let the guest retry it and take a page fault.
+    } else if cause == SCAUSE_ILLEGAL_INSN && fp::handle_illegal_instruction(&mut state, instruction.unwrap().0) {
+        // The guest's floating point registers have been loaded, so the instruction can be retried.
+        state.stats.set_reason(ExitReason::FpLoad);
     } else if cause == SCAUSE_ILLEGAL_INSN && state.smode {
         let pc = csrr!(sepc);
         let (instruction, len) = instruction.unwrap();
         let mut advance_pc = true;
-        match riscv_decode::decode(instruction).ok() {
+        let decoded = riscv_decode::decode(instruction).ok();
+        state.stats.set_reason(match &decoded {
+            Some(Instruction::Sret) => ExitReason::Sret,
+            Some(Instruction::SfenceVma(_)) => ExitReason::SfenceVma,
+            Some(Instruction::Csrrw(i)) | Some(Instruction::Csrrs(i)) | Some(Instruction::Csrrc(i)) => ExitReason::Csr(i.csr()),
+            Some(Instruction::Csrrwi(i)) | Some(Instruction::Csrrsi(i)) | Some(Instruction::Csrrci(i)) => ExitReason::Csr(i.csr()),
+            Some(Instruction::Wfi) => ExitReason::Wfi,
+            _ => ExitReason::Exception,
+        });
+        match decoded {
             Some(Instruction::Sret) => {
                 if !state.csrs.sstatus.get(STATUS_SIE) && state.csrs.sstatus.get(STATUS_SPIE) {
                     state.no_interrupt = false;
@@ -260,7 +300,7 @@ pub fn strap() {
                     state.no_interrupt = false;
                 }
             }
-            Some(Instruction::SfenceVma(rtype)) => pmap::handle_sfence_vma(&mut state, rtype),
+            Some(Instruction::SfenceVma(_)) => ptwatch::catch_up(&mut state),
             Some(Instruction::Csrrw(i)) => if let Some(prev) = state.get_csr(i.csr()) {
                 let value = get_register(state, i.rs1());
                 state.set_csr(i.csr(), value);
@@ -298,7 +338,7 @@ pub fn strap() {
                 }
                 set_register(state, i.rd(), prev);
             }
-            Some(Instruction::Wfi) => riscv::wfi(),
+            Some(Instruction::Wfi) => idle(&mut state),
             Some(decoded) => {
                 println!("Unrecognized instruction! {:?} @ pc={:#x}", decoded, pc);
                 forward_exception(&mut state, cause, pc);
@@ -315,30 +355,14 @@ pub fn strap() {
             riscv::set_sepc(pc + len);
         }
         maybe_forward_interrupt(&mut state, csrr!(sepc));
+    } else if cause == SCAUSE_ILLEGAL_INSN && emulate_user_counter_read(&mut state, instruction.unwrap().0) {
+        state.stats.set_reason(ExitReason::CounterRead);
+        riscv::set_sepc(csrr!(sepc) + instruction.unwrap().1);
     } else if cause == SCAUSE_ENV_CALL && state.smode {
-        match get_register(state, 17) {
-            0 => {
-                state.csrs.sip.set(IP_STIP, false);
-                state.csrs.mtimecmp = get_register(state, 10);
-                state.host_clint.set_mtimecmp(state.csrs.mtimecmp);
-            }
-            1 => {
-                let value = get_register(state, 10) as u8;
-                state.uart.output_byte(value)
-            }
-            5 => riscv::fence_i(),
-            6 | 7 => {
-                // Current versions of the Linux kernel pass wrong arguments to these SBI calls. As
-                // a result, this function ignores the arguments and just does a global fence. This
-                // will eventually be fixed by https://patchwork.kernel.org/patch/10872353.
-                pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
-            }
-            i => {
-                println!("Got ecall from guest function={}!", i);
-                loop {}
-            }
-        }
         riscv::set_sepc(csrr!(sepc) + 4);
+        sbi::handle_ecall(&mut state);
+    } else if cause == SCAUSE_BREAKPOINT && gdb::handle_breakpoint(&mut state) {
+        // Hit a breakpoint placed by the debugger.
     } else {
         if cause != SCAUSE_ENV_CALL { // no need to print anything for guest syscalls...
             println!("Forward exception (cause = {}, smode={})!", cause, state.smode);
@@ -362,11 +386,63 @@ pub fn strap() {
         }
         forward_exception(&mut state, cause, csrr!(sepc));
     }
+    finish_trap(&mut state);
+
+    // Guest kernel code can always read cycle and instret directly, but guest user code only if the
+    // guest kernel allowed it. Reads of time always trap so that the guest's time offset can be
+    // applied.
+    let counteren = if state.smode {
+        COUNTEREN_CY | COUNTEREN_IR
+    } else {
+        state.csrs.scounteren & (COUNTEREN_CY | COUNTEREN_IR)
+    };
+    unsafe { csrw!(scounteren, counteren) }
 
     state.shadow_page_tables.install_root(state.shadow());
 }
 
-fn handle_interrupt(state: &mut Context, cause: u64) {
+/// Work done at the end of every trap, after the cause of it has been handled.
+fn finish_trap(state: &mut Context) {
+    state.stats.end();
+
+    gdb::poll(state);
+    monitor::poll(state);
+
+    // Another vCPU is resetting the guest.
+    if state.stop_requested() {
+        state.park();
+    }
+}
+
+/// Emulate a counter read by guest user code if the guest kernel has enabled access to the counter
+/// through scounteren. Returns false if the instruction should instead be forwarded to the guest.
+fn emulate_user_counter_read(state: &mut Context, instruction: u32) -> bool {
+    let (counter, rd) = match riscv_decode::decode(instruction).ok() {
+        Some(Instruction::Csrrs(i)) if i.rs1() == 0 => (i.csr() as u64, i.rd()),
+        Some(Instruction::Csrrc(i)) if i.rs1() == 0 => (i.csr() as u64, i.rd()),
+        Some(Instruction::Csrrsi(i)) if i.zimm() == 0 => (i.csr() as u64, i.rd()),
+        Some(Instruction::Csrrci(i)) if i.zimm() == 0 => (i.csr() as u64, i.rd()),
+        _ => return false,
+    };
+
+    if counter < csr::cycle || counter > csr::hpmcounter31 {
+        return false;
+    }
+    if state.csrs.scounteren & (1 << (counter - csr::cycle)) == 0 {
+        return false;
+    }
+
+    let value = match counter {
+        csr::cycle => csrr!(cycle),
+        csr::time => state.guest_time(),
+        csr::instret => csrr!(instret),
+        _ => 0, // No hardware performance counters are exposed
+    };
+    set_register(state, rd, value);
+    true
+}
+
+pub fn handle_interrupt(state: &mut Context, cause: u64) {
     let interrupt = cause & 0xff;
     match interrupt {
         0x1 => {
@@ -374,9 +450,14 @@ fn handle_interrupt(state: &mut Context, cause: u64) {
             // because disarming a software interrupt doesn't require an SBI call but disarming
             // timer interrupts might. In more detail, on some hardware `sip.stip` will not be
             // writable while `sip.ssip` will be.
+            //
+            // Other vCPUs of the same guest also send software interrupts (via M-mode) to let us
+            // know that they have posted requests.
             riscv::clear_sip(1 << interrupt);
             assert_eq!(csrr!(sip) & (1 << interrupt), 0);
 
+            state.process_requests();
+
             let time = state.host_clint.get_mtime();
             crate::context::Uart::timer(state, time);
             if state.csrs.mtimecmp <= time {
@@ -385,8 +466,9 @@ fn handle_interrupt(state: &mut Context, cause: u64) {
             }
 
             let mut next = 0xffffffff;
-            if state.uart.next_interrupt_time > time {
-                next = next.min(state.uart.next_interrupt_time);
+            let uart_interrupt_time = state.shared.uart.lock().next_interrupt_time;
+            if uart_interrupt_time > time {
+                next = next.min(uart_interrupt_time);
             }
             if state.csrs.mtimecmp > time {
                 next = next.min(state.csrs.mtimecmp);
@@ -404,17 +486,9 @@ fn handle_interrupt(state: &mut Context, cause: u64) {
         0x9 => {
             // External
             let host_irq = state.host_plic.claim_and_clear();
-            let guest_irq = state.irq_map[host_irq as usize];
+            let guest_irq = state.shared.irq_map[host_irq as usize];
             if guest_irq != 0 {
-                state.plic.set_pending(guest_irq as u32, true);
-
-                // Guest might have masked out this interrupt
-                if state.plic.interrupt_pending() {
-                    state.no_interrupt = false;
-                    state.csrs.sip |= IP_SEIP;
-                } else {
-                    assert_eq!(state.csrs.sip & IP_SEIP, 0);
-                }
+                state.raise_interrupt(guest_irq as u32);
             }
 
         }
@@ -425,14 +499,73 @@ fn handle_interrupt(state: &mut Context, cause: u64) {
     }
 }
 
+/// How often an idle vCPU wakes up to check for console input, in host timer ticks (10ms with QEMU's
+/// 10MHz timebase). The host UART is polled rather than raising interrupts.
+const IDLE_POLL_INTERVAL: u64 = 100_000;
+
+/// Emulate a guest wfi by idling the host hart until an interrupt the guest has enabled in sie is
+/// pending. Whether it is then taken is up to `maybe_forward_interrupt`, since the guest usually has
+/// interrupts globally disabled while waiting. Also returns early whenever the hypervisor needs the
+/// vCPU for something else, which the guest just sees as a spurious wakeup.
+fn idle(state: &mut Context) {
+    loop {
+        let time = state.host_clint.get_mtime();
+        crate::context::Uart::timer(state, time);
+        if state.csrs.mtimecmp <= time {
+            state.csrs.sip |= IP_STIP;
+        }
+        let plic_context = state.plic_context();
+        let seip = state.shared.plic.lock().interrupt_pending(plic_context);
+        state.csrs.sip.set(IP_SEIP, seip);
+
+        if state.csrs.sie & state.csrs.sip != 0 || idle_interrupted(state) {
+            break;
+        }
+
+        let uart_interrupt_time = state.shared.uart.lock().next_interrupt_time;
+        let mut deadline = state.csrs.mtimecmp.min(time + IDLE_POLL_INTERVAL);
+        if uart_interrupt_time > time {
+            deadline = deadline.min(uart_interrupt_time);
+        }
+        state.host_clint.set_mtimecmp(deadline);
+
+        // Interrupts are disabled while in the hypervisor, but still wake the hart from wfi.
+        riscv::wfi();
+        let pending = csrr!(sip);
+        if pending & IP_SSIP != 0 {
+            handle_interrupt(state, 1);
+        }
+        if pending & IP_SEIP != 0 {
+            handle_interrupt(state, 9);
+        }
+
+        // The debugger only checks for input from GDB between exits.
+        if state.shared.debugger.is_some() {
+            break;
+        }
+    }
+    state.no_interrupt = false;
+}
+
+/// Whether an idle vCPU has to return to the end of `strap` to let the guest be stopped, paused or
+/// reset, or to run the monitor.
+fn idle_interrupted(state: &Context) -> bool {
+    state.stop_requested()
+        || state.shared.paused.load(Ordering::SeqCst)
+        || state.shared.debug_halt.load(Ordering::SeqCst) != 0
+        || monitor::requested()
+}
+
 fn maybe_forward_interrupt(state: &mut Context, sepc: u64) {
     if state.no_interrupt {
         return;
     }
 
-    if !state.csrs.sip.get(IP_SEIP) && state.plic.interrupt_pending() {
-        state.csrs.sip.set(IP_SEIP, true);
-    }
+    // Like a real PLIC, assert SEIP exactly when there is a pending interrupt this vCPU could
+    // claim. Another vCPU may have claimed the one that originally raised it.
+    let plic_context = state.plic_context();
+    let seip = state.shared.plic.lock().interrupt_pending(plic_context);
+    state.csrs.sip.set(IP_SEIP, seip);
 
     if (!state.smode || state.csrs.sstatus.get(STATUS_SIE)) && (state.csrs.sie & state.csrs.sip != 0) {
         let cause = if state.csrs.sip.get(IP_SEIP) {
@@ -475,6 +608,28 @@ fn forward_exception(state: &mut Context, cause: u64, sepc: u64) {
     riscv::set_sepc(state.csrs.stvec & TVEC_BASE);
 }
 
+/// Report an access that the hypervisor refused to emulate to the guest, by raising the access fault
+/// corresponding to the page fault `cause`.
+fn inject_access_fault(state: &mut Context, cause: u64, error: HypervisorError, sepc: u64) {
+    log_error(state, error, csrr!(stval), sepc);
+
+    let cause = match cause {
+        SCAUSE_INSN_PAGE_FAULT => SCAUSE_INSN_ACCESS_FAULT,
+        SCAUSE_LOAD_PAGE_FAULT => SCAUSE_LOAD_ACCESS_FAULT,
+        _ => SCAUSE_STORE_ACCESS_FAULT,
+    };
+    forward_exception(state, cause, sepc);
+}
+
+/// Log that the guest's access to `addr` from `pc` failed with `error`, and will be reported to it as
+/// an access fault. Each kind of error is only logged the first time the guest triggers it.
+pub fn log_error(state: &mut Context, error: HypervisorError, addr: u64, pc: u64) {
+    if state.shared.logged_errors.fetch_or(error.mask(), Ordering::Relaxed) & error.mask() == 0 {
+        println!("Injecting access fault for guest access to {:#x} from pc {:#x}: {}",
+                 addr, pc, error.description());
+    }
+}
+
 pub fn set_register(state: &mut Context, reg: u32, value: u64) {
     match reg {
         0 => {},
@@ -491,15 +646,3 @@ pub fn get_register(state: &mut Context, reg: u32) -> u64 {
         _ => unreachable!(),
     }
 }
-
-pub unsafe fn load_instruction_at_address(_state: &mut Context, guest_va: u64) -> (u32, u64) {
-    let pc_ptr = guest_va as *const u16;
-    sum::access_user_memory(||{
-        let il: u16 = *pc_ptr;
-        match riscv_decode::instruction_length(il) {
-            2 => (il as u32, 2),
-            4 => (il as u32 | ((*pc_ptr.offset(1) as u32) << 16), 4),
-            _ => unreachable!(),
-        }
-    })
-}
@@ -1,12 +1,31 @@
-use byteorder::{NativeEndian, ByteOrder};
-use riscv_decode::Instruction;
-use crate::context::Context;
+use spin::MutexGuard;
+use crate::context::{Context, VirtIO, REQUEST_FLUSH_PAGE_TABLES};
+use crate::error::HypervisorError;
+use crate::guest_memory::{AccessError, GuestMemory};
 use crate::memory_region::MemoryRegion;
-use crate::{pmap, riscv, trap};
+use crate::mmio::{self, MmioDevice};
+use crate::pmap;
 
 pub const MAX_QUEUES: usize = 4;
 pub const MAX_DEVICES: usize = 4;
 
+/// Size of a queue descriptor. The first 8 bytes hold the buffer's address, and the rest its length
+/// (32 bits), flags and the index of the next descriptor in the chain (16 bits each).
+const DESCRIPTOR_SIZE: u64 = 16;
+
+const VIRTQ_DESC_F_NEXT: u64 = 1;
+const VIRTQ_DESC_F_WRITE: u64 = 2;
+const VIRTQ_DESC_F_INDIRECT: u64 = 4;
+
+/// Device status bit telling the driver that the device hit an error it can't recover from.
+const STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+
+/// Interrupt status bit telling the driver that the device configuration has changed.
+const INTERRUPT_CONFIG_CHANGE: u32 = 0x2;
+
+/// Largest queue size, so that the descriptors of a queue take up at most one page.
+const MAX_QUEUE_SIZE: u32 = 256;
+
 #[derive(Copy, Clone)]
 pub struct Queue {
     /// Address guest thinks queue is mapped at
@@ -15,6 +34,30 @@ pub struct Queue {
     host_pa: u64,
     /// Number of entries in queue
     size: u64,
+    /// Alignment of the used ring, offset=0x3c
+    align: u64,
+    /// Descriptors as last written by the guest. The device instead sees them with host physical
+    /// addresses, or zeroed while they don't pass validation.
+    descriptors: [[u64; 2]; MAX_QUEUE_SIZE as usize],
+    /// Index into the available ring up to which descriptor chains have been validated.
+    checked_avail: u16,
+}
+impl Queue {
+    fn new() -> Self {
+        Self {
+            guest_pa: 0,
+            host_pa: 0,
+            size: 0,
+            align: 4096,
+            descriptors: [[0; 2]; MAX_QUEUE_SIZE as usize],
+            checked_avail: 0,
+        }
+    }
+
+    /// Guest physical address of the available ring.
+    fn avail(&self) -> u64 {
+        self.guest_pa + self.size * DESCRIPTOR_SIZE
+    }
 }
 
 pub struct Device {
@@ -22,173 +65,395 @@ pub struct Device {
     queue_sel: u32,
     queues: [Queue; MAX_QUEUES],
     device_registers: MemoryRegion<u32>,
+    /// Set once the guest has made a descriptor chain available that doesn't pass validation, until
+    /// the guest resets the device.
+    needs_reset: bool,
+    /// Set along with `needs_reset`, until the guest acknowledges the configuration change interrupt
+    /// that reports it.
+    config_interrupt: bool,
+    /// Interrupt the guest sees the device raise.
+    guest_irq: u32,
 }
 impl Device {
-    pub unsafe fn new(host_base_address: u64) -> Self {
+    pub unsafe fn new(host_base_address: u64, guest_irq: u32) -> Self {
         Self {
             queue_sel: 0,
-            queues: [Queue {guest_pa: 0, host_pa: 0, size: 0}; MAX_QUEUES],
+            queues: [Queue::new(); MAX_QUEUES],
             device_registers: MemoryRegion::with_base_address(pmap::pa2va(host_base_address), 0, 0x1000),
+            needs_reset: false,
+            config_interrupt: false,
+            guest_irq,
         }
     }
+
+    /// Reset the device, along with the host device backing it.
+    pub fn reset(&mut self) {
+        self.device_registers[0x70] = 0; // Status
+        self.queue_sel = 0;
+        self.queues = [Queue::new(); MAX_QUEUES];
+        self.needs_reset = false;
+        self.config_interrupt = false;
+    }
 }
 
 #[inline(always)]
 pub fn is_device_access(state: &mut Context, guest_pa: u64) -> bool {
-    guest_pa >= 0x10001000 && guest_pa < 0x10001000 + 0x1000 * state.virtio.devices.len() as u64
+    guest_pa >= 0x10001000 && guest_pa < 0x10001000 + 0x1000 * state.shared.virtio.lock().devices.len() as u64
 }
 
-pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
-    let device = ((guest_pa - 0x10001000) / 0x1000) as usize;
-    let offset = guest_pa & 0xfff;
+pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32) -> Result<(), HypervisorError> {
+    let shared = state.shared;
+    let mut access = DeviceAccess { virtio: shared.virtio.lock(), flush: false };
+    let result = mmio::emulate(state, &mut access, guest_pa, instruction);
 
-    let mut current = state.virtio.devices[device].device_registers[offset & !0x3];
-    if offset == 0x10 {
-        current = current & !(1 << 28); // No VIRTIO_F_INDIRECT_DESC
-    } else if offset == 0x34 {
-        current = current.min(256); // ensure queues take up at most one page
+    // Sad, but necessary because we don't know all the places the queue pages are mapped. Flushing
+    // takes the lock on the device state, so it must be released first.
+    let flush = access.flush;
+    drop(access);
+    if flush {
+        state.flush_page_tables();
+        state.remote_request(!0, REQUEST_FLUSH_PAGE_TABLES);
     }
+    result
+}
 
-    match riscv_decode::decode(instruction).ok() {
-        Some(Instruction::Lw(i)) => {
-            trap::set_register(state, i.rd(), current as u64)
+/// Accesses to the registers of virtio devices.
+struct DeviceAccess {
+    virtio: MutexGuard<'static, VirtIO>,
+    /// Set once the shadow page tables of every vCPU need to be flushed.
+    flush: bool,
+}
+impl DeviceAccess {
+    fn register(&self, device: usize, offset: u64) -> u32 {
+        let current = self.virtio.devices[device].device_registers[offset & !0x3];
+        if offset == 0x10 {
+            current & !(1 << 28) // No VIRTIO_F_INDIRECT_DESC
+        } else if offset == 0x34 {
+            current.min(MAX_QUEUE_SIZE)
+        } else if offset == 0x60 && self.virtio.devices[device].config_interrupt {
+            current | INTERRUPT_CONFIG_CHANGE
+        } else if offset == 0x70 && self.virtio.devices[device].needs_reset {
+            current | STATUS_DEVICE_NEEDS_RESET
+        } else {
+            current
         }
-        Some(Instruction::Lb(i)) => {
-            assert!(offset >= 0x100);
-            let value = (current >> (8*(offset & 0x3))) & 0xff;
-            trap::set_register(state, i.rd(), value as u64)
+    }
+}
+impl MmioDevice for DeviceAccess {
+    fn read(&mut self, _state: &mut Context, guest_pa: u64, width: u64) -> Result<u64, HypervisorError> {
+        let device = ((guest_pa - 0x10001000) / 0x1000) as usize;
+        let offset = guest_pa & 0xfff;
+
+        // Registers must be read whole, but the device specific configuration space that follows
+        // them can be read in any size.
+        match width {
+            4 if offset % 4 == 0 => Ok(self.register(device, offset) as u64),
+            1 | 2 if offset >= 0x100 && offset % 4 + width <= 4 => {
+                Ok((self.register(device, offset) >> (8 * (offset & 0x3))) as u64)
+            }
+            8 if offset >= 0x100 && offset % 8 == 0 => {
+                Ok(self.register(device, offset) as u64 | (self.register(device, offset + 4) as u64) << 32)
+            }
+            _ => Err(HypervisorError::UnsupportedAccess),
         }
-        Some(Instruction::Sw(i)) => {
-            let mut value = trap::get_register(state, i.rs2()) as u32;
-            if offset == 0x30 { // QueueSel
-                assert!(value < 4);
-                state.virtio.devices[device].queue_sel = value;
-            } else if offset == 0x38 { // QueueNum
-                let queue_sel = state.virtio.devices[device].queue_sel as usize;
-                let queue = &mut state.virtio.devices[device].queues[queue_sel];
-                queue.size = value as u64;
-
-                // Linux never changes queue sizes, so this isn't supported.
-                assert_eq!(queue.host_pa, 0);
-            } else if offset == 0x40 { // QueuePFN
-                let queue_sel = state.virtio.devices[device].queue_sel as usize;
-                let queue = &mut state.virtio.devices[device].queues[queue_sel];
-
-                // Linux never releases queues, so this is currently unimplemented.
-                assert_eq!(queue.host_pa, 0);
-
-                if value != 0 {
-                    queue.guest_pa = (value as u64) << 12;
-                    value += (state.guest_shift >> 12) as u32;
-                    queue.host_pa = (value as u64) << 12;
-                } else {
-                    unimplemented!();
-                }
+    }
+
+    fn write(&mut self, state: &mut Context, guest_pa: u64, width: u64, value: u64) -> Result<(), HypervisorError> {
+        let device = ((guest_pa - 0x10001000) / 0x1000) as usize;
+        let offset = guest_pa & 0xfff;
+        if width != 4 || offset % 4 != 0 {
+            return Err(HypervisorError::UnsupportedAccess);
+        }
+
+        let virtio = &mut *self.virtio;
+        let mut value = value as u32;
+        if offset == 0x30 { // QueueSel
+            if value as usize >= MAX_QUEUES {
+                return Err(HypervisorError::InvalidValue);
+            }
+            virtio.devices[device].queue_sel = value;
+        } else if offset == 0x38 { // QueueNum
+            if value > MAX_QUEUE_SIZE {
+                return Err(HypervisorError::InvalidValue);
+            }
+            let queue_sel = virtio.devices[device].queue_sel as usize;
+            self.flush |= release_queue(state, virtio, device, queue_sel);
+            virtio.devices[device].queues[queue_sel].size = value as u64;
+        } else if offset == 0x3c { // QueueAlign
+            if !value.is_power_of_two() || value > 4096 {
+                return Err(HypervisorError::InvalidValue);
+            }
+            let queue_sel = virtio.devices[device].queue_sel as usize;
+            self.flush |= release_queue(state, virtio, device, queue_sel);
+            virtio.devices[device].queues[queue_sel].align = value as u64;
+        } else if offset == 0x40 { // QueuePFN
+            let queue_sel = virtio.devices[device].queue_sel as usize;
+            let Queue { size, align, .. } = virtio.devices[device].queues[queue_sel];
+
+            // The device accesses the whole ring, not just the descriptors, so all of it must be in
+            // guest memory. Legacy queue sizes must also be powers of two.
+            let guest_pa = (value as u64) << 12;
+            let host_pfn = value.checked_add((state.guest_shift >> 12) as u32);
+            if value != 0 && (host_pfn.is_none() || !size.is_power_of_two() ||
+                              !state.guest_memory.in_region(guest_pa) ||
+                              !state.guest_memory.in_region(guest_pa + ring_size(size, align) - 1)) {
+                return Err(HypervisorError::InvalidValue);
+            }
+
+            // Setting up a queue again, or writing zero, releases the queue set up before.
+            self.flush |= release_queue(state, virtio, device, queue_sel);
+            if let Some(host_pfn) = host_pfn.filter(|_| value != 0) {
+                value = host_pfn;
+                let queue = &mut virtio.devices[device].queues[queue_sel];
+                queue.guest_pa = guest_pa;
+                queue.host_pa = (host_pfn as u64) << 12;
 
-                // Sad, but necessary because we don't know all the places this page is mapped.
-                pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
+                let mut avail_idx = [0; 2];
+                GuestMemory::unchecked(state).read_phys(queue.avail() + 2, &mut avail_idx)
+                    .map_err(|_| HypervisorError::InvalidValue)?;
+                queue.checked_avail = u16::from_le_bytes(avail_idx);
 
-                state.virtio.queue_guest_pages.push(queue.guest_pa);
-                for i in 0..queue.size {
-                    let value = &mut state.guest_memory[queue.guest_pa + i * 16];
-                    *value = (*value).wrapping_add(state.guest_shift);
+                // The descriptors left over from before the queue was set up become the guest's, and
+                // the device only sees the ones that pass validation.
+                virtio.queue_guest_pages.push(guest_pa);
+                self.flush = true;
+                for i in 0..size {
+                    let descriptor = guest_pa + i * DESCRIPTOR_SIZE;
+                    let (mut addr, mut rest) = ([0; 8], [0; 8]);
+                    let memory = GuestMemory::unchecked(state);
+                    memory.read_phys(descriptor, &mut addr).map_err(|_| HypervisorError::InvalidValue)?;
+                    memory.read_phys(descriptor + 8, &mut rest).map_err(|_| HypervisorError::InvalidValue)?;
+                    queue.descriptors[i as usize] = [u64::from_le_bytes(addr), u64::from_le_bytes(rest)];
+                    publish_descriptor(state, queue, i).map_err(|_| HypervisorError::InvalidValue)?;
+                }
+            }
+        } else if offset == 0x50 { // QueueNotify
+            // Chains that don't pass validation are never handed to the device. Instead the driver is
+            // told the device needs to be reset.
+            let device = &mut virtio.devices[device];
+            if device.needs_reset || !check_available(state, device, value as usize) {
+                if !device.needs_reset {
+                    device.needs_reset = true;
+                    device.config_interrupt = true;
+                    state.raise_interrupt(device.guest_irq);
                 }
+                return Ok(());
+            }
+        } else if offset == 0x64 { // InterruptACK
+            if value & INTERRUPT_CONFIG_CHANGE != 0 {
+                virtio.devices[device].config_interrupt = false;
+            }
+        } else if offset == 0x70 && value == 0 { // Status
+            for queue_sel in 0..MAX_QUEUES {
+                self.flush |= release_queue(state, virtio, device, queue_sel);
             }
-            state.virtio.devices[device].device_registers[offset] = value;
+            virtio.devices[device].needs_reset = false;
+            virtio.devices[device].config_interrupt = false;
         }
-        Some(instr) => {
-            println!("VIRTIO: Instruction {:?} used to target addr {:#x} from pc {:#x}", instr, guest_pa, csrr!(sepc));
-            loop {}
+        virtio.devices[device].device_registers[offset] = value;
+        Ok(())
+    }
+}
+
+/// Offset of the used ring of a queue with `size` entries in the legacy layout: it follows the
+/// descriptors and available ring, at the next multiple of `align`.
+fn used_offset(size: u64, align: u64) -> u64 {
+    (DESCRIPTOR_SIZE * size + 6 + 2 * size + align - 1) & !(align - 1)
+}
+
+/// Bytes taken up by a queue with `size` entries in the legacy layout.
+fn ring_size(size: u64, align: u64) -> u64 {
+    used_offset(size, align) + 6 + 8 * size
+}
+
+/// Release queue `queue_sel` of `device`, if it is set up: its descriptors are put back as the guest
+/// last wrote them, and accesses to them are no longer emulated. Returns whether there was a queue to
+/// release.
+fn release_queue(state: &mut Context, virtio: &mut VirtIO, device: usize, queue_sel: usize) -> bool {
+    let queue = &mut virtio.devices[device].queues[queue_sel];
+    if queue.host_pa == 0 {
+        return false;
+    }
+
+    // The queue was checked to be in guest memory when it was set up, so this can't fail.
+    for i in 0..queue.size {
+        let _ = write_descriptor(state, queue.guest_pa + i * DESCRIPTOR_SIZE, queue.descriptors[i as usize]);
+    }
+
+    if let Some(i) = virtio.queue_guest_pages.iter().position(|&page| page == queue.guest_pa) {
+        virtio.queue_guest_pages.remove(i);
+    }
+    queue.guest_pa = 0;
+    queue.host_pa = 0;
+    true
+}
+
+/// Check the descriptor chains the guest has made available in queue `queue_sel` of `device` since
+/// they were last checked, following them through the descriptors the guest wrote. Returns false if
+/// any of them has a descriptor that doesn't pass validation, or doesn't end.
+///
+/// The buffers the device may write, and the used ring it returns them through, are marked in the
+/// dirty log here, since nothing traps the device's writes to them.
+fn check_available(state: &mut Context, device: &mut Device, queue_sel: usize) -> bool {
+    if queue_sel >= MAX_QUEUES || device.queues[queue_sel].host_pa == 0 || device.queues[queue_sel].size == 0 {
+        return true;
+    }
+    let queue = &mut device.queues[queue_sel];
+
+    let mut idx = [0; 2];
+    if GuestMemory::unchecked(state).read_phys(queue.avail() + 2, &mut idx).is_err() {
+        return false;
+    }
+    let idx = u16::from_le_bytes(idx);
+    if queue.checked_avail != idx {
+        let used = queue.guest_pa + used_offset(queue.size, queue.align);
+        state.shared.dirty_log.mark(used, 6 + 8 * queue.size);
+    }
+    while queue.checked_avail != idx {
+        let ring_entry = queue.avail() + 4 + 2 * (queue.checked_avail as u64 % queue.size);
+        let mut head = [0; 2];
+        if GuestMemory::unchecked(state).read_phys(ring_entry, &mut head).is_err() {
+            return false;
         }
-        None => {
-            println!("Unrecognized instruction targetting VIRTIO {:#x} at {:#x}!", instruction, csrr!(sepc));
-            loop {}
+        let mut index = u16::from_le_bytes(head) as u64;
+        let mut length = 0;
+        loop {
+            if index >= queue.size || length == queue.size {
+                return false;
+            }
+            let [addr, rest] = queue.descriptors[index as usize];
+            if !check_descriptor(&state.guest_memory, queue.size, addr, rest) {
+                return false;
+            }
+            if (rest >> 32) & VIRTQ_DESC_F_WRITE != 0 {
+                state.shared.dirty_log.mark(addr, rest & 0xffff_ffff);
+            }
+            if (rest >> 32) & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = rest >> 48;
+            length += 1;
         }
+        queue.checked_avail = queue.checked_avail.wrapping_add(1);
     }
-    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
     true
 }
 
 pub fn is_queue_access(state: &mut Context, guest_page: u64) -> bool {
-    for i in 0..state.virtio.queue_guest_pages.len() {
-        if state.virtio.queue_guest_pages[i] == guest_page {
+    let virtio = state.shared.virtio.lock();
+    for i in 0..virtio.queue_guest_pages.len() {
+        if virtio.queue_guest_pages[i] == guest_page {
             return true;
         }
     }
     false
 }
 
-pub fn handle_queue_access(state: &mut Context, guest_pa: u64, host_pa: u64, instruction: u32) -> bool {
-    let mut hit_queue = false;
-    for d in &state.virtio.devices {
-        for q in &d.queues {
-            if guest_pa >= q.guest_pa && guest_pa < q.guest_pa + q.size * 16 && guest_pa & 0xf < 8 {
-                hit_queue = true;
-            }
-        }
+/// Whether any virtio queue lives in the guest physical address range `[start, end)`.
+pub fn has_queue_in(state: &mut Context, start: u64, end: u64) -> bool {
+    state.shared.virtio.lock().queue_guest_pages.iter().any(|&page| page >= start && page < end)
+}
+
+pub fn handle_queue_access(state: &mut Context, guest_pa: u64, instruction: u32) -> Result<(), HypervisorError> {
+    // Hold the lock for the whole access so that it can't race with another vCPU setting up a queue.
+    let shared = state.shared;
+    let mut access = QueueAccess { virtio: shared.virtio.lock() };
+    mmio::emulate(state, &mut access, guest_pa, instruction)
+}
+
+/// Host physical address that the device should use for a buffer at guest physical address `addr`.
+/// Zero, as used in descriptors that aren't in use, is left as is.
+fn host_address(state: &Context, addr: u64) -> u64 {
+    if addr == 0 { 0 } else { addr.wrapping_add(state.guest_shift) }
+}
+
+/// Check that a descriptor of a queue with `queue_size` entries, whose buffer is at guest physical
+/// address `addr` and whose remaining fields are `rest`, only gives the device access to guest
+/// memory. Indirect descriptors aren't offered to the guest, and aren't allowed either.
+fn check_descriptor(guest_memory: &MemoryRegion, queue_size: u64, addr: u64, rest: u64) -> bool {
+    let len = rest & 0xffff_ffff;
+    let flags = (rest >> 32) & 0xffff;
+    let next = rest >> 48;
+
+    if len != 0 && !(guest_memory.in_region(addr) && guest_memory.in_region(addr + len - 1)) {
+        return false;
     }
+    flags & VIRTQ_DESC_F_INDIRECT == 0 && (flags & VIRTQ_DESC_F_NEXT == 0 || next < queue_size)
+}
 
-    let decoded = riscv_decode::decode(instruction);
-    if let Err(err) = decoded {
-        println!("Unrecognized instruction targetting VQUEUE {:#x} at {:#x} (error: {:?})!",
-                 instruction, csrr!(sepc), err);
-        loop {}
+/// Update the device's view of descriptor `index` of `queue` from what the guest last wrote to it.
+fn publish_descriptor(state: &mut Context, queue: &Queue, index: u64) -> Result<(), AccessError> {
+    let [addr, rest] = queue.descriptors[index as usize];
+    let descriptor = queue.guest_pa + index * DESCRIPTOR_SIZE;
+    if check_descriptor(&state.guest_memory, queue.size, addr, rest) {
+        let addr = host_address(state, addr);
+        write_descriptor(state, descriptor, [addr, rest])
+    } else {
+        write_descriptor(state, descriptor, [0, 0])
     }
+}
 
-    if hit_queue {
-        match decoded.unwrap() {
-            Instruction::Ld(i) => {
-                trap::set_register(state, i.rd(), state.guest_memory[guest_pa].wrapping_sub(state.guest_shift));
-            }
-            Instruction::Sd(i) => {
-                let value = trap::get_register(state, i.rs2());
-                if value == 0 {
-                    state.guest_memory[guest_pa] = 0;
-                } else if state.guest_memory.in_region(value) {
-                    state.guest_memory[guest_pa] = value.wrapping_add(state.guest_shift);
-                } else {
-                    loop {}
+fn write_descriptor(state: &mut Context, descriptor: u64, [addr, rest]: [u64; 2]) -> Result<(), AccessError> {
+    let mut bytes = [0; DESCRIPTOR_SIZE as usize];
+    bytes[..8].copy_from_slice(&addr.to_le_bytes());
+    bytes[8..].copy_from_slice(&rest.to_le_bytes());
+    GuestMemory::unchecked(state).write_phys(descriptor, &bytes)
+}
+
+/// Accesses to pages containing virtio queues. The guest reads and writes its own copy of the queue
+/// descriptors, which are accepted whatever they hold. The device only sees a descriptor while it
+/// passes validation, with its address translated to a host physical address, so that it never gets
+/// access to memory outside of the guest. Everything else on the page is accessed as normal memory.
+struct QueueAccess {
+    virtio: MutexGuard<'static, VirtIO>,
+}
+impl QueueAccess {
+    /// If `guest_pa` is within a queue descriptor, the device and queue it belongs to and the index
+    /// of the descriptor.
+    fn descriptor(&self, guest_pa: u64) -> Option<(usize, usize, u64)> {
+        for (i, d) in self.virtio.devices.iter().enumerate() {
+            for (j, q) in d.queues.iter().enumerate() {
+                if q.host_pa != 0 && guest_pa >= q.guest_pa && guest_pa < q.guest_pa + q.size * DESCRIPTOR_SIZE {
+                    return Some((i, j, (guest_pa - q.guest_pa) / DESCRIPTOR_SIZE));
                 }
             }
-            instr => {
-                println!("VQUEUE: Instruction {:?} used to target addr {:#x} from pc {:#x}",
-                         instr, host_pa, csrr!(sepc));
-                loop {}
-            }
         }
-    } else {
-        let index = guest_pa & !0x7;
-        let offset = (guest_pa % 8) as usize;
-        let mut current = state.guest_memory[index].to_ne_bytes();
-        match decoded.as_ref().unwrap() {
-            Instruction::Ld(i) => trap::set_register(state, i.rd(), u64::from_ne_bytes(current)),
-            Instruction::Lwu(i) => trap::set_register(state, i.rd(), NativeEndian::read_u32(&current[offset..]) as u64),
-            Instruction::Lhu(i) => trap::set_register(state, i.rd(), NativeEndian::read_u16(&current[offset..]) as u64),
-            Instruction::Lbu(i) => trap::set_register(state, i.rd(), current[offset] as u64),
-            Instruction::Lw(i) => trap::set_register(state, i.rd(), NativeEndian::read_i32(&current[offset..]) as i64 as u64),
-            Instruction::Lh(i) => trap::set_register(state, i.rd(), NativeEndian::read_i16(&current[offset..]) as i64 as u64),
-            Instruction::Lb(i) => trap::set_register(state, i.rd(), current[offset] as i8 as i64 as u64),
-            Instruction::Sd(i) => state.guest_memory[index] = trap::get_register(state, i.rs2()),
-            Instruction::Sw(i) => {
-                NativeEndian::write_u32(&mut current[offset..], trap::get_register(state, i.rs2()) as u32);
-                state.guest_memory[index] = u64::from_ne_bytes(current);
-            }
-            Instruction::Sh(i) => {
-                NativeEndian::write_u16(&mut current[offset..], trap::get_register(state, i.rs2()) as u16);
-                state.guest_memory[index] = u64::from_ne_bytes(current);
-            }
-            Instruction::Sb(i) => {
-                current[offset] = trap::get_register(state, i.rs2()) as u8;
-                state.guest_memory[index] = u64::from_ne_bytes(current);
-            }
-            instr => {
-                println!("VQUEUE: Instruction {:?} used to target addr {:#x} from pc {:#x}",
-                         instr, host_pa, csrr!(sepc));
-                loop {}
-            }
+        None
+    }
+}
+impl MmioDevice for QueueAccess {
+    fn read(&mut self, state: &mut Context, guest_pa: u64, width: u64) -> Result<u64, HypervisorError> {
+        let offset = guest_pa % 8;
+        if offset + width > 8 {
+            return Err(HypervisorError::UnsupportedAccess);
+        }
+
+        if let Some((device, queue, index)) = self.descriptor(guest_pa) {
+            let descriptor = self.virtio.devices[device].queues[queue].descriptors[index as usize];
+            return Ok(descriptor[(guest_pa as usize >> 3) & 1] >> (8 * offset));
         }
+
+        let mut bytes = [0; 8];
+        GuestMemory::unchecked(state).read_phys(guest_pa, &mut bytes[..width as usize])
+            .map_err(|_| HypervisorError::UnmappedAddress)?;
+        Ok(u64::from_le_bytes(bytes))
     }
 
-    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
-    true
+    fn write(&mut self, state: &mut Context, guest_pa: u64, width: u64, value: u64) -> Result<(), HypervisorError> {
+        let offset = guest_pa % 8;
+        if offset + width > 8 {
+            return Err(HypervisorError::UnsupportedAccess);
+        }
+        let mask = (!0 >> (64 - 8 * width)) << (8 * offset);
+
+        if let Some((device, queue, index)) = self.descriptor(guest_pa) {
+            let queue = &mut self.virtio.devices[device].queues[queue];
+            let current = &mut queue.descriptors[index as usize][(guest_pa as usize >> 3) & 1];
+            *current = (*current & !mask) | ((value << (8 * offset)) & mask);
+            return publish_descriptor(state, queue, index).map_err(|_| HypervisorError::UnmappedAddress);
+        }
+
+        GuestMemory::unchecked(state).write_phys(guest_pa, &value.to_le_bytes()[..width as usize])
+            .map_err(|_| HypervisorError::UnmappedAddress)
+    }
 }